@@ -0,0 +1,68 @@
+//! How many packets per second a server answers with each UDP backend.
+//!
+//! A client sends a burst of connect requests over loopback, and waits
+//! for every response. The client always uses the fastest backend there
+//! is, so that only the server side changes between runs.
+use bittrickle::server::Server;
+use bittrickle::tracker::{Settings, Tracker};
+use bittrickle::transport::{Backend, Batch, BATCH_SIZE};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::net::UdpSocket;
+use std::thread;
+use std::time::Duration;
+
+
+/// How many requests are in flight at once, kept small enough
+/// that the kernel doesn't drop any of them
+const BURST: usize = 64;
+
+
+fn connect_requests(burst: usize) -> Vec<[u8; 16]> {
+    (0..burst as u32).map(|i| {
+        let mut packet = [0, 0, 4, 0x17, 0x27, 0x10, 0x19, 0x80, 0, 0, 0, 0, 0, 0, 0, 0];
+        packet[12..].copy_from_slice(&i.to_be_bytes());
+        packet
+    }).collect()
+}
+
+fn round_trips(c: &mut Criterion) {
+    let client_backend = if Backend::Mmsg.is_supported() { Backend::Mmsg } else { Backend::Std };
+    let mut group = c.benchmark_group("udp_round_trip");
+    group.throughput(Throughput::Elements(BURST as u64));
+    for &backend in &[Backend::Std, Backend::Mmsg] {
+        if !backend.is_supported() {
+            continue;
+        }
+        let tracker = Tracker::new(Settings::default());
+        let mut server = Server::with_backend("127.0.0.1:0", tracker, backend).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // A lost packet shouldn't hang the benchmark
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut client = client_backend.wrap(socket);
+        let mut requests = Batch::new(BURST, 16);
+        for packet in connect_requests(BURST) {
+            requests.push_with(addr, |buf| {
+                buf[..16].copy_from_slice(&packet);
+                16
+            });
+        }
+        let mut responses = Batch::new(BATCH_SIZE, 64);
+        group.bench_function(BenchmarkId::from_parameter(backend), |b| b.iter(|| {
+            client.send(&requests).unwrap();
+            let mut received = 0;
+            while received < BURST {
+                client.recv(&mut responses).unwrap();
+                if responses.is_empty() {
+                    break;
+                }
+                received += responses.len();
+            }
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, round_trips);
+criterion_main!(benches);
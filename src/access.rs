@@ -0,0 +1,292 @@
+use std::collections::HashSet;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::protocol::InfoHash;
+use crate::swarm::SharedSwarm;
+
+
+/// Which torrents the tracker accepts announces for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessMode {
+    /// Any torrent is allowed
+    Open,
+    /// Only the torrents in the list are allowed
+    Whitelist,
+    /// Every torrent except those in the list is allowed
+    Blacklist
+}
+
+impl FromStr for AccessMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(AccessMode::Open),
+            "whitelist" => Ok(AccessMode::Whitelist),
+            "blacklist" => Ok(AccessMode::Blacklist),
+            _ => Err(format!("unknown access mode: {}", s))
+        }
+    }
+}
+
+
+/// The errors that can happen while loading an access list
+#[derive(Debug)]
+pub enum AccessError {
+    /// The file couldn't be read
+    Io(PathBuf, io::Error),
+    /// A line isn't a hex encoded info hash
+    BadLine(usize, String)
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccessError::Io(path, e) => write!(f, "couldn't read {}: {}", path.display(), e),
+            AccessError::BadLine(line, text) => write!(f, "line {}: invalid info hash {:?}", line, text)
+        }
+    }
+}
+
+impl error::Error for AccessError {}
+
+
+/// Parse an info hash written as 40 hex digits
+pub fn parse_info_hash(s: &str) -> Option<InfoHash> {
+    if s.len() != 40 || !s.is_ascii() {
+        return None;
+    }
+    let mut out = [0; 20];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+/// Write an info hash as 40 lowercase hex digits, the inverse of `parse_info_hash`
+pub fn format_info_hash(info_hash: &InfoHash) -> String {
+    info_hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+
+/// Decides which torrents we track, from a mode and a list of info hashes
+#[derive(Debug, Clone)]
+pub struct AccessList {
+    mode: AccessMode,
+    hashes: HashSet<InfoHash>
+}
+
+impl AccessList {
+    /// A list allowing every torrent
+    pub fn open() -> Self {
+        AccessList::new(AccessMode::Open, HashSet::new())
+    }
+
+    /// Create a list with a mode and some info hashes
+    pub fn new(mode: AccessMode, hashes: HashSet<InfoHash>) -> Self {
+        AccessList { mode, hashes }
+    }
+
+    /// Parse a list with one hex info hash per line.
+    /// Blank lines, and anything after a `#`, are ignored.
+    pub fn parse(mode: AccessMode, text: &str) -> Result<Self, AccessError> {
+        let mut hashes = HashSet::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let hash = parse_info_hash(line).ok_or_else(|| AccessError::BadLine(i + 1, line.to_string()))?;
+            hashes.insert(hash);
+        }
+        Ok(AccessList::new(mode, hashes))
+    }
+
+    /// Read a list from a file, see `parse` for the format
+    pub fn from_file(mode: AccessMode, path: impl AsRef<Path>) -> Result<Self, AccessError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| AccessError::Io(path.to_owned(), e))?;
+        AccessList::parse(mode, &text)
+    }
+
+    /// The mode this list is in
+    pub fn mode(&self) -> AccessMode {
+        self.mode
+    }
+
+    /// Whether or not we accept announces for a torrent
+    pub fn allows(&self, info_hash: &InfoHash) -> bool {
+        match self.mode {
+            AccessMode::Open => true,
+            AccessMode::Whitelist => self.hashes.contains(info_hash),
+            AccessMode::Blacklist => !self.hashes.contains(info_hash)
+        }
+    }
+
+    /// Every info hash in the list
+    pub fn hashes(&self) -> impl Iterator<Item = &InfoHash> {
+        self.hashes.iter()
+    }
+
+    /// Whether or not an info hash is in the list, whatever the mode
+    pub fn contains(&self, info_hash: &InfoHash) -> bool {
+        self.hashes.contains(info_hash)
+    }
+
+    /// Add an info hash to the list, returning false if it was already there
+    pub fn insert(&mut self, info_hash: InfoHash) -> bool {
+        self.hashes.insert(info_hash)
+    }
+
+    /// Remove an info hash from the list, returning false if it wasn't there
+    pub fn remove(&mut self, info_hash: &InfoHash) -> bool {
+        self.hashes.remove(info_hash)
+    }
+}
+
+impl Default for AccessList {
+    fn default() -> Self {
+        AccessList::open()
+    }
+}
+
+
+/// An access list shared by every shard of a swarm.
+///
+/// There's a single copy however many shards there are, so reloads and
+/// edits change it in one place, without locking any of the shards.
+#[derive(Debug, Clone, Default)]
+pub struct SharedAccess {
+    list: Arc<RwLock<AccessList>>
+}
+
+impl SharedAccess {
+    /// Share a list
+    pub fn new(list: AccessList) -> Self {
+        SharedAccess { list: Arc::new(RwLock::new(list)) }
+    }
+
+    /// Lock the list for reading.
+    /// Every change leaves the list whole, so a panic while writing doesn't matter.
+    pub fn read(&self) -> RwLockReadGuard<'_, AccessList> {
+        self.list.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock the list for changing it
+    pub fn write(&self) -> RwLockWriteGuard<'_, AccessList> {
+        self.list.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Replace the list with another
+    pub fn set(&self, list: AccessList) {
+        *self.write() = list;
+    }
+}
+
+
+/// Add or remove an info hash in the text of a list file,
+/// keeping every other line, comments included
+fn edit_text(text: &str, info_hash: &InfoHash, add: bool) -> String {
+    let mut out = String::with_capacity(text.len() + 41);
+    for line in text.lines() {
+        let entry = line.split('#').next().unwrap_or("").trim();
+        if parse_info_hash(entry).as_ref() != Some(info_hash) {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    if add {
+        out.push_str(&format_info_hash(info_hash));
+        out.push('\n');
+    }
+    out
+}
+
+/// Add or remove an info hash in a list file, keeping its other lines.
+///
+/// The file is replaced in one go, so that `reload_every` never reads it
+/// half written. A missing file is created.
+pub fn edit_file(path: impl AsRef<Path>, info_hash: &InfoHash, add: bool) -> io::Result<()> {
+    let path = path.as_ref();
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e)
+    };
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, edit_text(&text, info_hash, add))?;
+    fs::rename(&tmp_path, path)
+}
+
+
+/// Reload an access list into a swarm whenever its file changes,
+/// checking every `interval`, and blocking the current thread forever.
+///
+/// If the file can't be read or parsed, we keep using the old list
+/// and report the problem on stderr.
+pub fn reload_every(swarm: SharedSwarm, mode: AccessMode, path: impl AsRef<Path>, interval: Duration) {
+    let path = path.as_ref();
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified: Option<SystemTime> = modified(path);
+    loop {
+        thread::sleep(interval);
+        let now_modified = modified(path);
+        if now_modified == last_modified {
+            continue;
+        }
+        match AccessList::from_file(mode, path) {
+            Ok(list) => {
+                swarm.set_access(list);
+                last_modified = now_modified;
+            }
+            Err(e) => eprintln!("bittrickle: keeping the old access list: {}", e)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0101010101010101010101010101010101010101";
+
+    #[test]
+    fn parse_list() {
+        let text = format!("# our torrents\n\n{}  # ubuntu\n{}\n", HASH, HASH.replace("01", "aB"));
+        let list = AccessList::parse(AccessMode::Whitelist, &text).unwrap();
+        assert!(list.allows(&[1; 20]));
+        assert!(list.allows(&[0xab; 20]));
+        assert!(!list.allows(&[2; 20]));
+        let list = AccessList::parse(AccessMode::Blacklist, &text).unwrap();
+        assert!(!list.allows(&[1; 20]));
+        assert!(list.allows(&[2; 20]));
+    }
+
+    #[test]
+    fn reject_bad_lines() {
+        let text = format!("{}\n{}\n", HASH, &HASH[1..]);
+        let error = AccessList::parse(AccessMode::Whitelist, &text).unwrap_err();
+        assert_eq!(error.to_string(), format!("line 2: invalid info hash {:?}", &HASH[1..]));
+        assert!(parse_info_hash(&HASH.replace('0', "g")).is_none());
+        assert_eq!(format_info_hash(&[0xab; 20]), HASH.replace("01", "ab"));
+    }
+
+    #[test]
+    fn edit_list_text() {
+        let text = format!("# our torrents\n{}  # ubuntu\n", HASH.to_uppercase());
+        let added = edit_text(&text, &[2; 20], true);
+        assert_eq!(added, format!("{}{}\n", text, "02".repeat(20)));
+        assert_eq!(edit_text(&added, &[2; 20], true), added);
+        assert_eq!(edit_text(&added, &[1; 20], false), format!("# our torrents\n{}\n", "02".repeat(20)));
+    }
+}
@@ -0,0 +1,458 @@
+//! Keeping track of how much each user uploads and downloads.
+//!
+//! Clients report the bytes they've moved since they started, so each
+//! announce is compared with the last one from the same session, and only
+//! the difference is credited. A session is an account, a torrent, and a
+//! peer id. Counters that go down mean the client restarted without telling
+//! us, so they're credited in full, as the start of a new session. A started
+//! event in a session we already know of is treated like any other announce,
+//! so that retransmitted or replayed announces aren't credited twice.
+//!
+//! Nothing is kept for announces that move no bytes, and since public trackers
+//! let clients pick their own keys, only so many totals are kept for keys.
+//!
+//! Totals are saved as text, with one line per account and torrent:
+//!
+//! ```text
+//! <account> <info hash in hex> <uploaded> <downloaded>
+//! ```
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::access::{format_info_hash, parse_info_hash};
+use crate::protocol::{AnnounceEvent, InfoHash, PeerId};
+use crate::swarm::PeerAnnounce;
+use crate::tracker::SWEEP_INTERVAL;
+use crate::users::{self, UserTable};
+
+
+/// The most totals we keep for accounts that are announce keys,
+/// counting one for each key and torrent
+const MAX_KEY_TOTALS: usize = 100_000;
+
+
+/// Who the bytes in an announce are credited to
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Account {
+    /// A user of a private tracker, by passkey
+    Passkey(String),
+    /// A client of a public tracker, by the key it announces with
+    Key(u32)
+}
+
+impl Account {
+    /// Find the account for an announce. Private trackers use the passkey
+    /// in the URL's path, and public ones use the announce key, leaving out
+    /// clients that didn't send one.
+    pub fn for_announce(users: Option<&UserTable>, path: &[u8], key: u32) -> Option<Self> {
+        match users {
+            Some(_) => users::passkey(path).map(|passkey| Account::Passkey(passkey.to_string())),
+            None if key == 0 => None,
+            None => Some(Account::Key(key))
+        }
+    }
+}
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Account::Passkey(passkey) => write!(f, "passkey:{}", passkey),
+            Account::Key(key) => write!(f, "key:{:08x}", key)
+        }
+    }
+}
+
+impl FromStr for Account {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(passkey) = s.strip_prefix("passkey:") {
+            if !passkey.is_empty() {
+                return Ok(Account::Passkey(passkey.to_string()));
+            }
+        }
+        if let Some(key) = s.strip_prefix("key:") {
+            if let Ok(key) = u32::from_str_radix(key, 16) {
+                return Ok(Account::Key(key));
+            }
+        }
+        Err(format!("invalid account: {}", s))
+    }
+}
+
+
+/// How many bytes have been moved
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Totals {
+    pub uploaded: u64,
+    pub downloaded: u64
+}
+
+impl Totals {
+    /// How many bytes were uploaded per byte downloaded,
+    /// which is only defined once something has been downloaded
+    pub fn ratio(&self) -> Option<f64> {
+        if self.downloaded == 0 {
+            return None;
+        }
+        Some(self.uploaded as f64 / self.downloaded as f64)
+    }
+
+    fn add(&mut self, other: Totals) {
+        self.uploaded = self.uploaded.saturating_add(other.uploaded);
+        self.downloaded = self.downloaded.saturating_add(other.downloaded);
+    }
+}
+
+
+/// The errors that can happen while loading saved totals
+#[derive(Debug)]
+pub enum LedgerError {
+    /// The file couldn't be read
+    Io(io::Error),
+    /// A line doesn't have an account, an info hash, and two counts
+    BadLine(usize, String)
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LedgerError::Io(e) => write!(f, "io error: {}", e),
+            LedgerError::BadLine(line, text) => write!(f, "line {}: invalid totals {:?}", line, text)
+        }
+    }
+}
+
+impl error::Error for LedgerError {}
+
+impl From<io::Error> for LedgerError {
+    fn from(e: io::Error) -> Self {
+        LedgerError::Io(e)
+    }
+}
+
+
+/// What a session last reported
+struct Session {
+    reported: Totals,
+    last_seen: Instant
+}
+
+#[derive(Default)]
+struct Books {
+    sessions: HashMap<(Account, InfoHash, PeerId), Session>,
+    users: HashMap<Account, Totals>,
+    torrents: HashMap<(Account, InfoHash), Totals>,
+    /// How many entries in `torrents` are for announce keys
+    key_totals: usize
+}
+
+impl Books {
+    /// Add to an account's totals, returning false if there's no room for new ones
+    fn credit(&mut self, account: Account, info_hash: InfoHash, delta: Totals) -> bool {
+        if delta == Totals::default() {
+            return true;
+        }
+        let torrent_key = (account, info_hash);
+        if let Account::Key(_) = torrent_key.0 {
+            if !self.torrents.contains_key(&torrent_key) {
+                if self.key_totals >= MAX_KEY_TOTALS {
+                    return false;
+                }
+                self.key_totals += 1;
+            }
+        }
+        self.users.entry(torrent_key.0.clone()).or_default().add(delta);
+        self.torrents.entry(torrent_key).or_default().add(delta);
+        true
+    }
+}
+
+
+/// The upload and download totals for every account, shared between frontends
+pub struct Ledger {
+    books: Mutex<Books>,
+    /// How long a session can stay silent before we forget it
+    session_lifetime: Duration,
+    last_sweep: Mutex<Instant>
+}
+
+impl Ledger {
+    /// Create an empty ledger, forgetting sessions silent for `session_lifetime`,
+    /// which should match how long the swarm keeps silent peers
+    pub fn new(session_lifetime: Duration) -> Self {
+        Ledger {
+            books: Mutex::new(Books::default()),
+            session_lifetime,
+            last_sweep: Mutex::new(Instant::now())
+        }
+    }
+
+    fn books(&self) -> MutexGuard<'_, Books> {
+        self.books.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Credit an account with what it moved since its last announce,
+    /// giving back the amounts credited.
+    ///
+    /// Without an earlier announce to compare to, only a started event
+    /// is credited, since the rest may already have been counted before
+    /// we forgot the session. With one, even a started event only gets
+    /// the difference, unless the counters went down. New announce keys
+    /// aren't credited once we're keeping as many as we can.
+    pub fn record(&self, account: Account, announce: &PeerAnnounce, now: Instant) -> Totals {
+        self.maybe_sweep(now);
+        let reported = Totals {
+            uploaded: announce.uploaded.max(0) as u64,
+            downloaded: announce.downloaded.max(0) as u64
+        };
+        let session_key = (account.clone(), announce.info_hash, announce.peer_id);
+        let mut books = self.books();
+        let previous = books.sessions.get(&session_key).map(|session| session.reported);
+        let delta = match (announce.event, previous) {
+            (AnnounceEvent::Started, None) => reported,
+            (_, None) => Totals::default(),
+            (_, Some(previous)) if reported.uploaded < previous.uploaded || reported.downloaded < previous.downloaded => reported,
+            (_, Some(previous)) => Totals {
+                uploaded: reported.uploaded - previous.uploaded,
+                downloaded: reported.downloaded - previous.downloaded
+            }
+        };
+        if announce.event == AnnounceEvent::Stopped {
+            books.sessions.remove(&session_key);
+        } else {
+            books.sessions.insert(session_key, Session { reported, last_seen: now });
+        }
+        if books.credit(account, announce.info_hash, delta) {
+            delta
+        } else {
+            Totals::default()
+        }
+    }
+
+    /// Forget the sessions that have gone silent, if it's been a while since we last did
+    fn maybe_sweep(&self, now: Instant) {
+        let mut last_sweep = self.last_sweep.lock().unwrap_or_else(PoisonError::into_inner);
+        if now.saturating_duration_since(*last_sweep) < SWEEP_INTERVAL {
+            return;
+        }
+        *last_sweep = now;
+        drop(last_sweep);
+        let lifetime = self.session_lifetime;
+        self.books().sessions.retain(|_, session| now.saturating_duration_since(session.last_seen) < lifetime);
+    }
+
+    /// The totals for an account, over every torrent
+    pub fn user(&self, account: &Account) -> Totals {
+        self.find(account).unwrap_or_default()
+    }
+
+    /// The totals for an account, if it's ever been credited
+    pub fn find(&self, account: &Account) -> Option<Totals> {
+        self.books().users.get(account).copied()
+    }
+
+    /// The totals for an account on a single torrent
+    pub fn torrent(&self, account: &Account, info_hash: &InfoHash) -> Totals {
+        self.books().torrents.get(&(account.clone(), *info_hash)).copied().unwrap_or_default()
+    }
+
+    /// The ratio for an account, over every torrent, see `Totals::ratio`
+    pub fn ratio(&self, account: &Account) -> Option<f64> {
+        self.user(account).ratio()
+    }
+
+    /// Every account, with its totals, sorted by account
+    pub fn users(&self) -> Vec<(Account, Totals)> {
+        let mut users: Vec<_> = self.books().users.iter().map(|(a, t)| (a.clone(), *t)).collect();
+        users.sort_by(|a, b| a.0.cmp(&b.0));
+        users
+    }
+
+    /// Write every account's totals per torrent, in the format described above
+    pub fn encode(&self) -> String {
+        let books = self.books();
+        let mut lines: Vec<_> = books.torrents.iter().map(|((account, info_hash), totals)| {
+            format!("{} {} {} {}\n", account, format_info_hash(info_hash), totals.uploaded, totals.downloaded)
+        }).collect();
+        lines.sort();
+        lines.concat()
+    }
+
+    /// Add the totals written by `encode` to this ledger
+    pub fn decode(&self, text: &str) -> Result<(), LedgerError> {
+        let mut parsed = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let bad_line = || LedgerError::BadLine(i + 1, line.to_string());
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() != 4 {
+                return Err(bad_line());
+            }
+            let account: Account = parts[0].parse().map_err(|_| bad_line())?;
+            let info_hash = parse_info_hash(parts[1]).ok_or_else(bad_line)?;
+            let uploaded = parts[2].parse().map_err(|_| bad_line())?;
+            let downloaded = parts[3].parse().map_err(|_| bad_line())?;
+            parsed.push((account, info_hash, Totals { uploaded, downloaded }));
+        }
+        // Only change anything once the whole file has parsed
+        let mut books = self.books();
+        for (account, info_hash, totals) in parsed {
+            books.credit(account, info_hash, totals);
+        }
+        Ok(())
+    }
+
+    /// Save the totals to a file, replacing it all at once
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let text = self.encode();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Load the totals saved in a file into this ledger
+    pub fn load(&self, path: impl AsRef<Path>) -> Result<(), LedgerError> {
+        let text = fs::read_to_string(path)?;
+        self.decode(&text)
+    }
+}
+
+
+/// Save a ledger every `interval`, blocking the current thread forever.
+/// Failures are reported on stderr, and we try again next time.
+pub fn save_every(ledger: &Ledger, path: impl AsRef<Path>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        if let Err(e) = ledger.save(path.as_ref()) {
+            eprintln!("bittrickle: couldn't save accounting to {}: {}", path.as_ref().display(), e);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announce(uploaded: i64, downloaded: i64, event: AnnounceEvent) -> PeerAnnounce {
+        PeerAnnounce {
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            key: 3,
+            downloaded,
+            left: 0,
+            uploaded,
+            event
+        }
+    }
+
+    #[test]
+    fn credit_deltas() {
+        let ledger = Ledger::new(Duration::from_secs(1800));
+        let account = Account::Passkey("abc".to_string());
+        let now = Instant::now();
+        let record = |u, d, event| {
+            let delta = ledger.record(account.clone(), &announce(u, d, event), now);
+            (delta.uploaded, delta.downloaded)
+        };
+        assert_eq!(record(0, 0, AnnounceEvent::Started), (0, 0));
+        assert_eq!(record(100, 400, AnnounceEvent::Nothing), (100, 400));
+        assert_eq!(record(150, 400, AnnounceEvent::Nothing), (50, 0));
+        // The client restarted, and is counting from zero again
+        assert_eq!(record(30, 10, AnnounceEvent::Nothing), (30, 10));
+        assert_eq!(record(40, 10, AnnounceEvent::Stopped), (10, 0));
+        // A forgotten session isn't credited until it starts again
+        assert_eq!(record(500, 500, AnnounceEvent::Nothing), (0, 0));
+        assert_eq!(record(600, 500, AnnounceEvent::Nothing), (100, 0));
+        assert_eq!(ledger.user(&account), Totals { uploaded: 290, downloaded: 410 });
+        assert_eq!(ledger.torrent(&account, &[1; 20]), ledger.user(&account));
+        assert_eq!(ledger.torrent(&account, &[2; 20]), Totals::default());
+        assert_eq!(ledger.ratio(&account), Some(290.0 / 410.0));
+        assert_eq!(ledger.ratio(&Account::Key(1)), None);
+    }
+
+    #[test]
+    fn credit_started_once() {
+        let ledger = Ledger::new(Duration::from_secs(1800));
+        let account = Account::Key(3);
+        let now = Instant::now();
+        let started = announce(1000, 10, AnnounceEvent::Started);
+        assert_eq!(ledger.record(account.clone(), &started, now), Totals { uploaded: 1000, downloaded: 10 });
+        // A retransmission, or a replay, gets nothing more
+        assert_eq!(ledger.record(account.clone(), &started, now), Totals::default());
+        // Starting over with bigger counters only gets the difference
+        let inflated = announce(5000, 10, AnnounceEvent::Started);
+        assert_eq!(ledger.record(account.clone(), &inflated, now), Totals { uploaded: 4000, downloaded: 0 });
+        assert_eq!(ledger.user(&account), Totals { uploaded: 5000, downloaded: 10 });
+        assert_eq!(ledger.find(&Account::Key(4)), None);
+    }
+
+    #[test]
+    fn skip_empty_credits() {
+        let ledger = Ledger::new(Duration::from_secs(1800));
+        let now = Instant::now();
+        for key in 1..=1000 {
+            ledger.record(Account::Key(key), &announce(0, 0, AnnounceEvent::Started), now);
+        }
+        assert_eq!(ledger.users(), Vec::new());
+        assert_eq!(ledger.encode(), "");
+    }
+
+    #[test]
+    fn limit_key_totals() {
+        let ledger = Ledger::new(Duration::from_secs(1800));
+        let now = Instant::now();
+        let started = announce(1, 0, AnnounceEvent::Started);
+        for key in 0..MAX_KEY_TOTALS as u32 {
+            ledger.record(Account::Key(key), &started, now);
+        }
+        assert_eq!(ledger.record(Account::Key(u32::MAX), &started, now), Totals::default());
+        assert_eq!(ledger.find(&Account::Key(u32::MAX)), None);
+        // Keys we already have, and passkeys, are still credited
+        let more = announce(2, 0, AnnounceEvent::Nothing);
+        assert_eq!(ledger.record(Account::Key(0), &more, now), Totals { uploaded: 1, downloaded: 0 });
+        let passkey = Account::Passkey("abc".to_string());
+        assert_eq!(ledger.record(passkey, &started, now), Totals { uploaded: 1, downloaded: 0 });
+    }
+
+    #[test]
+    fn choose_accounts() {
+        let users = UserTable::parse("abc alice\n").unwrap();
+        assert_eq!(Account::for_announce(Some(&users), b"/announce/abc", 7), Some(Account::Passkey("abc".to_string())));
+        assert_eq!(Account::for_announce(None, b"/announce/abc", 7), Some(Account::Key(7)));
+        assert_eq!(Account::for_announce(None, b"", 0), None);
+        assert_eq!("key:0000000a".parse(), Ok(Account::Key(10)));
+        assert!("name:alice".parse::<Account>().is_err());
+    }
+
+    #[test]
+    fn totals_round_trip() {
+        let ledger = Ledger::new(Duration::from_secs(1800));
+        let now = Instant::now();
+        ledger.record(Account::Passkey("abc".to_string()), &announce(5, 7, AnnounceEvent::Started), now);
+        ledger.record(Account::Key(0xbeef), &announce(1, 2, AnnounceEvent::Started), now);
+        let text = ledger.encode();
+        assert_eq!(text, format!("key:0000beef {} 1 2\npasskey:abc {} 5 7\n", "01".repeat(20), "01".repeat(20)));
+        let loaded = Ledger::new(Duration::from_secs(1800));
+        loaded.decode(&text).unwrap();
+        assert_eq!(loaded.users(), ledger.users());
+        let error = loaded.decode("key:1 nothex 1 2\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 1: invalid totals \"key:1 nothex 1 2\"");
+    }
+}
@@ -0,0 +1,418 @@
+//! An HTTP API for looking at and changing the swarm while the tracker runs.
+//!
+//! Every request needs an `Authorization: Bearer <token>` header with the
+//! admin token. Responses are JSON, and info hashes are written as hex.
+//!
+//! - `GET /stats` gives the totals for the whole swarm
+//! - `GET /torrents` lists every torrent with its counts
+//! - `GET /torrents/<hash>` gives one torrent, along with its peers
+//! - `DELETE /torrents/<hash>` stops tracking a torrent
+//! - `DELETE /torrents/<hash>/peers/<addr>` removes a single peer
+//! - `GET /whitelist` lists the whitelisted torrents
+//! - `PUT /whitelist/<hash>` and `DELETE /whitelist/<hash>` edit the whitelist
+//! - `GET /users` lists every account's upload and download totals and ratio
+//! - `GET /users/<account>` gives one account, by passkey, or as `key:<hex>`
+//!
+//! Whitelist edits are also saved to the access list file, if there is one,
+//! so that reloading it, or restarting, keeps them.
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::access::{self, format_info_hash, parse_info_hash, AccessMode};
+use crate::accounting::{Account, Ledger, Totals};
+use crate::http::{percent_decode, Handler, Request, Response};
+use crate::protocol::InfoHash;
+use crate::swarm::{SharedSwarm, TorrentInfo};
+
+
+const JSON: &str = "application/json";
+
+
+/// Write bytes as lowercase hex
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Compare two byte strings in time that only depends on their lengths,
+/// so that the token can't be guessed a byte at a time
+fn same_bytes(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// A JSON object with a torrent's counts, without the closing brace
+fn open_torrent(out: &mut String, info_hash: &InfoHash, info: &TorrentInfo) {
+    let counts = info.scrape_info();
+    let _ = write!(
+        out,
+        "{{\"info_hash\":\"{}\",\"seeders\":{},\"leechers\":{},\"completed\":{}",
+        format_info_hash(info_hash), counts.seeders, counts.leechers, counts.completed
+    );
+}
+
+/// Write a string as a JSON string, with quotes
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A JSON object with an account's totals
+fn account_json(account: &Account, totals: Totals) -> String {
+    let ratio = totals.ratio().map_or("null".to_string(), |ratio| ratio.to_string());
+    format!(
+        "{{\"account\":{},\"uploaded\":{},\"downloaded\":{},\"ratio\":{}}}",
+        json_string(&account.to_string()), totals.uploaded, totals.downloaded, ratio
+    )
+}
+
+/// Give one account, written as in the accounting file, or as a bare passkey
+fn user(ledger: &Ledger, account: &str) -> Response {
+    let account = match segment(account) {
+        Some(account) => account.parse().unwrap_or(Account::Passkey(account)),
+        None => return Response::text(400, "invalid account")
+    };
+    match ledger.find(&account) {
+        Some(totals) => Response::ok(JSON, account_json(&account, totals).into_bytes()),
+        None => Response::text(404, "unknown account")
+    }
+}
+
+/// Decode one segment of a path, which might be percent encoded
+fn segment(s: &str) -> Option<String> {
+    String::from_utf8(percent_decode(s.as_bytes())?).ok()
+}
+
+/// Answer a request for the info hash in a path segment, if it's valid
+fn with_hash(hash: &str, f: impl FnOnce(&InfoHash) -> Response) -> Response {
+    match segment(hash).as_deref().and_then(parse_info_hash) {
+        Some(info_hash) => f(&info_hash),
+        None => Response::text(400, "invalid info hash")
+    }
+}
+
+
+/// Serves the admin API for a swarm
+pub struct AdminHandler {
+    swarm: SharedSwarm,
+    token: String,
+    access_list: Option<PathBuf>,
+    ledger: Option<Arc<Ledger>>
+}
+
+impl AdminHandler {
+    /// Serve the admin API for a swarm, to clients with a certain token
+    pub fn new(swarm: SharedSwarm, token: impl Into<String>) -> Self {
+        AdminHandler { swarm, token: token.into(), access_list: None, ledger: None }
+    }
+
+    /// Save whitelist edits to the file the access list is loaded from.
+    /// Without this, they're lost as soon as the list is replaced.
+    pub fn set_access_list(&mut self, path: impl Into<PathBuf>) {
+        self.access_list = Some(path.into());
+    }
+
+    /// Serve the upload and download totals kept in a ledger
+    pub fn set_ledger(&mut self, ledger: Arc<Ledger>) {
+        self.ledger = Some(ledger);
+    }
+
+    /// Whether or not a request carries our token
+    fn authorized(&self, req: &Request) -> bool {
+        req.headers.iter()
+            .filter(|(name, _)| name == "authorization")
+            .filter_map(|(_, value)| value.strip_prefix("Bearer "))
+            .any(|token| same_bytes(token.trim().as_bytes(), self.token.as_bytes()))
+    }
+
+    fn stats(&self) -> Response {
+        let (mut torrents, mut seeders, mut leechers, mut completed) = (0, 0u64, 0u64, 0u64);
+        self.swarm.for_each_shard(|swarm| for (_, info) in swarm.torrents() {
+            let counts = info.scrape_info();
+            torrents += 1;
+            seeders += counts.seeders as u64;
+            leechers += counts.leechers as u64;
+            completed += counts.completed as u64;
+        });
+        let body = format!(
+            "{{\"torrents\":{},\"peers\":{},\"seeders\":{},\"leechers\":{},\"completed\":{}}}",
+            torrents, seeders + leechers, seeders, leechers, completed
+        );
+        Response::ok(JSON, body.into_bytes())
+    }
+
+    fn torrents(&self) -> Response {
+        let mut body = String::from("[");
+        self.swarm.for_each_shard(|swarm| for (info_hash, info) in swarm.torrents() {
+            if body.len() > 1 {
+                body.push(',');
+            }
+            open_torrent(&mut body, info_hash, info);
+            body.push('}');
+        });
+        body.push(']');
+        Response::ok(JSON, body.into_bytes())
+    }
+
+    fn torrent(&self, info_hash: &InfoHash) -> Response {
+        let swarm = self.swarm.lock(info_hash);
+        let info = match swarm.torrent(info_hash) {
+            Some(info) => info,
+            None => return Response::text(404, "unknown torrent")
+        };
+        let now = Instant::now();
+        let mut body = String::new();
+        open_torrent(&mut body, info_hash, info);
+        body.push_str(",\"peers\":[");
+        for (i, (addr, peer)) in info.peers().enumerate() {
+            if i > 0 {
+                body.push(',');
+            }
+            let _ = write!(
+                body,
+                "{{\"addr\":\"{}\",\"peer_id\":\"{}\",\"key\":{},\"seeder\":{},\"left\":{},\"uploaded\":{},\"downloaded\":{},\"last_announce_secs\":{}}}",
+                addr, hex(peer.peer_id()), peer.key(), peer.is_seeder(), peer.left(), peer.uploaded(), peer.downloaded(),
+                now.saturating_duration_since(peer.last_announce()).as_secs()
+            );
+        }
+        body.push_str("]}");
+        Response::ok(JSON, body.into_bytes())
+    }
+
+    fn remove_torrent(&self, info_hash: &InfoHash) -> Response {
+        if !self.swarm.lock(info_hash).remove_torrent(info_hash) {
+            return Response::text(404, "unknown torrent");
+        }
+        Response::ok(JSON, b"{\"removed\":true}".to_vec())
+    }
+
+    fn remove_peer(&self, info_hash: &InfoHash, addr: &str) -> Response {
+        let addr: SocketAddr = match segment(addr).and_then(|s| s.parse().ok()) {
+            Some(addr) => addr,
+            None => return Response::text(400, "invalid peer address")
+        };
+        if !self.swarm.lock(info_hash).remove_peer(info_hash, addr) {
+            return Response::text(404, "unknown peer");
+        }
+        Response::ok(JSON, b"{\"removed\":true}".to_vec())
+    }
+
+    fn whitelist(&self) -> Response {
+        let access = self.swarm.access().read();
+        let hashes: Vec<String> = access.hashes().map(|h| format!("\"{}\"", format_info_hash(h))).collect();
+        Response::ok(JSON, format!("[{}]", hashes.join(",")).into_bytes())
+    }
+
+    /// Add or remove a whitelist entry, saving it to the file first
+    fn edit_whitelist(&self, info_hash: &InfoHash, add: bool) -> Response {
+        // Holding the lock keeps the file in the same order as the list
+        let mut access = self.swarm.access().write();
+        if let Some(path) = &self.access_list {
+            if let Err(e) = access::edit_file(path, info_hash, add) {
+                return Response::text(500, &format!("couldn't save the access list: {}", e));
+            }
+        }
+        let changed = if add { access.insert(*info_hash) } else { access.remove(info_hash) };
+        let body = format!("{{\"{}\":{}}}", if add { "added" } else { "removed" }, changed);
+        Response::ok(JSON, body.into_bytes())
+    }
+
+    /// Answer a request under `/users`, with the rest of its path
+    fn users(&self, method: &str, rest: &[&str]) -> Response {
+        let ledger = match &self.ledger {
+            Some(ledger) => ledger,
+            None => return Response::text(400, "the tracker isn't keeping accounts")
+        };
+        match (method, rest) {
+            ("GET", []) => {
+                let users: Vec<String> = ledger.users().iter().map(|(account, totals)| account_json(account, *totals)).collect();
+                Response::ok(JSON, format!("[{}]", users.join(",")).into_bytes())
+            }
+            ("GET", [account]) => user(ledger, account),
+            (_, []) | (_, [_]) => Response::text(405, "method not allowed"),
+            _ => Response::text(404, "not found")
+        }
+    }
+}
+
+impl Handler for AdminHandler {
+    fn handle(&self, req: &Request, _: SocketAddr) -> Response {
+        if !self.authorized(req) {
+            return Response::text(401, "missing or wrong admin token");
+        }
+        let parts: Vec<&str> = req.path.trim_matches('/').split('/').collect();
+        let whitelisting = || self.swarm.access().read().mode() == AccessMode::Whitelist;
+        match (req.method.as_str(), parts.as_slice()) {
+            ("GET", ["stats"]) => self.stats(),
+            ("GET", ["torrents"]) => self.torrents(),
+            ("GET", ["torrents", hash]) => with_hash(hash, |h| self.torrent(h)),
+            ("DELETE", ["torrents", hash]) => with_hash(hash, |h| self.remove_torrent(h)),
+            ("DELETE", ["torrents", hash, "peers", addr]) => with_hash(hash, |h| self.remove_peer(h, addr)),
+            (_, ["whitelist", ..]) if !whitelisting() => Response::text(400, "the tracker isn't using a whitelist"),
+            ("GET", ["whitelist"]) => self.whitelist(),
+            ("PUT", ["whitelist", hash]) => with_hash(hash, |h| self.edit_whitelist(h, true)),
+            ("DELETE", ["whitelist", hash]) => with_hash(hash, |h| self.edit_whitelist(h, false)),
+            (method, ["users", rest @ ..]) => self.users(method, rest),
+            (_, ["stats"]) | (_, ["torrents", ..]) | (_, ["whitelist", ..]) => {
+                Response::text(405, "method not allowed")
+            }
+            _ => Response::text(404, "not found")
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::AccessList;
+    use crate::protocol::AnnounceEvent;
+    use crate::swarm::PeerAnnounce;
+    use std::time::Duration;
+
+    const HASH: &str = "0101010101010101010101010101010101010101";
+
+    fn request(method: &str, path: &str, token: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: Vec::new(),
+            headers: vec![("authorization".to_string(), format!("Bearer {}", token))]
+        }
+    }
+
+    fn swarm() -> SharedSwarm {
+        let swarm = SharedSwarm::default();
+        let announce = PeerAnnounce {
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            key: 0,
+            downloaded: 0,
+            left: 0,
+            uploaded: 0,
+            event: AnnounceEvent::Started
+        };
+        let now = Instant::now();
+        swarm.lock(&[1; 20]).announce("1.1.1.1:1".parse().unwrap(), &announce, now).unwrap();
+        let leecher = PeerAnnounce { peer_id: [3; 20], left: 10, ..announce };
+        swarm.lock(&[1; 20]).announce("[::1]:2".parse().unwrap(), &leecher, now).unwrap();
+        swarm
+    }
+
+    fn body(response: Response) -> String {
+        String::from_utf8(response.body).unwrap()
+    }
+
+    #[test]
+    fn require_token() {
+        let admin = AdminHandler::new(swarm(), "secret");
+        let src = "127.0.0.1:1".parse().unwrap();
+        assert_eq!(admin.handle(&request("GET", "/stats", "wrong"), src).status, 401);
+        let mut req = request("GET", "/stats", "secret");
+        assert_eq!(admin.handle(&req, src).status, 200);
+        req.headers.clear();
+        assert_eq!(admin.handle(&req, src).status, 401);
+    }
+
+    #[test]
+    fn inspect_torrents() {
+        let admin = AdminHandler::new(swarm(), "secret");
+        let src = "127.0.0.1:1".parse().unwrap();
+        let stats = body(admin.handle(&request("GET", "/stats", "secret"), src));
+        assert_eq!(stats, "{\"torrents\":1,\"peers\":2,\"seeders\":1,\"leechers\":1,\"completed\":0}");
+        let torrents = body(admin.handle(&request("GET", "/torrents", "secret"), src));
+        assert_eq!(torrents, format!("[{{\"info_hash\":\"{}\",\"seeders\":1,\"leechers\":1,\"completed\":0}}]", HASH));
+        let torrent = body(admin.handle(&request("GET", &format!("/torrents/{}", HASH), "secret"), src));
+        assert!(torrent.contains("\"addr\":\"1.1.1.1:1\",\"peer_id\":\"0202"));
+        assert!(torrent.contains("\"addr\":\"[::1]:2\""));
+        let unknown = format!("/torrents/{}", HASH.replace('1', "2"));
+        assert_eq!(admin.handle(&request("GET", &unknown, "secret"), src).status, 404);
+        assert_eq!(admin.handle(&request("GET", "/torrents/xyz", "secret"), src).status, 400);
+        assert_eq!(admin.handle(&request("POST", "/stats", "secret"), src).status, 405);
+    }
+
+    #[test]
+    fn remove_from_swarm() {
+        let swarm = swarm();
+        let admin = AdminHandler::new(swarm.clone(), "secret");
+        let src = "127.0.0.1:1".parse().unwrap();
+        let peer = format!("/torrents/{}/peers/%5B::1%5D:2", HASH);
+        assert_eq!(admin.handle(&request("DELETE", &peer, "secret"), src).status, 200);
+        assert_eq!(admin.handle(&request("DELETE", &peer, "secret"), src).status, 404);
+        assert_eq!(swarm.scrape(&[1; 20]).leechers, 0);
+        let torrent = format!("/torrents/{}", HASH);
+        assert_eq!(admin.handle(&request("DELETE", &torrent, "secret"), src).status, 200);
+        assert!(swarm.lock(&[1; 20]).torrent(&[1; 20]).is_none());
+    }
+
+    #[test]
+    fn edit_whitelist() {
+        let swarm = SharedSwarm::sharded(2, Default::default);
+        let admin = AdminHandler::new(swarm.clone(), "secret");
+        let src = "127.0.0.1:1".parse().unwrap();
+        let entry = format!("/whitelist/{}", HASH);
+        assert_eq!(admin.handle(&request("PUT", &entry, "secret"), src).status, 400);
+        swarm.set_access(AccessList::new(AccessMode::Whitelist, Default::default()));
+        assert_eq!(body(admin.handle(&request("PUT", &entry, "secret"), src)), "{\"added\":true}");
+        assert_eq!(body(admin.handle(&request("GET", "/whitelist", "secret"), src)), format!("[\"{}\"]", HASH));
+        swarm.for_each_shard(|swarm| assert!(swarm.access().read().allows(&[1; 20])));
+        assert_eq!(body(admin.handle(&request("DELETE", &entry, "secret"), src)), "{\"removed\":true}");
+        assert!(!swarm.lock(&[1; 20]).access().read().allows(&[1; 20]));
+    }
+
+    #[test]
+    fn query_accounts() {
+        let mut admin = AdminHandler::new(swarm(), "secret");
+        let src = "127.0.0.1:1".parse().unwrap();
+        assert_eq!(admin.handle(&request("GET", "/users", "secret"), src).status, 400);
+        let ledger = Arc::new(Ledger::new(Duration::from_secs(1800)));
+        let announce = PeerAnnounce {
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            key: 0,
+            downloaded: 400,
+            left: 0,
+            uploaded: 100,
+            event: AnnounceEvent::Started
+        };
+        ledger.record(Account::Passkey("a\"b".to_string()), &announce, Instant::now());
+        admin.set_ledger(ledger);
+        let expected = "{\"account\":\"passkey:a\\\"b\",\"uploaded\":100,\"downloaded\":400,\"ratio\":0.25}";
+        assert_eq!(body(admin.handle(&request("GET", "/users/a%22b", "secret"), src)), expected);
+        assert_eq!(body(admin.handle(&request("GET", "/users/passkey:a%22b", "secret"), src)), expected);
+        assert_eq!(body(admin.handle(&request("GET", "/users", "secret"), src)), format!("[{}]", expected));
+        assert_eq!(admin.handle(&request("GET", "/users/key:00000001", "secret"), src).status, 404);
+        assert_eq!(admin.handle(&request("DELETE", "/users/a", "secret"), src).status, 405);
+    }
+
+    #[test]
+    fn save_whitelist_edits() {
+        let path = std::env::temp_dir().join(format!("bittrickle-admin-{}.txt", std::process::id()));
+        std::fs::write(&path, "# our torrents\n").unwrap();
+        let swarm = SharedSwarm::default();
+        swarm.set_access(AccessList::from_file(AccessMode::Whitelist, &path).unwrap());
+        let mut admin = AdminHandler::new(swarm.clone(), "secret");
+        admin.set_access_list(&path);
+        let src = "127.0.0.1:1".parse().unwrap();
+        let entry = format!("/whitelist/{}", HASH);
+        assert_eq!(admin.handle(&request("PUT", &entry, "secret"), src).status, 200);
+        // Reloading the file, as happens when it changes, keeps the edit
+        let reloaded = AccessList::from_file(AccessMode::Whitelist, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(reloaded.contains(&[1; 20]));
+        assert!(swarm.access().read().contains(&[1; 20]));
+    }
+}
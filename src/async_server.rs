@@ -0,0 +1,120 @@
+//! A UDP tracker running on tokio, for processes that are already async.
+//!
+//! This drives the same `Tracker` as `server::Server`, so packets are parsed
+//! and answered the same way by both, and the two can share a swarm.
+//! It's only built with the `tokio` feature.
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::time;
+
+use crate::protocol::Writable;
+use crate::swarm::SharedSwarm;
+use crate::tracker::{Tracker, SWEEP_INTERVAL};
+use crate::transport::is_socket_error;
+
+
+/// A server answering one UDP socket on a tokio runtime
+pub struct AsyncServer {
+    socket: UdpSocket,
+    tracker: Tracker,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>
+}
+
+impl AsyncServer {
+    /// Bind a socket to serve a tracker on.
+    /// This needs to be called from within a tokio runtime.
+    pub async fn bind(addr: impl ToSocketAddrs, tracker: Tracker) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        Ok(AsyncServer::with_socket(socket, tracker))
+    }
+
+    /// Serve a tracker on a socket that's already bound
+    pub fn with_socket(socket: UdpSocket, tracker: Tracker) -> Self {
+        // Responses are kept within the datagram budget by the tracker
+        let settings = tracker.settings();
+        let write_buf = vec![0; settings.max_datagram_v4.max(settings.max_datagram_v6)];
+        AsyncServer { socket, tracker, read_buf: vec![0; 2048], write_buf }
+    }
+
+    /// Get a handle to the swarm this server uses, to share it with other frontends
+    pub fn swarm(&self) -> SharedSwarm {
+        self.tracker.swarm()
+    }
+
+    /// The address the socket is bound to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Serve requests until the socket fails.
+    ///
+    /// The future can be spawned, and is cancelled by dropping it or aborting
+    /// its task. Cancelling it while it's sending loses that one response,
+    /// but never leaves the swarm half changed.
+    pub async fn run(mut self) -> io::Result<()> {
+        let metrics = Arc::clone(self.tracker.metrics());
+        loop {
+            // We need to wake up regularly to sweep, even without traffic
+            let received = time::timeout(SWEEP_INTERVAL, self.socket.recv_from(&mut self.read_buf)).await;
+            let now = Instant::now();
+            if let Ok(received) = received {
+                let (count, src) = received?;
+                metrics.record_received(count);
+                if let Some(response) = self.tracker.handle_packet(src, &self.read_buf[..count], now) {
+                    let count = response.write(&mut self.write_buf);
+                    metrics.record_sent(count);
+                    match self.socket.send_to(&self.write_buf[..count], src).await {
+                        Ok(_) => {}
+                        Err(e) if is_socket_error(&e) => return Err(e),
+                        Err(_) => metrics.record_send_failures(1)
+                    }
+                }
+            }
+            self.tracker.maintain(now);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Announce, TrackerClient};
+    use crate::protocol::AnnounceEvent;
+    use crate::server::Server;
+    use crate::tracker::Settings;
+    use std::thread;
+
+    #[tokio::test]
+    async fn share_a_swarm() {
+        let mut blocking = Server::new("127.0.0.1:0", Settings::default()).unwrap();
+        let tracker = Tracker::with_swarm(Settings::default(), blocking.swarm());
+        let server = AsyncServer::bind("127.0.0.1:0", tracker).await.unwrap();
+        let (blocking_addr, async_addr) = (blocking.local_addr().unwrap(), server.local_addr().unwrap());
+        thread::spawn(move || blocking.run());
+        let task = tokio::spawn(server.run());
+        // Announce to the async server, then scrape the blocking one
+        let scrapes = tokio::task::spawn_blocking(move || {
+            let announce = Announce {
+                info_hash: [1; 20],
+                peer_id: [2; 20],
+                downloaded: 0,
+                left: 0,
+                uploaded: 0,
+                event: AnnounceEvent::Started,
+                key: 0,
+                num_want: -1,
+                port: 6881
+            };
+            TrackerClient::new(async_addr).unwrap().announce(&announce).unwrap();
+            TrackerClient::new(blocking_addr).unwrap().scrape(&[[1; 20]]).unwrap()
+        }).await.unwrap();
+        assert_eq!(scrapes[0].seeders, 1);
+        task.abort();
+        assert!(task.await.unwrap_err().is_cancelled());
+    }
+}
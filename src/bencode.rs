@@ -0,0 +1,613 @@
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::io::{self, Write};
+
+
+/// How deeply lists and dictionaries can be nested before we give up
+const MAX_DEPTH: usize = 64;
+
+
+/// Represents the ways decoding can fail.
+/// Each variant holds the byte offset where the problem was found.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    /// The input ended in the middle of a value
+    UnexpectedEnd(usize),
+    /// This byte can't start a value
+    UnexpectedByte(usize),
+    /// An integer was malformed, or not in canonical form
+    InvalidInteger(usize),
+    /// The length of a byte string was malformed, or not in canonical form
+    InvalidLength(usize),
+    /// A dictionary key wasn't a byte string
+    NonStringKey(usize),
+    /// A dictionary key wasn't strictly greater than the one before it
+    UnsortedKey(usize),
+    /// Values were nested deeper than we allow
+    TooDeep(usize),
+    /// There were more bytes after a complete value
+    TrailingBytes(usize)
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (message, offset) = match *self {
+            DecodeError::UnexpectedEnd(o) => ("unexpected end of input", o),
+            DecodeError::UnexpectedByte(o) => ("unexpected byte", o),
+            DecodeError::InvalidInteger(o) => ("invalid integer", o),
+            DecodeError::InvalidLength(o) => ("invalid byte string length", o),
+            DecodeError::NonStringKey(o) => ("dictionary key isn't a byte string", o),
+            DecodeError::UnsortedKey(o) => ("dictionary keys aren't sorted", o),
+            DecodeError::TooDeep(o) => ("values nested too deeply", o),
+            DecodeError::TrailingBytes(o) => ("trailing bytes after value", o)
+        };
+        write!(f, "{} at byte {}", message, offset)
+    }
+}
+
+impl error::Error for DecodeError {}
+
+
+/// Represents the ways encoding can fail
+#[derive(Debug)]
+pub enum EncodeError {
+    /// The underlying writer failed
+    Io(io::Error),
+    /// A value was written where a dictionary key was expected
+    NonStringKey,
+    /// A dictionary key wasn't strictly greater than the one before it
+    UnsortedKey,
+    /// A key was written where a value was expected
+    MissingValue,
+    /// A key was written outside of a dictionary
+    UnexpectedKey,
+    /// `end` was called without an open list or dictionary
+    UnbalancedEnd,
+    /// The encoder was finished with lists or dictionaries still open
+    Unfinished
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::Io(e) => write!(f, "io error: {}", e),
+            EncodeError::NonStringKey => f.write_str("dictionary key isn't a byte string"),
+            EncodeError::UnsortedKey => f.write_str("dictionary keys aren't sorted"),
+            EncodeError::MissingValue => f.write_str("dictionary key without a value"),
+            EncodeError::UnexpectedKey => f.write_str("key outside of a dictionary"),
+            EncodeError::UnbalancedEnd => f.write_str("end without a list or dictionary"),
+            EncodeError::Unfinished => f.write_str("unclosed list or dictionary")
+        }
+    }
+}
+
+impl error::Error for EncodeError {}
+
+impl From<io::Error> for EncodeError {
+    fn from(e: io::Error) -> Self {
+        EncodeError::Io(e)
+    }
+}
+
+
+/// A bencoded value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// A signed integer
+    Int(i64),
+    /// A string of arbitrary bytes
+    Bytes(Vec<u8>),
+    /// A list of values
+    List(Vec<Value>),
+    /// A dictionary, which is always kept sorted by key
+    Dict(BTreeMap<Vec<u8>, Value>)
+}
+
+impl Value {
+    /// Create an empty dictionary
+    pub fn dict() -> Self {
+        Value::Dict(BTreeMap::new())
+    }
+
+    /// Insert an entry, if this value is a dictionary.
+    /// This returns the value itself, to allow chaining.
+    pub fn with(mut self, key: &str, value: impl Into<Value>) -> Self {
+        if let Value::Dict(ref mut map) = self {
+            map.insert(key.as_bytes().to_vec(), value.into());
+        }
+        self
+    }
+
+    /// Get the integer inside, if this is one
+    pub fn as_int(&self) -> Option<i64> {
+        match *self {
+            Value::Int(n) => Some(n),
+            _ => None
+        }
+    }
+
+    /// Get the bytes inside, if this is a byte string
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            _ => None
+        }
+    }
+
+    /// Get the values inside, if this is a list
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(l) => Some(l),
+            _ => None
+        }
+    }
+
+    /// Get the entries inside, if this is a dictionary
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Value>> {
+        match self {
+            Value::Dict(d) => Some(d),
+            _ => None
+        }
+    }
+
+    /// Look up a key, if this is a dictionary
+    pub fn get(&self, key: &[u8]) -> Option<&Value> {
+        self.as_dict().and_then(|d| d.get(key))
+    }
+
+    /// Encode this value into a new buffer
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new(Vec::new());
+        // Values are always canonical, and writing to a Vec can't fail
+        encoder.value(self).expect("encoding a value failed");
+        encoder.finish().expect("encoding a value failed")
+    }
+
+    /// Decode a single value, which must take up all of the bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut decoder = Decoder::new(bytes);
+        let value = match decoder.next_token()? {
+            Some(token) => decoder.finish_value(token)?,
+            None => return Err(DecodeError::UnexpectedEnd(0))
+        };
+        match decoder.next_token()? {
+            None => Ok(value),
+            Some(_) => Err(DecodeError::TrailingBytes(decoder.position()))
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Int(n)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(n: i32) -> Self {
+        Value::Int(n as i64)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(bytes: Vec<u8>) -> Self {
+        Value::Bytes(bytes)
+    }
+}
+
+impl<'a> From<&'a [u8]> for Value {
+    fn from(bytes: &'a [u8]) -> Self {
+        Value::Bytes(bytes.to_vec())
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(s: &'a str) -> Self {
+        Value::Bytes(s.as_bytes().to_vec())
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(values: Vec<Value>) -> Self {
+        Value::List(values)
+    }
+}
+
+impl From<BTreeMap<Vec<u8>, Value>> for Value {
+    fn from(map: BTreeMap<Vec<u8>, Value>) -> Self {
+        Value::Dict(map)
+    }
+}
+
+
+/// Where we are inside of an open list or dictionary
+#[derive(Debug)]
+enum EncodeFrame {
+    List,
+    Dict { last_key: Option<Vec<u8>>, expecting_value: bool }
+}
+
+/// Writes bencode piece by piece, checking that the output is canonical.
+///
+/// Dictionary entries are written with `key` followed by a value,
+/// and keys have to be given in strictly increasing order.
+pub struct Encoder<W> {
+    writer: W,
+    stack: Vec<EncodeFrame>
+}
+
+impl<W: Write> Encoder<W> {
+    /// Create an encoder writing to some destination
+    pub fn new(writer: W) -> Self {
+        Encoder { writer, stack: Vec::new() }
+    }
+
+    /// Check that a value can be written here
+    fn before_value(&mut self) -> Result<(), EncodeError> {
+        if let Some(EncodeFrame::Dict { expecting_value, .. }) = self.stack.last_mut() {
+            if !*expecting_value {
+                return Err(EncodeError::NonStringKey);
+            }
+            *expecting_value = false;
+        }
+        Ok(())
+    }
+
+    /// Write an integer
+    pub fn int(&mut self, n: i64) -> Result<(), EncodeError> {
+        self.before_value()?;
+        write!(self.writer, "i{}e", n)?;
+        Ok(())
+    }
+
+    /// Write a byte string
+    pub fn bytes(&mut self, bytes: &[u8]) -> Result<(), EncodeError> {
+        self.before_value()?;
+        write!(self.writer, "{}:", bytes.len())?;
+        self.writer.write_all(bytes)?;
+        Ok(())
+    }
+
+    /// Start a list, which is closed with `end`
+    pub fn begin_list(&mut self) -> Result<(), EncodeError> {
+        self.before_value()?;
+        self.writer.write_all(b"l")?;
+        self.stack.push(EncodeFrame::List);
+        Ok(())
+    }
+
+    /// Start a dictionary, which is closed with `end`
+    pub fn begin_dict(&mut self) -> Result<(), EncodeError> {
+        self.before_value()?;
+        self.writer.write_all(b"d")?;
+        self.stack.push(EncodeFrame::Dict { last_key: None, expecting_value: false });
+        Ok(())
+    }
+
+    /// Write the key of the next dictionary entry
+    pub fn key(&mut self, key: &[u8]) -> Result<(), EncodeError> {
+        match self.stack.last_mut() {
+            Some(EncodeFrame::Dict { last_key, expecting_value }) => {
+                if *expecting_value {
+                    return Err(EncodeError::MissingValue);
+                }
+                if last_key.as_ref().is_some_and(|last| last.as_slice() >= key) {
+                    return Err(EncodeError::UnsortedKey);
+                }
+                *last_key = Some(key.to_vec());
+                *expecting_value = true;
+            }
+            _ => return Err(EncodeError::UnexpectedKey)
+        }
+        write!(self.writer, "{}:", key.len())?;
+        self.writer.write_all(key)?;
+        Ok(())
+    }
+
+    /// Close the innermost list or dictionary
+    pub fn end(&mut self) -> Result<(), EncodeError> {
+        match self.stack.pop() {
+            Some(EncodeFrame::Dict { expecting_value: true, .. }) => Err(EncodeError::MissingValue),
+            Some(_) => {
+                self.writer.write_all(b"e")?;
+                Ok(())
+            }
+            None => Err(EncodeError::UnbalancedEnd)
+        }
+    }
+
+    /// Write an entire value
+    pub fn value(&mut self, value: &Value) -> Result<(), EncodeError> {
+        match value {
+            Value::Int(n) => self.int(*n),
+            Value::Bytes(b) => self.bytes(b),
+            Value::List(values) => {
+                self.begin_list()?;
+                for v in values {
+                    self.value(v)?;
+                }
+                self.end()
+            }
+            Value::Dict(map) => {
+                self.begin_dict()?;
+                for (k, v) in map {
+                    self.key(k)?;
+                    self.value(v)?;
+                }
+                self.end()
+            }
+        }
+    }
+
+    /// Finish encoding, giving back the writer
+    pub fn finish(self) -> Result<W, EncodeError> {
+        if !self.stack.is_empty() {
+            return Err(EncodeError::Unfinished);
+        }
+        Ok(self.writer)
+    }
+}
+
+
+/// A single piece of bencode, as read by a `Decoder`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token<'a> {
+    /// An integer
+    Int(i64),
+    /// A byte string, borrowed from the input
+    Bytes(&'a [u8]),
+    /// The start of a list
+    List,
+    /// The start of a dictionary
+    Dict,
+    /// The end of the innermost list or dictionary
+    End
+}
+
+/// Where we are inside of an open list or dictionary
+#[derive(Debug)]
+enum DecodeFrame<'a> {
+    List,
+    Dict { last_key: Option<&'a [u8]>, expecting_value: bool }
+}
+
+/// Reads bencode token by token, rejecting anything that isn't canonical.
+///
+/// The input has to hold exactly one value: once that value is done,
+/// `next_token` returns `None`, or an error if bytes are left over.
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    stack: Vec<DecodeFrame<'a>>,
+    started: bool
+}
+
+impl<'a> Decoder<'a> {
+    /// Create a decoder over some bytes
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Decoder { bytes, pos: 0, stack: Vec::new(), started: false }
+    }
+
+    /// The offset of the next byte to be read
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    fn peek(&self) -> Result<u8, DecodeError> {
+        self.bytes.get(self.pos).cloned().ok_or(DecodeError::UnexpectedEnd(self.pos))
+    }
+
+    /// Read the digits of a number up to a terminator, in canonical form
+    fn read_number(&mut self, terminator: u8, signed: bool) -> Option<(i64, usize)> {
+        let start = self.pos;
+        let rest = &self.bytes[start..];
+        let end = rest.iter().position(|&b| b == terminator)?;
+        let digits = &rest[..end];
+        let unsigned = if signed && digits.first() == Some(&b'-') { &digits[1..] } else { digits };
+        let canonical = !unsigned.is_empty()
+            && unsigned.iter().all(u8::is_ascii_digit)
+            && (unsigned[0] != b'0' || unsigned.len() == 1)
+            && !(unsigned.len() < digits.len() && unsigned == b"0");
+        if !canonical {
+            return None;
+        }
+        let n = std::str::from_utf8(digits).ok()?.parse().ok()?;
+        Some((n, end + 1))
+    }
+
+    /// Read the next token, or `None` once the value is complete
+    pub fn next_token(&mut self) -> Result<Option<Token<'a>>, DecodeError> {
+        if self.started && self.stack.is_empty() {
+            if self.pos < self.bytes.len() {
+                return Err(DecodeError::TrailingBytes(self.pos));
+            }
+            return Ok(None);
+        }
+        self.started = true;
+        let start = self.pos;
+        let byte = self.peek()?;
+        let in_key_position = match self.stack.last() {
+            Some(DecodeFrame::Dict { expecting_value, .. }) => !expecting_value,
+            _ => false
+        };
+        if in_key_position && byte != b'e' && !byte.is_ascii_digit() {
+            return Err(DecodeError::NonStringKey(start));
+        }
+        let token = match byte {
+            b'i' => {
+                self.pos += 1;
+                let (n, used) = self.read_number(b'e', true)
+                    .ok_or(DecodeError::InvalidInteger(start))?;
+                self.pos += used;
+                Token::Int(n)
+            }
+            b'0'..=b'9' => {
+                let (len, used) = self.read_number(b':', false)
+                    .ok_or(DecodeError::InvalidLength(start))?;
+                self.pos += used;
+                let len = len as usize;
+                if self.bytes.len() - self.pos < len {
+                    return Err(DecodeError::UnexpectedEnd(self.bytes.len()));
+                }
+                let bytes = &self.bytes[self.pos..self.pos + len];
+                self.pos += len;
+                Token::Bytes(bytes)
+            }
+            b'l' | b'd' => {
+                if self.stack.len() >= MAX_DEPTH {
+                    return Err(DecodeError::TooDeep(start));
+                }
+                self.pos += 1;
+                if byte == b'l' { Token::List } else { Token::Dict }
+            }
+            b'e' if !self.stack.is_empty() => {
+                if let Some(DecodeFrame::Dict { expecting_value: true, .. }) = self.stack.last() {
+                    return Err(DecodeError::UnexpectedByte(start));
+                }
+                self.pos += 1;
+                self.stack.pop();
+                return Ok(Some(Token::End));
+            }
+            _ => return Err(DecodeError::UnexpectedByte(start))
+        };
+        // Update the enclosing dictionary, now that we know what we read
+        if let Some(DecodeFrame::Dict { last_key, expecting_value }) = self.stack.last_mut() {
+            if *expecting_value {
+                *expecting_value = false;
+            } else if let Token::Bytes(key) = token {
+                if last_key.is_some_and(|last| last >= key) {
+                    return Err(DecodeError::UnsortedKey(start));
+                }
+                *last_key = Some(key);
+                *expecting_value = true;
+            }
+        }
+        match token {
+            Token::List => self.stack.push(DecodeFrame::List),
+            Token::Dict => self.stack.push(DecodeFrame::Dict { last_key: None, expecting_value: false }),
+            _ => {}
+        }
+        Ok(Some(token))
+    }
+
+    /// Read the rest of a value, given the token it started with
+    fn finish_value(&mut self, token: Token<'a>) -> Result<Value, DecodeError> {
+        match token {
+            Token::Int(n) => Ok(Value::Int(n)),
+            Token::Bytes(b) => Ok(Value::Bytes(b.to_vec())),
+            Token::List => {
+                let mut values = Vec::new();
+                loop {
+                    match self.next_token()? {
+                        Some(Token::End) => return Ok(Value::List(values)),
+                        Some(token) => values.push(self.finish_value(token)?),
+                        None => return Err(DecodeError::UnexpectedEnd(self.pos))
+                    }
+                }
+            }
+            Token::Dict => {
+                let mut map = BTreeMap::new();
+                loop {
+                    let key = match self.next_token()? {
+                        Some(Token::End) => return Ok(Value::Dict(map)),
+                        Some(Token::Bytes(key)) => key.to_vec(),
+                        // The decoder only gives back byte strings as keys
+                        _ => return Err(DecodeError::UnexpectedEnd(self.pos))
+                    };
+                    let value = match self.next_token()? {
+                        Some(token) => self.finish_value(token)?,
+                        None => return Err(DecodeError::UnexpectedEnd(self.pos))
+                    };
+                    map.insert(key, value);
+                }
+            }
+            Token::End => Err(DecodeError::UnexpectedByte(self.pos - 1))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(bytes: &[u8]) {
+        let value = Value::from_bytes(bytes).unwrap();
+        assert_eq!(value.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(b"i0e");
+        round_trip(b"i-42e");
+        round_trip(b"0:");
+        round_trip(b"4:spam");
+        round_trip(b"le");
+        round_trip(b"li1e4:spamlee");
+        round_trip(b"de");
+        round_trip(b"d3:bar4:spam3:fooi42e4:listl1:ai-1eee");
+    }
+
+    #[test]
+    fn builds_values() {
+        let value = Value::dict()
+            .with("b", 1)
+            .with("a", "x")
+            .with("c", vec![Value::from(&b"\x00\xff"[..])]);
+        assert_eq!(value.to_bytes(), b"d1:a1:x1:bi1e1:cl2:\x00\xffee");
+        assert_eq!(value.get(b"b").and_then(Value::as_int), Some(1));
+    }
+
+    #[test]
+    fn rejects_non_canonical() {
+        assert_eq!(Value::from_bytes(b"i03e"), Err(DecodeError::InvalidInteger(0)));
+        assert_eq!(Value::from_bytes(b"i-0e"), Err(DecodeError::InvalidInteger(0)));
+        assert_eq!(Value::from_bytes(b"ie"), Err(DecodeError::InvalidInteger(0)));
+        assert_eq!(Value::from_bytes(b"03:abc"), Err(DecodeError::InvalidLength(0)));
+        assert_eq!(Value::from_bytes(b"d1:bi1e1:ai2ee"), Err(DecodeError::UnsortedKey(7)));
+        assert_eq!(Value::from_bytes(b"d1:ai1e1:ai2ee"), Err(DecodeError::UnsortedKey(7)));
+        assert_eq!(Value::from_bytes(b"di1ei2ee"), Err(DecodeError::NonStringKey(1)));
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        assert_eq!(Value::from_bytes(b""), Err(DecodeError::UnexpectedEnd(0)));
+        assert_eq!(Value::from_bytes(b"l"), Err(DecodeError::UnexpectedEnd(1)));
+        assert_eq!(Value::from_bytes(b"5:abc"), Err(DecodeError::UnexpectedEnd(5)));
+        assert_eq!(Value::from_bytes(b"d1:ae"), Err(DecodeError::UnexpectedByte(4)));
+        assert_eq!(Value::from_bytes(b"i1ei2e"), Err(DecodeError::TrailingBytes(3)));
+        assert_eq!(Value::from_bytes(b"x"), Err(DecodeError::UnexpectedByte(0)));
+        assert_eq!(Value::from_bytes(b"e"), Err(DecodeError::UnexpectedByte(0)));
+        let deep = vec![b'l'; MAX_DEPTH + 1];
+        assert_eq!(Value::from_bytes(&deep), Err(DecodeError::TooDeep(MAX_DEPTH)));
+    }
+
+    #[test]
+    fn streams_tokens() {
+        let mut decoder = Decoder::new(b"d1:ali1eee");
+        assert_eq!(decoder.next_token(), Ok(Some(Token::Dict)));
+        assert_eq!(decoder.next_token(), Ok(Some(Token::Bytes(b"a"))));
+        assert_eq!(decoder.next_token(), Ok(Some(Token::List)));
+        assert_eq!(decoder.next_token(), Ok(Some(Token::Int(1))));
+        assert_eq!(decoder.next_token(), Ok(Some(Token::End)));
+        assert_eq!(decoder.next_token(), Ok(Some(Token::End)));
+        assert_eq!(decoder.next_token(), Ok(None));
+    }
+
+    #[test]
+    fn encoder_enforces_canonical() {
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.begin_dict().unwrap();
+        encoder.key(b"b").unwrap();
+        encoder.int(1).unwrap();
+        assert!(matches!(encoder.key(b"a"), Err(EncodeError::UnsortedKey)));
+        assert!(matches!(encoder.int(2), Err(EncodeError::NonStringKey)));
+        encoder.key(b"c").unwrap();
+        assert!(matches!(encoder.end(), Err(EncodeError::MissingValue)));
+
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.begin_list().unwrap();
+        assert!(matches!(encoder.finish(), Err(EncodeError::Unfinished)));
+    }
+}
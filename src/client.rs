@@ -0,0 +1,391 @@
+use rand::{FromEntropy, Rng, rngs::SmallRng};
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::protocol::{
+    AnnounceEvent, AnnounceOption, AnnounceRequest, AnnounceResponse, ConnectionID, ConnectRequest,
+    InfoHash, PeerId, Response, ScrapeInfo, ScrapeRequest, TransactionID, Writable
+};
+
+
+/// How long a connection id can be used for, as the spec says
+const CONNECTION_LIFETIME: Duration = Duration::from_secs(60);
+
+/// How long we wait for the first response, before doubling
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How many times we double the timeout before giving up, as the spec says
+const MAX_RETRIES: u32 = 8;
+
+/// The longest we wait for a single response, which is as long as the spec ever waits
+const MAX_TIMEOUT: Duration = Duration::from_secs(3840);
+
+
+/// The errors that can happen while talking to a tracker
+#[derive(Debug)]
+pub enum ClientError {
+    /// Something went wrong with the socket
+    Io(io::Error),
+    /// The tracker sent back an error message
+    Tracker(String),
+    /// The tracker never answered, even after retrying
+    TimedOut,
+    /// The tracker answered with the wrong kind of response
+    UnexpectedResponse
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "io error: {}", e),
+            ClientError::Tracker(message) => write!(f, "tracker error: {}", message),
+            ClientError::TimedOut => f.write_str("tracker timed out"),
+            ClientError::UnexpectedResponse => f.write_str("unexpected response")
+        }
+    }
+}
+
+impl error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ClientError::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+
+/// The information a client sends when announcing
+#[derive(Debug, Clone)]
+pub struct Announce {
+    /// The torrent we're announcing
+    pub info_hash: InfoHash,
+    /// Our own peer id
+    pub peer_id: PeerId,
+    /// How many bytes we've downloaded
+    pub downloaded: i64,
+    /// How many bytes we have left to download
+    pub left: i64,
+    /// How many bytes we've uploaded
+    pub uploaded: i64,
+    /// The event we're reporting
+    pub event: AnnounceEvent,
+    /// A key to identify us, even if our address changes
+    pub key: u32,
+    /// How many peers we want, with -1 leaving it up to the tracker
+    pub num_want: i32,
+    /// The port we're listening on
+    pub port: u16
+}
+
+
+/// A client for a single UDP tracker, following BEP 15.
+///
+/// Connection ids are cached for a minute, and requests are retransmitted
+/// after waiting 15 * 2^n seconds, up to n = 8.
+pub struct TrackerClient {
+    socket: UdpSocket,
+    ipv6: bool,
+    rng: SmallRng,
+    connection: Option<(ConnectionID, Instant)>,
+    base_timeout: Duration,
+    max_retries: u32,
+    url_data: Vec<AnnounceOption>,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>
+}
+
+impl TrackerClient {
+    /// Create a client talking to the tracker at a given address
+    pub fn new(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no address for tracker")
+        })?;
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        Ok(TrackerClient {
+            socket,
+            ipv6: addr.is_ipv6(),
+            rng: SmallRng::from_entropy(),
+            connection: None,
+            base_timeout: BASE_TIMEOUT,
+            max_retries: MAX_RETRIES,
+            url_data: Vec::new(),
+            read_buf: vec![0; 2048],
+            write_buf: vec![0; 2048]
+        })
+    }
+
+    /// Change how long we wait before the first retransmission,
+    /// and how many times we double that wait before giving up.
+    /// A single wait never goes over `MAX_TIMEOUT`, however many retries there are.
+    pub fn set_timeouts(&mut self, base_timeout: Duration, max_retries: u32) {
+        self.base_timeout = base_timeout;
+        self.max_retries = max_retries;
+    }
+
+    /// Send the path and query of the tracker's URL with every announce,
+    /// as in BEP 41, so that private trackers can see our passkey
+    pub fn set_url_data(&mut self, path: &[u8]) {
+        self.url_data = AnnounceOption::url_data(path);
+        let needed = 98 + path.len() + 2 * self.url_data.len();
+        if self.write_buf.len() < needed {
+            self.write_buf.resize(needed, 0);
+        }
+    }
+
+    /// Announce ourselves to the tracker, getting back some peers
+    pub fn announce(&mut self, announce: &Announce) -> Result<AnnounceResponse<SocketAddr>, ClientError> {
+        let options = self.url_data.clone();
+        let response = self.request(|connection_id, transaction_id| AnnounceRequest {
+            connection_id,
+            transaction_id,
+            info_hash: announce.info_hash,
+            peer_id: announce.peer_id,
+            downloaded: announce.downloaded,
+            left: announce.left,
+            uploaded: announce.uploaded,
+            event: announce.event,
+            ip: 0,
+            key: announce.key,
+            num_want: announce.num_want,
+            port: announce.port,
+            options: options.clone()
+        })?;
+        match response {
+            Response::Announce(r) => Ok(AnnounceResponse {
+                transaction_id: r.transaction_id,
+                interval: r.interval,
+                leechers: r.leechers,
+                seeders: r.seeders,
+                peers: r.peers.into_iter().map(SocketAddr::V4).collect()
+            }),
+            Response::AnnounceV6(r) => Ok(AnnounceResponse {
+                transaction_id: r.transaction_id,
+                interval: r.interval,
+                leechers: r.leechers,
+                seeders: r.seeders,
+                peers: r.peers.into_iter().map(SocketAddr::V6).collect()
+            }),
+            _ => Err(ClientError::UnexpectedResponse)
+        }
+    }
+
+    /// Get the counts for some torrents, in the same order
+    pub fn scrape(&mut self, info_hashes: &[InfoHash]) -> Result<Vec<ScrapeInfo>, ClientError> {
+        let needed = 16 + 20 * info_hashes.len();
+        if self.write_buf.len() < needed {
+            self.write_buf.resize(needed, 0);
+        }
+        let response = self.request(|connection_id, transaction_id| ScrapeRequest {
+            connection_id,
+            transaction_id,
+            info_hashes: info_hashes.to_vec()
+        })?;
+        match response {
+            Response::Scrape(r) if r.scrapes.len() == info_hashes.len() => Ok(r.scrapes),
+            _ => Err(ClientError::UnexpectedResponse)
+        }
+    }
+
+    /// The connection id we can still use, if any
+    fn live_connection(&self, now: Instant) -> Option<ConnectionID> {
+        match self.connection {
+            Some((id, since)) if now.saturating_duration_since(since) < CONNECTION_LIFETIME => Some(id),
+            _ => None
+        }
+    }
+
+    /// Make a request needing a connection id, connecting first if we need to.
+    /// The retry count is shared between connecting and the request itself.
+    fn request<W: Writable>(&mut self, make: impl Fn(ConnectionID, TransactionID) -> W) -> Result<Response, ClientError> {
+        let mut retries = 0;
+        let transaction_id = TransactionID::new(self.rng.gen());
+        loop {
+            let connection_id = match self.live_connection(Instant::now()) {
+                Some(id) => id,
+                None => self.connect(&mut retries)?
+            };
+            // The id might expire while we wait, so we check it again on every retry
+            if let Some(response) = self.exchange(&make(connection_id, transaction_id), transaction_id, &mut retries)? {
+                if let Response::Error(e) = response {
+                    self.connection = None;
+                    return Err(ClientError::Tracker(e.message));
+                }
+                return Ok(response);
+            }
+        }
+    }
+
+    /// Get a new connection id from the tracker
+    fn connect(&mut self, retries: &mut u32) -> Result<ConnectionID, ClientError> {
+        let transaction_id = TransactionID::new(self.rng.gen());
+        let request = ConnectRequest { connection_id: ConnectionID::magic(), transaction_id };
+        loop {
+            match self.exchange(&request, transaction_id, retries)? {
+                Some(Response::Connect(r)) => {
+                    self.connection = Some((r.connection_id, Instant::now()));
+                    return Ok(r.connection_id);
+                }
+                Some(Response::Error(e)) => return Err(ClientError::Tracker(e.message)),
+                Some(_) => return Err(ClientError::UnexpectedResponse),
+                None => {}
+            }
+        }
+    }
+
+    /// How long to wait for a response after some number of retries
+    fn timeout(&self, retries: u32) -> Duration {
+        2u32.checked_pow(retries)
+            .and_then(|factor| self.base_timeout.checked_mul(factor))
+            .map_or(MAX_TIMEOUT, |timeout| timeout.min(MAX_TIMEOUT))
+    }
+
+    /// Send a request once, and wait for the matching response.
+    /// Returns `None` if we timed out, and should send it again.
+    fn exchange(&mut self, request: &impl Writable, transaction_id: TransactionID, retries: &mut u32) -> Result<Option<Response>, ClientError> {
+        if *retries > self.max_retries {
+            return Err(ClientError::TimedOut);
+        }
+        let count = request.write(&mut self.write_buf);
+        self.socket.send(&self.write_buf[..count])?;
+        let deadline = Instant::now() + self.timeout(*retries);
+        let response = self.receive(transaction_id, deadline)?;
+        if response.is_none() {
+            *retries += 1;
+        }
+        Ok(response)
+    }
+
+    /// Wait until a response with a certain transaction id arrives,
+    /// ignoring anything else, or until the deadline passes
+    fn receive(&mut self, transaction_id: TransactionID, deadline: Instant) -> io::Result<Option<Response>> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Ok(None);
+            }
+            self.socket.set_read_timeout(Some(remaining))?;
+            let amt = match self.socket.recv(&mut self.read_buf) {
+                Ok(amt) => amt,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                    return Ok(None);
+                }
+                // Some systems report ICMP errors from earlier sends here
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                Err(e) => return Err(e)
+            };
+            match Response::from_bytes(&self.read_buf[..amt], self.ipv6) {
+                Ok(response) if response.transaction_id() == transaction_id => return Ok(Some(response)),
+                _ => {}
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::protocol::{ConnectResponse, ErrorResponse, Request};
+    use crate::server::Server;
+    use crate::tracker::Settings;
+
+    fn announce() -> Announce {
+        Announce {
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            downloaded: 0,
+            left: 0,
+            uploaded: 0,
+            event: AnnounceEvent::Started,
+            key: 0,
+            num_want: -1,
+            port: 6881
+        }
+    }
+
+    #[test]
+    fn announce_and_scrape() {
+        let mut server = Server::new("127.0.0.1:0", Settings::default()).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+        let mut client = TrackerClient::new(addr).unwrap();
+        let mut leecher = announce();
+        leecher.left = 10;
+        leecher.port = 6882;
+        assert!(client.announce(&leecher).unwrap().peers.is_empty());
+        let response = client.announce(&announce()).unwrap();
+        assert_eq!((response.seeders, response.leechers), (1, 1));
+        assert_eq!(response.peers, vec!["127.0.0.1:6882".parse().unwrap()]);
+        let scrapes = client.scrape(&[[1; 20], [3; 20]]).unwrap();
+        assert_eq!((scrapes[0].seeders, scrapes[1].seeders), (1, 0));
+    }
+
+    #[test]
+    fn retransmit_after_timeout() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 2048];
+            // Drop the first connect request, answer the retransmission
+            socket.recv_from(&mut buf).unwrap();
+            let (amt, src) = socket.recv_from(&mut buf).unwrap();
+            let transaction_id = match Request::from_bytes(&buf[..amt]) {
+                Ok(Request::Connect(r)) => r.transaction_id,
+                r => panic!("unexpected request {:?}", r)
+            };
+            let connection_id = ConnectionID::new(7);
+            let count = ConnectResponse { transaction_id, connection_id }.write(&mut buf);
+            socket.send_to(&buf[..count], src).unwrap();
+            let (amt, src) = socket.recv_from(&mut buf).unwrap();
+            let transaction_id = match Request::from_bytes(&buf[..amt]) {
+                Ok(Request::Announce(r)) => r.transaction_id,
+                r => panic!("unexpected request {:?}", r)
+            };
+            let count = ErrorResponse::new(transaction_id, "go away").write(&mut buf);
+            socket.send_to(&buf[..count], src).unwrap();
+        });
+        let mut client = TrackerClient::new(addr).unwrap();
+        client.set_timeouts(Duration::from_millis(50), 2);
+        match client.announce(&announce()) {
+            Err(ClientError::Tracker(message)) => assert_eq!(message, "go away"),
+            r => panic!("unexpected result {:?}", r)
+        }
+    }
+
+    #[test]
+    fn cap_timeouts() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut client = TrackerClient::new(socket.local_addr().unwrap()).unwrap();
+        assert_eq!(client.timeout(0), BASE_TIMEOUT);
+        assert_eq!(client.timeout(MAX_RETRIES), MAX_TIMEOUT);
+        client.set_timeouts(Duration::from_secs(1), 100);
+        assert_eq!(client.timeout(3), Duration::from_secs(8));
+        assert_eq!(client.timeout(40), MAX_TIMEOUT);
+        client.set_timeouts(Duration::from_secs(u64::MAX), 1);
+        assert_eq!(client.timeout(1), MAX_TIMEOUT);
+    }
+
+    #[test]
+    fn give_up_eventually() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut client = TrackerClient::new(socket.local_addr().unwrap()).unwrap();
+        client.set_timeouts(Duration::from_millis(10), 1);
+        assert!(matches!(client.scrape(&[[1; 20]]), Err(ClientError::TimedOut)));
+    }
+}
@@ -0,0 +1,568 @@
+use serde::Deserialize;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::access::AccessMode;
+use crate::protocol::ParseLimits;
+use crate::ratelimit::{Limit, RateLimits};
+use crate::tracker::Settings;
+use crate::transport::Backend;
+
+
+/// The largest payload a UDP datagram can carry
+const MAX_UDP_PAYLOAD: usize = 65507;
+
+/// The help text for the command line
+pub const USAGE: &str = "\
+usage: bittrickle [options]
+
+options:
+    -c, --config <path>              read settings from a TOML file
+    --udp <addr>                     serve UDP on this address, can be repeated
+    --http <addr>                    serve HTTP on this address, can be repeated
+    --metrics <addr>                 serve Prometheus metrics over HTTP on this address
+    --full-scrape <bool>             answer HTTP scrapes without info hashes for every torrent
+    --admin <addr>                   serve the admin API over HTTP on this address
+    --admin-token <token>            the token admin API requests must carry
+    --announce-interval <secs>       how long clients should wait between announces
+    --min-interval <secs>            the shortest wait we allow between announces
+    --default-num-want <n>           peers to send when the client doesn't say
+    --max-num-want <n>               the most peers to send in one response
+    --selector <name>                how to pick peers: random, freshest or locality
+    --seeder-share <percent>         how many of the peers sent to leechers should be seeders
+    --max-datagram-v4 <bytes>        the largest UDP response to send over IPv4
+    --max-datagram-v6 <bytes>        the largest UDP response to send over IPv6
+    --peer-timeout <n>               intervals a peer can stay silent before we drop it
+    --connection-lifetime <secs>     how long connection ids stay valid
+    --snapshot <path>                save the swarm here, and load it on startup
+    --snapshot-interval <secs>       how often to save the swarm
+    --access-mode <mode>             which torrents to allow: open, whitelist or blacklist
+    --access-list <path>             the info hashes for the whitelist or blacklist
+    --access-reload-interval <secs>  how often to check the access list for changes
+    --users <path>                   only let in announces with a passkey from this file
+    --accounting <path>              keep each user's upload and download totals in this file
+    --socket-workers <n>             threads reading from each UDP address
+    --swarm-workers <n>              threads handling announces, each owning part of the swarm
+    --udp-backend <name>             how to move UDP packets: std, or mmsg on Linux
+    --max-torrents <n>               the most torrents to track
+    --max-peers <n>                  the most peers to track per torrent
+    --connect-limit-ip <rate>        UDP connects allowed per address, as <per second>/<burst>
+    --connect-limit-prefix <rate>    UDP connects allowed per /24 or /64
+    --announce-limit-ip <rate>       UDP announces allowed per address
+    --announce-limit-prefix <rate>   UDP announces allowed per /24 or /64
+    --scrape-limit-ip <rate>         UDP scrapes allowed per address
+    --scrape-limit-prefix <rate>     UDP scrapes allowed per /24 or /64
+    --over-limit <action>            what to do with requests over a limit: drop or error
+    --max-scrape-hashes <n>          the most torrents a UDP scrape can ask about
+    --scrape-overflow <action>       what to do with scrapes over that: truncate or error
+    --strict-parsing <bool>          reject UDP requests with bytes that don't belong to them
+    --export <format>                print every torrent in the snapshot as json or bencode, and exit
+    --since <unix secs>              with --export, only print torrents that changed since then
+    -h, --help                       print this message
+
+Options given on the command line override the config file.";
+
+
+/// The errors that can happen while loading a config
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file couldn't be read
+    Io(PathBuf, io::Error),
+    /// The config file isn't valid TOML, or has the wrong keys
+    Toml(PathBuf, toml::de::Error),
+    /// The command line didn't make sense
+    Usage(String),
+    /// The settings were read fine, but don't make sense together
+    Invalid(String)
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "couldn't read {}: {}", path.display(), e),
+            ConfigError::Toml(path, e) => write!(f, "invalid config in {}: {}", path.display(), e),
+            ConfigError::Usage(message) => write!(f, "{}", message),
+            ConfigError::Invalid(message) => write!(f, "invalid config: {}", message)
+        }
+    }
+}
+
+impl error::Error for ConfigError {}
+
+
+/// The settings as they appear in a file, or on the command line.
+/// Anything left out keeps its default.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    udp: Option<Vec<SocketAddr>>,
+    http: Option<Vec<SocketAddr>>,
+    metrics: Option<SocketAddr>,
+    full_scrape: Option<bool>,
+    admin: Option<SocketAddr>,
+    admin_token: Option<String>,
+    snapshot: Option<PathBuf>,
+    snapshot_interval: Option<u64>,
+    access_mode: Option<String>,
+    access_list: Option<PathBuf>,
+    access_reload_interval: Option<u64>,
+    users: Option<PathBuf>,
+    accounting: Option<PathBuf>,
+    socket_workers: Option<usize>,
+    swarm_workers: Option<usize>,
+    udp_backend: Option<String>,
+    announce_interval: Option<u64>,
+    min_interval: Option<u64>,
+    default_num_want: Option<usize>,
+    max_num_want: Option<usize>,
+    selector: Option<String>,
+    seeder_share: Option<u8>,
+    max_datagram_v4: Option<usize>,
+    max_datagram_v6: Option<usize>,
+    peer_timeout: Option<u32>,
+    connection_lifetime: Option<u64>,
+    max_torrents: Option<usize>,
+    max_peers: Option<usize>,
+    connect_limit_ip: Option<String>,
+    connect_limit_prefix: Option<String>,
+    announce_limit_ip: Option<String>,
+    announce_limit_prefix: Option<String>,
+    scrape_limit_ip: Option<String>,
+    scrape_limit_prefix: Option<String>,
+    over_limit: Option<String>,
+    max_scrape_hashes: Option<usize>,
+    scrape_overflow: Option<String>,
+    strict_parsing: Option<bool>
+}
+
+impl RawConfig {
+    /// Read the settings from a TOML file
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Toml(path.to_owned(), e))
+    }
+
+    /// Overwrite our settings with every setting `other` has
+    fn merge(&mut self, other: RawConfig) {
+        fn take<T>(ours: &mut Option<T>, theirs: Option<T>) {
+            if theirs.is_some() {
+                *ours = theirs;
+            }
+        }
+        take(&mut self.udp, other.udp);
+        take(&mut self.http, other.http);
+        take(&mut self.metrics, other.metrics);
+        take(&mut self.full_scrape, other.full_scrape);
+        take(&mut self.admin, other.admin);
+        take(&mut self.admin_token, other.admin_token);
+        take(&mut self.snapshot, other.snapshot);
+        take(&mut self.snapshot_interval, other.snapshot_interval);
+        take(&mut self.access_mode, other.access_mode);
+        take(&mut self.access_list, other.access_list);
+        take(&mut self.access_reload_interval, other.access_reload_interval);
+        take(&mut self.users, other.users);
+        take(&mut self.accounting, other.accounting);
+        take(&mut self.socket_workers, other.socket_workers);
+        take(&mut self.swarm_workers, other.swarm_workers);
+        take(&mut self.udp_backend, other.udp_backend);
+        take(&mut self.announce_interval, other.announce_interval);
+        take(&mut self.min_interval, other.min_interval);
+        take(&mut self.default_num_want, other.default_num_want);
+        take(&mut self.max_num_want, other.max_num_want);
+        take(&mut self.selector, other.selector);
+        take(&mut self.seeder_share, other.seeder_share);
+        take(&mut self.max_datagram_v4, other.max_datagram_v4);
+        take(&mut self.max_datagram_v6, other.max_datagram_v6);
+        take(&mut self.peer_timeout, other.peer_timeout);
+        take(&mut self.connection_lifetime, other.connection_lifetime);
+        take(&mut self.max_torrents, other.max_torrents);
+        take(&mut self.max_peers, other.max_peers);
+        take(&mut self.connect_limit_ip, other.connect_limit_ip);
+        take(&mut self.connect_limit_prefix, other.connect_limit_prefix);
+        take(&mut self.announce_limit_ip, other.announce_limit_ip);
+        take(&mut self.announce_limit_prefix, other.announce_limit_prefix);
+        take(&mut self.scrape_limit_ip, other.scrape_limit_ip);
+        take(&mut self.scrape_limit_prefix, other.scrape_limit_prefix);
+        take(&mut self.over_limit, other.over_limit);
+        take(&mut self.max_scrape_hashes, other.max_scrape_hashes);
+        take(&mut self.scrape_overflow, other.scrape_overflow);
+        take(&mut self.strict_parsing, other.strict_parsing);
+    }
+}
+
+
+/// Parse the value following a command line flag
+fn flag_value<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, ConfigError> {
+    let value = value.ok_or_else(|| ConfigError::Usage(format!("{} needs a value", flag)))?;
+    value.parse().map_err(|_| ConfigError::Usage(format!("invalid value for {}: {}", flag, value)))
+}
+
+
+/// Everything the server binary needs to start
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The addresses to serve UDP on
+    pub udp: Vec<SocketAddr>,
+    /// The addresses to serve HTTP on
+    pub http: Vec<SocketAddr>,
+    /// The address to serve metrics on, if anywhere
+    pub metrics: Option<SocketAddr>,
+    /// Whether HTTP scrapes without info hashes get every torrent
+    pub full_scrape: bool,
+    /// The address to serve the admin API on, if anywhere
+    pub admin: Option<SocketAddr>,
+    /// The token admin API requests must carry
+    pub admin_token: Option<String>,
+    /// Where to save the swarm, if anywhere
+    pub snapshot: Option<PathBuf>,
+    /// How often to save the swarm
+    pub snapshot_interval: Duration,
+    /// Which torrents we accept announces for
+    pub access_mode: AccessMode,
+    /// The file listing the torrents for the access mode
+    pub access_list: Option<PathBuf>,
+    /// How often to check the access list for changes
+    pub access_reload_interval: Duration,
+    /// The passkeys of the users allowed to announce, if the tracker is private
+    pub users: Option<PathBuf>,
+    /// Where to keep each user's upload and download totals, if we count them
+    pub accounting: Option<PathBuf>,
+    /// How many threads read from each UDP address
+    pub socket_workers: usize,
+    /// How many threads handle announces, which is also how many shards the swarm has
+    pub swarm_workers: usize,
+    /// How UDP packets are moved in and out of the sockets
+    pub udp_backend: Backend,
+    /// The settings shared by every frontend
+    pub tracker: Settings
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        Config {
+            udp: vec![addr],
+            // HTTP has to be asked for, so that it's never served by surprise
+            http: Vec::new(),
+            metrics: None,
+            full_scrape: false,
+            admin: None,
+            admin_token: None,
+            snapshot: None,
+            snapshot_interval: Duration::from_secs(5 * 60),
+            access_mode: AccessMode::Open,
+            access_list: None,
+            access_reload_interval: Duration::from_secs(60),
+            users: None,
+            accounting: None,
+            socket_workers: 1,
+            swarm_workers: 1,
+            udp_backend: Backend::Std,
+            tracker: Settings::default()
+        }
+    }
+}
+
+impl Config {
+    /// Read a config from a TOML file, then validate it
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        Config::from_raw(RawConfig::from_file(path.as_ref())?)
+    }
+
+    /// Build a config from command line arguments, not including the program name.
+    ///
+    /// If a config file is given, it's read first, and the other
+    /// arguments override what it says.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+        let mut file = None;
+        let mut cli = RawConfig::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            // Addresses can be repeated, so these build up a list
+            fn push(list: &mut Option<Vec<SocketAddr>>, addr: SocketAddr) {
+                list.get_or_insert_with(Vec::new).push(addr);
+            }
+            match arg.as_str() {
+                "-c" | "--config" => file = Some(flag_value::<PathBuf>(&arg, args.next())?),
+                "--udp" => push(&mut cli.udp, flag_value(&arg, args.next())?),
+                "--http" => push(&mut cli.http, flag_value(&arg, args.next())?),
+                "--metrics" => cli.metrics = Some(flag_value(&arg, args.next())?),
+                "--full-scrape" => cli.full_scrape = Some(flag_value(&arg, args.next())?),
+                "--admin" => cli.admin = Some(flag_value(&arg, args.next())?),
+                "--admin-token" => cli.admin_token = Some(flag_value(&arg, args.next())?),
+                "--snapshot" => cli.snapshot = Some(flag_value(&arg, args.next())?),
+                "--snapshot-interval" => cli.snapshot_interval = Some(flag_value(&arg, args.next())?),
+                "--access-mode" => cli.access_mode = Some(flag_value(&arg, args.next())?),
+                "--access-list" => cli.access_list = Some(flag_value(&arg, args.next())?),
+                "--access-reload-interval" => cli.access_reload_interval = Some(flag_value(&arg, args.next())?),
+                "--users" => cli.users = Some(flag_value(&arg, args.next())?),
+                "--accounting" => cli.accounting = Some(flag_value(&arg, args.next())?),
+                "--socket-workers" => cli.socket_workers = Some(flag_value(&arg, args.next())?),
+                "--swarm-workers" => cli.swarm_workers = Some(flag_value(&arg, args.next())?),
+                "--udp-backend" => cli.udp_backend = Some(flag_value(&arg, args.next())?),
+                "--announce-interval" => cli.announce_interval = Some(flag_value(&arg, args.next())?),
+                "--min-interval" => cli.min_interval = Some(flag_value(&arg, args.next())?),
+                "--default-num-want" => cli.default_num_want = Some(flag_value(&arg, args.next())?),
+                "--max-num-want" => cli.max_num_want = Some(flag_value(&arg, args.next())?),
+                "--selector" => cli.selector = Some(flag_value(&arg, args.next())?),
+                "--seeder-share" => cli.seeder_share = Some(flag_value(&arg, args.next())?),
+                "--max-datagram-v4" => cli.max_datagram_v4 = Some(flag_value(&arg, args.next())?),
+                "--max-datagram-v6" => cli.max_datagram_v6 = Some(flag_value(&arg, args.next())?),
+                "--peer-timeout" => cli.peer_timeout = Some(flag_value(&arg, args.next())?),
+                "--connection-lifetime" => cli.connection_lifetime = Some(flag_value(&arg, args.next())?),
+                "--max-torrents" => cli.max_torrents = Some(flag_value(&arg, args.next())?),
+                "--max-peers" => cli.max_peers = Some(flag_value(&arg, args.next())?),
+                "--connect-limit-ip" => cli.connect_limit_ip = Some(flag_value(&arg, args.next())?),
+                "--connect-limit-prefix" => cli.connect_limit_prefix = Some(flag_value(&arg, args.next())?),
+                "--announce-limit-ip" => cli.announce_limit_ip = Some(flag_value(&arg, args.next())?),
+                "--announce-limit-prefix" => cli.announce_limit_prefix = Some(flag_value(&arg, args.next())?),
+                "--scrape-limit-ip" => cli.scrape_limit_ip = Some(flag_value(&arg, args.next())?),
+                "--scrape-limit-prefix" => cli.scrape_limit_prefix = Some(flag_value(&arg, args.next())?),
+                "--over-limit" => cli.over_limit = Some(flag_value(&arg, args.next())?),
+                "--max-scrape-hashes" => cli.max_scrape_hashes = Some(flag_value(&arg, args.next())?),
+                "--scrape-overflow" => cli.scrape_overflow = Some(flag_value(&arg, args.next())?),
+                "--strict-parsing" => cli.strict_parsing = Some(flag_value(&arg, args.next())?),
+                _ => return Err(ConfigError::Usage(format!("unknown argument: {}", arg)))
+            }
+        }
+        let mut raw = match file {
+            Some(path) => RawConfig::from_file(&path)?,
+            None => RawConfig::default()
+        };
+        raw.merge(cli);
+        Config::from_raw(raw)
+    }
+
+    /// Fill in the defaults for anything missing, and validate the result
+    fn from_raw(raw: RawConfig) -> Result<Self, ConfigError> {
+        let defaults = Config::default();
+        let seconds = |s: Option<u64>, default: Duration| s.map_or(default, Duration::from_secs);
+        let selector = match raw.selector {
+            Some(name) => name.parse().map_err(ConfigError::Invalid)?,
+            None => defaults.tracker.selector
+        };
+        let access_mode = match raw.access_mode {
+            Some(name) => name.parse().map_err(ConfigError::Invalid)?,
+            None => defaults.access_mode
+        };
+        let udp_backend = match raw.udp_backend {
+            Some(name) => name.parse().map_err(ConfigError::Invalid)?,
+            None => defaults.udp_backend
+        };
+        let limit = |s: Option<String>| s.map(|s| s.parse::<Limit>()).transpose().map_err(ConfigError::Invalid);
+        let rate_limits = RateLimits {
+            connect_ip: limit(raw.connect_limit_ip)?,
+            connect_prefix: limit(raw.connect_limit_prefix)?,
+            announce_ip: limit(raw.announce_limit_ip)?,
+            announce_prefix: limit(raw.announce_limit_prefix)?,
+            scrape_ip: limit(raw.scrape_limit_ip)?,
+            scrape_prefix: limit(raw.scrape_limit_prefix)?,
+            over_limit: match raw.over_limit {
+                Some(name) => name.parse().map_err(ConfigError::Invalid)?,
+                None => defaults.tracker.rate_limits.over_limit
+            }
+        };
+        let parse_limits = ParseLimits {
+            max_scrape_hashes: raw.max_scrape_hashes.unwrap_or(defaults.tracker.parse_limits.max_scrape_hashes),
+            scrape_overflow: match raw.scrape_overflow {
+                Some(name) => name.parse().map_err(ConfigError::Invalid)?,
+                None => defaults.tracker.parse_limits.scrape_overflow
+            },
+            strict: raw.strict_parsing.unwrap_or(defaults.tracker.parse_limits.strict)
+        };
+        let tracker = Settings {
+            announce_interval: seconds(raw.announce_interval, defaults.tracker.announce_interval),
+            min_interval: seconds(raw.min_interval, defaults.tracker.min_interval),
+            default_num_want: raw.default_num_want.unwrap_or(defaults.tracker.default_num_want),
+            max_num_want: raw.max_num_want.unwrap_or(defaults.tracker.max_num_want),
+            selector,
+            seeder_share: raw.seeder_share.unwrap_or(defaults.tracker.seeder_share),
+            max_datagram_v4: raw.max_datagram_v4.unwrap_or(defaults.tracker.max_datagram_v4),
+            max_datagram_v6: raw.max_datagram_v6.unwrap_or(defaults.tracker.max_datagram_v6),
+            peer_timeout: raw.peer_timeout.unwrap_or(defaults.tracker.peer_timeout),
+            connection_lifetime: seconds(raw.connection_lifetime, defaults.tracker.connection_lifetime),
+            max_torrents: raw.max_torrents.or(defaults.tracker.max_torrents),
+            max_peers: raw.max_peers.or(defaults.tracker.max_peers),
+            rate_limits,
+            parse_limits
+        };
+        let config = Config {
+            udp: raw.udp.unwrap_or(defaults.udp),
+            http: raw.http.unwrap_or(defaults.http),
+            metrics: raw.metrics.or(defaults.metrics),
+            full_scrape: raw.full_scrape.unwrap_or(defaults.full_scrape),
+            admin: raw.admin.or(defaults.admin),
+            admin_token: raw.admin_token.or(defaults.admin_token),
+            snapshot: raw.snapshot.or(defaults.snapshot),
+            snapshot_interval: seconds(raw.snapshot_interval, defaults.snapshot_interval),
+            access_mode,
+            access_list: raw.access_list.or(defaults.access_list),
+            access_reload_interval: seconds(raw.access_reload_interval, defaults.access_reload_interval),
+            users: raw.users.or(defaults.users),
+            accounting: raw.accounting.or(defaults.accounting),
+            socket_workers: raw.socket_workers.unwrap_or(defaults.socket_workers),
+            swarm_workers: raw.swarm_workers.unwrap_or(defaults.swarm_workers),
+            udp_backend,
+            tracker
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check that the settings make sense together
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: &str| Err(ConfigError::Invalid(message.to_string()));
+        let settings = &self.tracker;
+        if self.udp.is_empty() && self.http.is_empty() {
+            return invalid("there must be at least one udp or http address");
+        }
+        if self.admin.is_some() && self.admin_token.as_deref().is_none_or(str::is_empty) {
+            return invalid("admin_token must be set to serve the admin API");
+        }
+        if self.snapshot_interval.as_secs() == 0 {
+            return invalid("snapshot_interval must be at least 1 second");
+        }
+        if self.access_mode != AccessMode::Open && self.access_list.is_none() {
+            return invalid("access_list must be set for the whitelist and blacklist modes");
+        }
+        if self.access_reload_interval.as_secs() == 0 {
+            return invalid("access_reload_interval must be at least 1 second");
+        }
+        if self.socket_workers == 0 || self.swarm_workers == 0 {
+            return invalid("socket_workers and swarm_workers must be at least 1");
+        }
+        if !self.udp_backend.is_supported() {
+            return invalid(&format!("the {} udp backend isn't supported on this system", self.udp_backend));
+        }
+        if settings.announce_interval.as_secs() == 0 {
+            return invalid("announce_interval must be at least 1 second");
+        }
+        // The UDP protocol sends the interval as an i32
+        if settings.announce_interval.as_secs() > i32::MAX as u64 {
+            return invalid("announce_interval is too large");
+        }
+        if settings.min_interval > settings.announce_interval {
+            return invalid("min_interval can't be longer than announce_interval");
+        }
+        if settings.max_num_want == 0 {
+            return invalid("max_num_want must be at least 1");
+        }
+        if settings.default_num_want > settings.max_num_want {
+            return invalid("default_num_want can't be more than max_num_want");
+        }
+        if settings.seeder_share > 100 {
+            return invalid("seeder_share is a percentage, so it can't be more than 100");
+        }
+        // An announce response has a 20 byte header, then 6 or 18 bytes per peer
+        if settings.max_datagram_v4 < 26 || settings.max_datagram_v6 < 38 {
+            return invalid("max_datagram_v4 and max_datagram_v6 must leave room for at least one peer");
+        }
+        if settings.max_datagram_v4.max(settings.max_datagram_v6) > MAX_UDP_PAYLOAD {
+            return invalid("max_datagram_v4 and max_datagram_v6 can't be more than 65507");
+        }
+        if settings.parse_limits.max_scrape_hashes == 0 {
+            return invalid("max_scrape_hashes must be at least 1");
+        }
+        // A scrape response has an 8 byte header, then 12 bytes per torrent
+        let max_scrape_response = settings.parse_limits.max_scrape_hashes.saturating_mul(12).saturating_add(8);
+        if max_scrape_response > settings.max_datagram_v4.min(settings.max_datagram_v6) {
+            return invalid("max_scrape_hashes is too large for the answer to fit in max_datagram_v4 and max_datagram_v6");
+        }
+        if settings.peer_timeout == 0 {
+            return invalid("peer_timeout must be at least 1");
+        }
+        // Clients keep using a connection id for a minute after getting it
+        if settings.connection_lifetime < Duration::from_secs(60) {
+            return invalid("connection_lifetime must be at least 60 seconds");
+        }
+        if settings.max_torrents == Some(0) || settings.max_peers == Some(0) {
+            return invalid("max_torrents and max_peers must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn parse_toml() {
+        let raw: RawConfig = toml::from_str("udp = [\"[::]:6969\"]\nhttp = []\nmax_peers = 10").unwrap();
+        let config = Config::from_raw(raw).unwrap();
+        assert_eq!(config.udp, vec!["[::]:6969".parse().unwrap()]);
+        assert!(config.http.is_empty());
+        assert_eq!(config.tracker.max_peers, Some(10));
+        assert_eq!(config.tracker.announce_interval, Duration::from_secs(900));
+    }
+
+    #[test]
+    fn reject_unknown_keys() {
+        assert!(toml::from_str::<RawConfig>("interval = 10").is_err());
+    }
+
+    #[test]
+    fn parse_args() {
+        let config = Config::from_args(args("--udp 0.0.0.0:1 --udp [::]:1 --max-num-want 10 --default-num-want 5")).unwrap();
+        assert_eq!(config.udp.len(), 2);
+        assert!(config.http.is_empty());
+        assert_eq!((config.tracker.default_num_want, config.tracker.max_num_want), (5, 10));
+        assert!(Config::from_args(args("--udp")).is_err());
+        assert!(Config::from_args(args("--peer-timeout x")).is_err());
+        assert!(Config::from_args(args("--frobnicate")).is_err());
+    }
+
+    #[test]
+    fn parse_rate_limits() {
+        let raw: RawConfig = toml::from_str("announce_limit_ip = \"0.5/4\"\nover_limit = \"error\"").unwrap();
+        let limits = Config::from_raw(raw).unwrap().tracker.rate_limits;
+        assert_eq!(limits.announce_ip, Some(Limit { rate: 0.5, burst: 4 }));
+        assert_eq!(limits.connect_ip, None);
+        assert_eq!(limits.over_limit, crate::ratelimit::OverLimit::Error);
+        let error = Config::from_args(args("--scrape-limit-prefix 0")).unwrap_err();
+        assert_eq!(error.to_string(), "invalid config: invalid rate limit: 0, expected <per second>/<burst>");
+        assert!(Config::from_args(args("--over-limit ignore")).is_err());
+    }
+
+    #[test]
+    fn parse_limits() {
+        let limits = Config::from_args(args("--strict-parsing true --scrape-overflow error")).unwrap().tracker.parse_limits;
+        assert_eq!(limits.max_scrape_hashes, 74);
+        assert_eq!(limits.scrape_overflow, crate::protocol::ScrapeOverflow::Error);
+        assert!(limits.strict);
+        let error = Config::from_args(args("--scrape-overflow drop")).unwrap_err();
+        assert_eq!(error.to_string(), "invalid config: unknown scrape overflow action: drop");
+        assert!(Config::from_args(args("--max-scrape-hashes 0")).is_err());
+        assert!(Config::from_args(args("--max-scrape-hashes 130")).is_err());
+        assert!(Config::from_args(args("--max-scrape-hashes 130 --max-datagram-v4 1600 --max-datagram-v6 1600")).is_ok());
+    }
+
+    #[test]
+    fn validate_settings() {
+        let error = Config::from_args(args("--default-num-want 500")).unwrap_err();
+        assert_eq!(error.to_string(), "invalid config: default_num_want can't be more than max_num_want");
+        assert!(Config::from_args(args("--announce-interval 30")).is_err());
+        assert!(Config::from_args(args("--connection-lifetime 10")).is_err());
+        assert!(Config::from_args(args("--max-datagram-v6 30")).is_err());
+        assert!(Config::from_args(args("--seeder-share 120")).is_err());
+        assert!(Config::from_args(args("--access-mode whitelist")).is_err());
+        assert!(Config::from_args(args("--swarm-workers 0")).is_err());
+        assert!(Config::from_args(args("--admin 127.0.0.1:9000")).is_err());
+        assert!(Config::from_args(args("--admin 127.0.0.1:9000 --admin-token secret")).is_ok());
+        let error = Config::from_args(args("--udp-backend io_uring")).unwrap_err();
+        assert_eq!(error.to_string(), "invalid config: unknown udp backend: io_uring");
+        let error = Config::from_args(args("--selector nearest")).unwrap_err();
+        assert_eq!(error.to_string(), "invalid config: unknown peer selector: nearest");
+    }
+}
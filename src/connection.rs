@@ -0,0 +1,141 @@
+use rand::{thread_rng, Rng};
+use siphasher::sip::SipHasher24;
+use std::hash::Hasher;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use crate::protocol::ConnectionID;
+
+
+/// A secret key used to sign connection ids
+type Secret = (u64, u64);
+
+fn random_secret() -> Secret {
+    thread_rng().gen()
+}
+
+
+/// Hands out connection ids, and checks the ones clients send back.
+///
+/// Instead of remembering which id each client got, the id is a keyed hash
+/// of the client's address and the current time window. Each window has its
+/// own key, and ids from the current or the previous window are accepted,
+/// so an id stays valid for between one and two windows.
+///
+/// The window keys are derived from a single random master key, so clones
+/// of a validator accept each other's ids, which lets several threads
+/// share clients without sharing a lock.
+#[derive(Clone)]
+pub struct ConnectionValidator {
+    /// How long a single time window lasts
+    window_length: Duration,
+    /// The instant the first window started
+    start: Instant,
+    /// The key every window's secret is derived from
+    master: Secret
+}
+
+impl ConnectionValidator {
+    /// Create a new validator, with windows of a certain length.
+    ///
+    /// BEP 15 asks trackers to accept ids for 2 minutes, which is
+    /// what a `window_length` of 2 minutes guarantees.
+    pub fn new(window_length: Duration, now: Instant) -> Self {
+        ConnectionValidator {
+            window_length,
+            start: now,
+            master: random_secret()
+        }
+    }
+
+    /// The number of the window `now` falls in
+    fn window(&self, now: Instant) -> u64 {
+        let elapsed = now.saturating_duration_since(self.start);
+        (elapsed.as_nanos() / self.window_length.as_nanos().max(1)) as u64
+    }
+
+    /// The secret used to sign ids in a given window
+    fn secret(&self, window: u64) -> Secret {
+        let half = |n: u8| {
+            let mut hasher = SipHasher24::new_with_keys(self.master.0, self.master.1);
+            hasher.write_u64(window);
+            hasher.write_u8(n);
+            hasher.finish()
+        };
+        (half(0), half(1))
+    }
+
+    /// Generate a new connection id for a client
+    pub fn generate(&mut self, src: SocketAddr, now: Instant) -> ConnectionID {
+        let window = self.window(now);
+        sign(self.secret(window), window, src)
+    }
+
+    /// Check if a connection id was given to this client recently enough
+    pub fn validate(&mut self, src: SocketAddr, id: ConnectionID, now: Instant) -> bool {
+        let window = self.window(now);
+        if sign(self.secret(window), window, src) == id {
+            return true;
+        }
+        window > 0 && sign(self.secret(window - 1), window - 1, src) == id
+    }
+}
+
+/// Compute the connection id for an address in a given window
+fn sign(secret: Secret, window: u64, src: SocketAddr) -> ConnectionID {
+    let mut hasher = SipHasher24::new_with_keys(secret.0, secret.1);
+    match src.ip() {
+        IpAddr::V4(ip) => hasher.write(&ip.octets()),
+        IpAddr::V6(ip) => hasher.write(&ip.octets())
+    }
+    hasher.write_u16(src.port());
+    hasher.write_u64(window);
+    ConnectionID::new(hasher.finish() as i64)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(120);
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn accepts_own_ids() {
+        let start = Instant::now();
+        let mut validator = ConnectionValidator::new(WINDOW, start);
+        let src = addr("1.2.3.4:5");
+        let id = validator.generate(src, start);
+        assert!(validator.validate(src, id, start));
+        assert!(validator.validate(src, id, start + WINDOW));
+        assert!(!validator.validate(addr("1.2.3.4:6"), id, start));
+        assert!(!validator.validate(addr("[::1]:5"), id, start));
+    }
+
+    #[test]
+    fn rejects_old_ids() {
+        let start = Instant::now();
+        let mut validator = ConnectionValidator::new(WINDOW, start);
+        let src = addr("1.2.3.4:5");
+        let id = validator.generate(src, start + WINDOW / 2);
+        assert!(!validator.validate(src, id, start + WINDOW * 2));
+        let id = validator.generate(src, start + WINDOW * 2);
+        assert!(!validator.validate(src, id, start + WINDOW * 5));
+    }
+
+    #[test]
+    fn clones_share_ids() {
+        let start = Instant::now();
+        let mut validator = ConnectionValidator::new(WINDOW, start);
+        let mut clone = validator.clone();
+        let src = addr("1.2.3.4:5");
+        let id = validator.generate(src, start);
+        assert!(clone.validate(src, id, start + WINDOW));
+        let other = ConnectionValidator::new(WINDOW, start).generate(src, start);
+        assert!(!clone.validate(src, other, start));
+    }
+}
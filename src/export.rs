@@ -0,0 +1,197 @@
+//! Exporting the counts for every torrent at once, for indexers.
+//!
+//! A normal scrape only answers for the torrents a client asks about.
+//! An export gives every torrent, optionally only those whose counts
+//! changed since some time, either as JSON lines:
+//!
+//! ```text
+//! {"info_hash":"<hex>","seeders":1,"leechers":2,"completed":3}
+//! ```
+//!
+//! or as the bencoded `files` dictionary of a full scrape, keyed by
+//! raw info hash, like a scrape response for every torrent.
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::access::format_info_hash;
+use crate::bencode::{EncodeError, Encoder};
+use crate::protocol::{InfoHash, ScrapeInfo};
+use crate::swarm::SharedSwarm;
+
+
+/// How an export is written out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// One JSON object per torrent, per line
+    JsonLines,
+    /// A bencoded dictionary, as in a full scrape
+    Bencode
+}
+
+impl Format {
+    /// The content type to serve this format with
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::JsonLines => "application/x-ndjson",
+            Format::Bencode => "text/plain"
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::JsonLines),
+            "bencode" => Ok(Format::Bencode),
+            _ => Err(format!("unknown export format: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::JsonLines => f.write_str("json"),
+            Format::Bencode => f.write_str("bencode")
+        }
+    }
+}
+
+
+/// Turn a wall clock time, in seconds since the unix epoch, into an `Instant`.
+/// `now` and `wall_now` should be taken at the same time.
+///
+/// Returns `None` for times before the monotonic clock can go,
+/// which every torrent has changed since.
+pub fn since_unix(secs: u64, now: Instant, wall_now: SystemTime) -> Option<Instant> {
+    let wall_secs = wall_now.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    now.checked_sub(Duration::from_secs(wall_secs.saturating_sub(secs)))
+}
+
+
+/// The counts for every torrent, sorted by info hash.
+/// With `since`, only the torrents whose counts changed since then are kept.
+///
+/// Shards are locked one at a time, and only while copying out their counts.
+pub fn entries(swarm: &SharedSwarm, since: Option<Instant>) -> Vec<(InfoHash, ScrapeInfo)> {
+    let mut entries = Vec::new();
+    swarm.for_each_shard(|shard| {
+        let changed = shard.torrents().filter(|(_, info)| since.is_none_or(|since| info.changed() >= since));
+        entries.extend(changed.map(|(info_hash, info)| (*info_hash, info.scrape_info())));
+    });
+    entries.sort_unstable_by_key(|(info_hash, _)| *info_hash);
+    entries
+}
+
+/// Write out some entries, which need to be sorted by info hash
+pub fn write(entries: &[(InfoHash, ScrapeInfo)], format: Format, out: impl Write) -> io::Result<()> {
+    match format {
+        Format::JsonLines => write_json_lines(entries, out),
+        Format::Bencode => write_bencode(entries, out)
+    }
+}
+
+fn write_json_lines(entries: &[(InfoHash, ScrapeInfo)], mut out: impl Write) -> io::Result<()> {
+    for (info_hash, info) in entries {
+        writeln!(
+            out,
+            "{{\"info_hash\":\"{}\",\"seeders\":{},\"leechers\":{},\"completed\":{}}}",
+            format_info_hash(info_hash), info.seeders, info.leechers, info.completed
+        )?;
+    }
+    out.flush()
+}
+
+fn write_bencode(entries: &[(InfoHash, ScrapeInfo)], out: impl Write) -> io::Result<()> {
+    // Our keys are fixed and sorted, so this can only fail on io
+    encode_files(entries, out).map_err(|e| match e {
+        EncodeError::Io(e) => e,
+        e => io::Error::other(e)
+    })
+}
+
+fn encode_files(entries: &[(InfoHash, ScrapeInfo)], out: impl Write) -> Result<(), EncodeError> {
+    let mut enc = Encoder::new(out);
+    enc.begin_dict()?;
+    enc.key(b"files")?;
+    enc.begin_dict()?;
+    for (info_hash, info) in entries {
+        enc.key(info_hash)?;
+        enc.begin_dict()?;
+        enc.key(b"complete")?;
+        enc.int(info.seeders as i64)?;
+        enc.key(b"downloaded")?;
+        enc.int(info.completed as i64)?;
+        enc.key(b"incomplete")?;
+        enc.int(info.leechers as i64)?;
+        enc.end()?;
+    }
+    enc.end()?;
+    enc.end()?;
+    enc.finish()?.flush()?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::Value;
+    use crate::protocol::AnnounceEvent;
+    use crate::swarm::{PeerAnnounce, Swarm};
+
+    fn swarm(now: Instant) -> SharedSwarm {
+        let swarm = SharedSwarm::sharded(2, Swarm::new);
+        for (hash, when) in [(3u8, now), (1, now + Duration::from_secs(10)), (2, now + Duration::from_secs(20))] {
+            let announce = PeerAnnounce {
+                info_hash: [hash; 20],
+                peer_id: [hash; 20],
+                key: 0,
+                downloaded: 0,
+                left: hash as i64 - 1,
+                uploaded: 0,
+                event: AnnounceEvent::Started
+            };
+            swarm.lock(&[hash; 20]).announce("1.1.1.1:1".parse().unwrap(), &announce, when).unwrap();
+        }
+        swarm
+    }
+
+    #[test]
+    fn filter_by_changes() {
+        let now = Instant::now();
+        let swarm = swarm(now);
+        let hashes = |since| entries(&swarm, since).iter().map(|(hash, _)| hash[0]).collect::<Vec<_>>();
+        assert_eq!(hashes(None), vec![1, 2, 3]);
+        assert_eq!(hashes(Some(now + Duration::from_secs(5))), vec![1, 2]);
+        assert_eq!(hashes(Some(now + Duration::from_secs(30))), Vec::<u8>::new());
+        let wall_now = SystemTime::now();
+        let wall_secs = wall_now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert_eq!(since_unix(wall_secs - 10, now, wall_now), now.checked_sub(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn write_formats() {
+        let all = entries(&swarm(Instant::now()), None);
+        let mut json = Vec::new();
+        write(&all, Format::JsonLines, &mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert_eq!(json.lines().count(), 3);
+        assert_eq!(
+            json.lines().next().unwrap(),
+            format!("{{\"info_hash\":\"{}\",\"seeders\":1,\"leechers\":0,\"completed\":0}}", "01".repeat(20))
+        );
+        let mut bencode = Vec::new();
+        write(&all, Format::Bencode, &mut bencode).unwrap();
+        let value = Value::from_bytes(&bencode).unwrap();
+        let files = value.get(b"files").unwrap().as_dict().unwrap();
+        assert_eq!(files.len(), 3);
+        let file = &files[&vec![2; 20]];
+        assert_eq!(file.get(b"incomplete").and_then(Value::as_int), Some(1));
+        assert_eq!(file.get(b"downloaded").and_then(Value::as_int), Some(0));
+    }
+}
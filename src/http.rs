@@ -0,0 +1,409 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+/// The longest request line or header line we're willing to read
+const MAX_LINE: usize = 8 * 1024;
+
+/// The most headers we're willing to read for a single request
+const MAX_HEADERS: usize = 64;
+
+/// How long a client gets to send its whole request, and then to take our response
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most connections we serve at once on a listener, each on its own thread
+const MAX_CONNECTIONS: usize = 256;
+
+
+/// Decode a `%XX` encoded query component, with `+` standing for a space.
+/// Returns `None` if an escape is malformed.
+pub fn percent_decode(bytes: &[u8]) -> Option<Vec<u8>> {
+    fn hex(byte: u8) -> Option<u8> {
+        match byte {
+            b'0'..=b'9' => Some(byte - b'0'),
+            b'a'..=b'f' => Some(byte - b'a' + 10),
+            b'A'..=b'F' => Some(byte - b'A' + 10),
+            _ => None
+        }
+    }
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                if i + 2 >= bytes.len() {
+                    return None;
+                }
+                out.push(hex(bytes[i + 1])? << 4 | hex(bytes[i + 2])?);
+                i += 3;
+            }
+            b'+' => {
+                out.push(b' ');
+                i += 1;
+            }
+            byte => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Some(out)
+}
+
+
+/// A parsed HTTP request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    /// The method, such as `GET`
+    pub method: String,
+    /// The path, without the query string
+    pub path: String,
+    /// The decoded query parameters, in the order they appeared
+    pub query: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The headers, with lowercased names
+    pub headers: Vec<(String, String)>
+}
+
+impl Request {
+    /// Parse the target of a request line, such as `/announce?port=1`
+    fn parse_target(method: String, target: &str) -> Option<Self> {
+        let (path, query_string) = match target.find('?') {
+            Some(i) => (&target[..i], &target[i + 1..]),
+            None => (target, "")
+        };
+        let mut query = Vec::new();
+        for part in query_string.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = match part.find('=') {
+                Some(i) => (&part[..i], &part[i + 1..]),
+                None => (part, "")
+            };
+            query.push((percent_decode(key.as_bytes())?, percent_decode(value.as_bytes())?));
+        }
+        Some(Request {
+            method,
+            path: path.to_string(),
+            query,
+            headers: Vec::new()
+        })
+    }
+
+    /// Read a request from a stream, up to the end of its headers.
+    /// We only deal with bodiless requests, so the body is never read.
+    pub fn read_from(reader: &mut impl BufRead) -> io::Result<Self> {
+        let line = read_line(reader)?;
+        let mut words = line.split_whitespace();
+        let (method, target) = match (words.next(), words.next(), words.next()) {
+            (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/") => {
+                (method.to_string(), target)
+            }
+            _ => return Err(invalid_data("malformed request line"))
+        };
+        let mut request = Request::parse_target(method, target)
+            .ok_or_else(|| invalid_data("malformed request target"))?;
+        loop {
+            let line = read_line(reader)?;
+            if line.is_empty() {
+                break;
+            }
+            if request.headers.len() >= MAX_HEADERS {
+                return Err(invalid_data("too many headers"));
+            }
+            let i = line.find(':').ok_or_else(|| invalid_data("malformed header"))?;
+            let name = line[..i].trim().to_ascii_lowercase();
+            let value = line[i + 1..].trim().to_string();
+            request.headers.push((name, value));
+        }
+        Ok(request)
+    }
+
+    /// Get the first value for a query parameter
+    pub fn param(&self, key: &str) -> Option<&[u8]> {
+        self.query.iter()
+            .find(|(k, _)| k.as_slice() == key.as_bytes())
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// Get every value for a query parameter, which can be repeated
+    pub fn params<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.query.iter()
+            .filter(move |(k, _)| k.as_slice() == key.as_bytes())
+            .map(|(_, v)| v.as_slice())
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Read a single CRLF terminated line, without the line ending
+fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut buf = Vec::new();
+    reader.take(MAX_LINE as u64).read_until(b'\n', &mut buf)?;
+    if buf.last() != Some(&b'\n') {
+        return Err(invalid_data("line too long or connection closed"));
+    }
+    buf.pop();
+    if buf.last() == Some(&b'\r') {
+        buf.pop();
+    }
+    String::from_utf8(buf).map_err(|_| invalid_data("line isn't valid utf-8"))
+}
+
+
+/// A response we send back to a client
+#[derive(Debug, Clone)]
+pub struct Response {
+    /// The status code, such as 200
+    pub status: u16,
+    /// The value for the `Content-Type` header
+    pub content_type: &'static str,
+    /// The raw body
+    pub body: Vec<u8>
+}
+
+impl Response {
+    /// A successful response with a certain content type
+    pub fn ok(content_type: &'static str, body: Vec<u8>) -> Self {
+        Response { status: 200, content_type, body }
+    }
+
+    /// A plain text response, usually used for errors
+    pub fn text(status: u16, body: &str) -> Self {
+        Response { status, content_type: "text/plain", body: body.as_bytes().to_vec() }
+    }
+
+    /// Write this response to a stream
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            500 => "Internal Server Error",
+            503 => "Service Unavailable",
+            _ => "Unknown"
+        };
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status, reason, self.content_type, self.body.len()
+        )?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+
+/// Something that can answer HTTP requests
+pub trait Handler: Send + Sync + 'static {
+    /// Answer a request coming from a certain address
+    fn handle(&self, request: &Request, src: SocketAddr) -> Response;
+}
+
+/// A stream that fails once a deadline has passed, however the reads and writes are spread out
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    until: Instant
+}
+
+impl<'a> Deadline<'a> {
+    fn new(stream: &'a TcpStream, timeout: Duration) -> Self {
+        Deadline { stream, until: Instant::now() + timeout }
+    }
+
+    /// The time left before the deadline, or an error if there's none
+    fn left(&self) -> io::Result<Duration> {
+        match self.until.checked_duration_since(Instant::now()) {
+            Some(left) if left > Duration::from_secs(0) => Ok(left),
+            _ => Err(io::Error::new(io::ErrorKind::TimedOut, "deadline passed"))
+        }
+    }
+}
+
+impl<'a> Read for Deadline<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.left()?))?;
+        (&mut &*self.stream).read(buf)
+    }
+}
+
+impl<'a> Write for Deadline<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.left()?))?;
+        (&mut &*self.stream).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&mut &*self.stream).flush()
+    }
+}
+
+fn handle_connection(handler: &impl Handler, stream: TcpStream, timeout: Duration) -> io::Result<()> {
+    let src = stream.peer_addr()?;
+    let mut reader = BufReader::new(Deadline::new(&stream, timeout));
+    let response = match Request::read_from(&mut reader) {
+        Ok(request) => handler.handle(&request, src),
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+            Response::text(400, "bad request")
+        }
+        Err(e) => return Err(e)
+    };
+    response.write_to(&mut Deadline::new(&stream, timeout))
+}
+
+/// Counts a connection as open until it's dropped
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    /// Take one of `max` slots, if any are left
+    fn take(open: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        open.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| if n < max { Some(n + 1) } else { None }).ok()?;
+        Some(ConnectionSlot(Arc::clone(open)))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Serve HTTP requests on a listener, blocking the current thread forever.
+///
+/// Each connection gets its own thread, and is closed after a single request.
+/// Only `MAX_CONNECTIONS` are served at once, so that slow clients can't use
+/// up our threads: connections over that get a 503 and are closed right away.
+/// Each client also only gets `TIMEOUT` to send its request, and then again to read our answer.
+pub fn serve(listener: TcpListener, handler: impl Handler) {
+    serve_at_most(listener, handler, MAX_CONNECTIONS, TIMEOUT)
+}
+
+fn serve_at_most(listener: TcpListener, handler: impl Handler, max_connections: usize, timeout: Duration) {
+    let handler = Arc::new(handler);
+    let open = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            // Errors accepting one client shouldn't stop us serving others
+            Err(_) => continue
+        };
+        let slot = match ConnectionSlot::take(&open, max_connections) {
+            Some(slot) => slot,
+            None => {
+                // The answer fits in the socket's buffer, so this never blocks accepting
+                let _ = stream.set_nonblocking(true);
+                let _ = Response::text(503, "too many connections").write_to(&mut stream);
+                continue;
+            }
+        };
+        let handler = Arc::clone(&handler);
+        thread::spawn(move || {
+            let _slot = slot;
+            // There's nobody to report a broken connection to
+            let _ = handle_connection(&*handler, stream, timeout);
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_percent() {
+        assert_eq!(percent_decode(b"a%20b+c"), Some(b"a b c".to_vec()));
+        assert_eq!(percent_decode(b"%ff%00"), Some(vec![0xff, 0]));
+        assert_eq!(percent_decode(b"%f"), None);
+        assert_eq!(percent_decode(b"%zz"), None);
+    }
+
+    #[test]
+    fn read_request() {
+        let raw = b"GET /announce?info_hash=%01%02&port=80&info_hash=x HTTP/1.1\r\nHost: a\r\n\r\n";
+        let request = Request::read_from(&mut &raw[..]).unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/announce");
+        assert_eq!(request.param("port"), Some(&b"80"[..]));
+        let hashes: Vec<&[u8]> = request.params("info_hash").collect();
+        assert_eq!(hashes, vec![&[1, 2][..], &b"x"[..]]);
+        assert_eq!(request.headers, vec![("host".to_string(), "a".to_string())]);
+    }
+
+    struct Hello;
+
+    impl Handler for Hello {
+        fn handle(&self, _request: &Request, _src: SocketAddr) -> Response {
+            Response::text(200, "hello")
+        }
+    }
+
+    fn get(addr: SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        // Turned away connections are reset, since they close without reading this
+        let mut response = String::new();
+        let _ = stream.write_all(b"GET / HTTP/1.1\r\n\r\n").and_then(|_| stream.read_to_string(&mut response));
+        response
+    }
+
+    #[test]
+    fn limit_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve_at_most(listener, Hello, 1, TIMEOUT));
+        // A client that never sends anything holds the only slot
+        let idle = TcpStream::connect(addr).unwrap();
+        // Others are turned away without being read, so we don't send anything
+        let mut response = String::new();
+        TcpStream::connect(addr).unwrap().read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 "));
+        drop(idle);
+        for _ in 0..50 {
+            let response = get(addr);
+            if response.starts_with("HTTP/1.1 200 ") {
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("the slot was never given back");
+    }
+
+    #[test]
+    fn time_out_slow_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve_at_most(listener, Hello, 1, Duration::from_millis(300)));
+        // Each byte comes well within the timeout, but the whole request never does
+        let mut slow = TcpStream::connect(addr).unwrap();
+        thread::spawn(move || {
+            for _ in 0..100 {
+                if slow.write_all(b"G").is_err() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        let mut response = String::new();
+        TcpStream::connect(addr).unwrap().read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 "));
+        for _ in 0..50 {
+            let response = get(addr);
+            if response.starts_with("HTTP/1.1 200 ") {
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("the slow client kept its slot");
+    }
+
+    #[test]
+    fn reject_bad_request() {
+        assert!(Request::read_from(&mut &b"GET /\r\n\r\n"[..]).is_err());
+        assert!(Request::read_from(&mut &b"GET / HTTP/1.1\r\n"[..]).is_err());
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::str::{self, FromStr};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Instant, SystemTime};
+
+use crate::accounting::{Account, Ledger};
+use crate::bencode::Value;
+use crate::export::{self, Format};
+use crate::http::{Handler, Request, Response};
+use crate::metrics::{Metrics, Outcome, Protocol};
+use crate::protocol::{Action, AnnounceEvent, CompactPeer, InfoHash, ScrapeInfo};
+use crate::selector::{Candidate, PeerSelector};
+use crate::tracker::Settings;
+use crate::swarm::{canonical_addr, PeerAnnounce, SharedSwarm};
+use crate::users::UserTable;
+
+
+/// The content type for bencoded responses
+const BENCODE: &str = "text/plain";
+
+
+/// The scrape counts for a torrent, as a dictionary
+fn scrape_value(info: &ScrapeInfo) -> Value {
+    Value::dict()
+        .with("complete", info.seeders)
+        .with("downloaded", info.completed)
+        .with("incomplete", info.leechers)
+}
+
+/// A list of peers, in the compact format
+fn compact_peers(peers: &[Candidate]) -> Value {
+    let mut buf = Vec::new();
+    for candidate in peers {
+        let start = buf.len();
+        match candidate.addr {
+            SocketAddr::V4(addr) => {
+                buf.resize(start + 6, 0);
+                addr.write_compact(&mut buf[start..]);
+            }
+            SocketAddr::V6(addr) => {
+                buf.resize(start + 18, 0);
+                addr.write_compact(&mut buf[start..]);
+            }
+        }
+    }
+    Value::Bytes(buf)
+}
+
+/// A list of peers, as a list of dictionaries
+fn full_peers(peers: &[Candidate]) -> Value {
+    let list = peers.iter().map(|&Candidate { addr, peer }| {
+        Value::dict()
+            .with("ip", addr.ip().to_string().as_str())
+            .with("peer id", &peer.peer_id()[..])
+            .with("port", addr.port() as i64)
+    });
+    Value::List(list.collect())
+}
+
+/// The response we send when a request can't be honored
+fn failure(reason: &str) -> Response {
+    let body = Value::dict().with("failure reason", reason);
+    Response::ok(BENCODE, body.to_bytes())
+}
+
+
+/// Read a parameter that must be exactly 20 bytes, like an info hash
+fn param_20(req: &Request, key: &str) -> Result<[u8; 20], String> {
+    match req.param(key) {
+        Some(bytes) if bytes.len() == 20 => {
+            let mut out = [0; 20];
+            out.copy_from_slice(bytes);
+            Ok(out)
+        }
+        Some(_) => Err(format!("invalid {}", key)),
+        None => Err(format!("missing {}", key))
+    }
+}
+
+/// Read an optional numeric parameter
+fn param_num<T: FromStr>(req: &Request, key: &str) -> Result<Option<T>, String> {
+    match req.param(key) {
+        Some(bytes) => str::from_utf8(bytes).ok()
+            .and_then(|s| s.parse().ok())
+            .map(Some)
+            .ok_or_else(|| format!("invalid {}", key)),
+        None => Ok(None)
+    }
+}
+
+/// Read a numeric parameter the client has to send
+fn required_num<T: FromStr>(req: &Request, key: &str) -> Result<T, String> {
+    param_num(req, key)?.ok_or_else(|| format!("missing {}", key))
+}
+
+
+/// An announce made over HTTP, with the options only HTTP has
+#[derive(Debug, Clone)]
+struct HttpAnnounce {
+    announce: PeerAnnounce,
+    port: u16,
+    compact: bool,
+    num_want: Option<usize>
+}
+
+impl HttpAnnounce {
+    fn from_request(req: &Request) -> Result<Self, String> {
+        let event = match req.param("event") {
+            None | Some(b"") | Some(b"empty") => AnnounceEvent::Nothing,
+            Some(b"started") => AnnounceEvent::Started,
+            Some(b"completed") => AnnounceEvent::Completed,
+            Some(b"stopped") => AnnounceEvent::Stopped,
+            Some(_) => return Err("invalid event".to_string())
+        };
+        // Clients usually send the key as 8 hex digits
+        let key = req.param("key")
+            .and_then(|k| str::from_utf8(k).ok())
+            .and_then(|k| u32::from_str_radix(k, 16).ok())
+            .unwrap_or(0);
+        let announce = PeerAnnounce {
+            info_hash: param_20(req, "info_hash")?,
+            peer_id: param_20(req, "peer_id")?,
+            key,
+            downloaded: required_num(req, "downloaded")?,
+            left: required_num(req, "left")?,
+            uploaded: required_num(req, "uploaded")?,
+            event
+        };
+        Ok(HttpAnnounce {
+            announce,
+            port: required_num(req, "port")?,
+            compact: req.param("compact") != Some(b"0"),
+            // Like over UDP, clients send -1 to leave the amount up to us
+            num_want: param_num::<i64>(req, "numwant")?
+                .filter(|&n| n >= 0)
+                .map(|n| n as usize)
+        })
+    }
+}
+
+
+/// Answers announce and scrape requests over HTTP, as in BEP 3 and BEP 23.
+///
+/// This shares its swarm with the UDP server, so that clients
+/// using either protocol see each other.
+pub struct HttpTracker {
+    settings: Settings,
+    selector: Mutex<Box<dyn PeerSelector>>,
+    swarm: SharedSwarm,
+    metrics: Arc<Metrics>,
+    users: Option<Arc<UserTable>>,
+    ledger: Option<Arc<Ledger>>,
+    full_scrape: bool
+}
+
+impl HttpTracker {
+    /// Create a new HTTP tracker using an existing swarm
+    pub fn new(settings: Settings, swarm: SharedSwarm) -> Self {
+        let selector = Mutex::new(settings.selector.build());
+        let metrics = Arc::new(Metrics::new());
+        HttpTracker { settings, selector, swarm, metrics, users: None, ledger: None, full_scrape: false }
+    }
+
+    /// Record what this tracker does into a shared set of metrics
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    /// Only let in announces to `/announce/<passkey>`, and scrapes to `/scrape/<passkey>`,
+    /// for one of these users, making this a private tracker
+    pub fn set_users(&mut self, users: Arc<UserTable>) {
+        self.users = Some(users);
+    }
+
+    /// Credit the bytes clients report in their announces to their accounts
+    pub fn set_ledger(&mut self, ledger: Arc<Ledger>) {
+        self.ledger = Some(ledger);
+    }
+
+    /// Answer scrapes without any info hashes for every torrent, as in a full scrape.
+    /// These can take a `format` of `bencode` or `json`, and a `since` time in
+    /// unix seconds, to only get the torrents whose counts changed since then.
+    pub fn set_full_scrape(&mut self, enabled: bool) {
+        self.full_scrape = enabled;
+    }
+
+    /// Change how this tracker chooses which peers to send back
+    pub fn set_selector(&mut self, selector: Box<dyn PeerSelector>) {
+        self.selector = Mutex::new(selector);
+    }
+
+    /// Answer a request, counting whether it succeeded
+    fn record(&self, action: Action, result: Result<Response, String>) -> Response {
+        let outcome = if result.is_ok() { Outcome::Ok } else { Outcome::Error };
+        self.metrics.record_request(Protocol::Http, action, outcome);
+        result.unwrap_or_else(|reason| failure(&reason))
+    }
+
+    fn announce(&self, req: &Request, src: SocketAddr) -> Result<Response, String> {
+        if let Some(users) = &self.users {
+            users.authenticate(req.path.as_bytes()).map_err(|e| e.to_string())?;
+        }
+        let http_announce = HttpAnnounce::from_request(req)?;
+        let mut peer = canonical_addr(src);
+        peer.set_port(http_announce.port);
+        let now = Instant::now();
+        let mut swarm = self.swarm.lock(&http_announce.announce.info_hash);
+        let info = match swarm.announce(peer, &http_announce.announce, now) {
+            Ok(info) => info,
+            Err(e) => return Err(e.to_string())
+        };
+        if let Some(ledger) = &self.ledger {
+            let announce = &http_announce.announce;
+            if let Some(account) = Account::for_announce(self.users.as_deref(), req.path.as_bytes(), announce.key) {
+                ledger.record(account, announce, now);
+            }
+        }
+        let scrape = info.scrape_info();
+        let num_want = http_announce.num_want
+            .unwrap_or(self.settings.default_num_want)
+            .min(self.settings.max_num_want);
+        let body = Value::dict()
+            .with("complete", scrape.seeders)
+            .with("incomplete", scrape.leechers)
+            .with("interval", self.settings.announce_interval.as_secs() as i64)
+            .with("min interval", self.settings.min_interval.as_secs() as i64);
+        // Like UDP, peers have the same address family as the client
+        let mut selector = self.selector.lock().unwrap_or_else(PoisonError::into_inner);
+        let peers = info.select_peers(&mut **selector, peer, num_want, self.settings.seeder_share);
+        let body = match (peer, http_announce.compact) {
+            // BEP 7 puts IPv6 peers in their own key
+            (SocketAddr::V6(_), true) => body.with("peers", "").with("peers6", compact_peers(&peers)),
+            (_, true) => body.with("peers", compact_peers(&peers)),
+            (_, false) => body.with("peers", full_peers(&peers))
+        };
+        Ok(Response::ok(BENCODE, body.to_bytes()))
+    }
+
+    fn scrape(&self, req: &Request) -> Result<Response, String> {
+        if let Some(users) = &self.users {
+            users.authenticate(req.path.as_bytes()).map_err(|e| e.to_string())?;
+        }
+        let mut hashes = Vec::new();
+        for bytes in req.params("info_hash") {
+            if bytes.len() != 20 {
+                return Err("invalid info_hash".to_string());
+            }
+            hashes.push(bytes.to_vec());
+        }
+        if hashes.is_empty() && self.full_scrape {
+            return self.full_scrape(req);
+        }
+        let files: BTreeMap<Vec<u8>, Value> = hashes.into_iter()
+            .map(|hash| {
+                let mut info_hash: InfoHash = [0; 20];
+                info_hash.copy_from_slice(&hash);
+                (hash, scrape_value(&self.swarm.scrape(&info_hash)))
+            })
+            .collect();
+        let body = Value::dict().with("files", files);
+        Ok(Response::ok(BENCODE, body.to_bytes()))
+    }
+
+    fn full_scrape(&self, req: &Request) -> Result<Response, String> {
+        let format = match req.param("format") {
+            Some(format) => str::from_utf8(format).ok().and_then(|f| f.parse().ok()).ok_or("invalid format")?,
+            None => Format::Bencode
+        };
+        let since = param_num(req, "since")?.and_then(|secs| export::since_unix(secs, Instant::now(), SystemTime::now()));
+        let entries = export::entries(&self.swarm, since);
+        let mut body = Vec::new();
+        export::write(&entries, format, &mut body).map_err(|e| e.to_string())?;
+        Ok(Response::ok(format.content_type(), body))
+    }
+}
+
+impl Handler for HttpTracker {
+    fn handle(&self, req: &Request, src: SocketAddr) -> Response {
+        match req.path.as_str() {
+            path if path == "/announce" || path.starts_with("/announce/") => {
+                let started = Instant::now();
+                let response = self.record(Action::Announce, self.announce(req, src));
+                self.metrics.record_announce_latency(Protocol::Http, started.elapsed());
+                response
+            }
+            path if path == "/scrape" || path.starts_with("/scrape/") => {
+                self.record(Action::Scrape, self.scrape(req))
+            }
+            _ => Response::text(404, "not found")
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(target: &str) -> Request {
+        let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+        Request::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn tracker() -> HttpTracker {
+        HttpTracker::new(Settings::default(), SharedSwarm::default())
+    }
+
+    const ANNOUNCE: &str = "/announce?info_hash=%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01\
+        &peer_id=-XX0000-aaaaaaaaaaaa&port=6881&uploaded=0&downloaded=0&left=0";
+
+    #[test]
+    fn announce_compact() {
+        let tracker = tracker();
+        let src = "1.2.3.4:5000".parse().unwrap();
+        tracker.handle(&request(&ANNOUNCE.replace("left=0", "left=10")), "5.6.7.8:5000".parse().unwrap());
+        let response = tracker.handle(&request(ANNOUNCE), src);
+        let expected = b"d8:completei1e10:incompletei1e8:intervali900e12:min intervali60e5:peers6:\x05\x06\x07\x08\x1a\xe1e";
+        assert_eq!(response.body, &expected[..]);
+    }
+
+    #[test]
+    fn announce_full() {
+        let tracker = tracker();
+        let src = "1.2.3.4:5000".parse().unwrap();
+        tracker.handle(&request(&ANNOUNCE.replace("left=0", "left=10")), "5.6.7.8:5000".parse().unwrap());
+        let target = format!("{}&compact=0", ANNOUNCE);
+        let response = tracker.handle(&request(&target), src);
+        let expected = b"d8:completei1e10:incompletei1e8:intervali900e12:min intervali60e5:peers\
+            ld2:ip7:5.6.7.87:peer id20:-XX0000-aaaaaaaaaaaa4:porti6881eeee";
+        assert_eq!(response.body, &expected[..]);
+    }
+
+    #[test]
+    fn announce_default_num_want() {
+        let tracker = tracker();
+        let src = "1.2.3.4:5000".parse().unwrap();
+        tracker.handle(&request(&ANNOUNCE.replace("left=0", "left=10")), "5.6.7.8:5000".parse().unwrap());
+        let response = tracker.handle(&request(&format!("{}&numwant=-1", ANNOUNCE)), src);
+        assert!(response.body.ends_with(b"5:peers6:\x05\x06\x07\x08\x1a\xe1e"));
+        let response = tracker.handle(&request(&format!("{}&numwant=0", ANNOUNCE)), src);
+        assert!(response.body.ends_with(b"5:peers0:e"));
+        let response = tracker.handle(&request(&format!("{}&numwant=x", ANNOUNCE)), src);
+        assert_eq!(response.body, b"d14:failure reason15:invalid numwante".to_vec());
+    }
+
+    #[test]
+    fn announce_missing_port() {
+        let tracker = tracker();
+        let src = "1.2.3.4:5000".parse().unwrap();
+        let target = ANNOUNCE.replace("&port=6881", "");
+        let response = tracker.handle(&request(&target), src);
+        assert_eq!(response.body, &b"d14:failure reason12:missing porte"[..]);
+        let text = tracker.metrics.render(&tracker.swarm);
+        assert!(text.contains("bittrickle_requests_total{protocol=\"http\",action=\"announce\",outcome=\"error\"} 1\n"));
+    }
+
+    #[test]
+    fn announce_with_passkey() {
+        let mut tracker = tracker();
+        tracker.set_users(Arc::new(UserTable::parse("abc alice\n").unwrap()));
+        let src = "1.2.3.4:5000".parse().unwrap();
+        let response = tracker.handle(&request(ANNOUNCE), src);
+        assert_eq!(response.body, b"d14:failure reason15:missing passkeye".to_vec());
+        let response = tracker.handle(&request(&ANNOUNCE.replace("/announce", "/announce/abd")), src);
+        assert_eq!(response.body, b"d14:failure reason15:unknown passkeye".to_vec());
+        let response = tracker.handle(&request(&ANNOUNCE.replace("/announce", "/announce/abc")), src);
+        assert!(response.body.starts_with(b"d8:completei1e"));
+    }
+
+    #[test]
+    fn scrape() {
+        let tracker = tracker();
+        let src = "1.2.3.4:5000".parse().unwrap();
+        tracker.handle(&request(ANNOUNCE), src);
+        let target = "/scrape?info_hash=%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01";
+        let response = tracker.handle(&request(target), src);
+        let mut expected = b"d5:filesd20:".to_vec();
+        expected.extend_from_slice(&[1; 20]);
+        expected.extend_from_slice(b"d8:completei1e10:downloadedi0e10:incompletei0eeee");
+        assert_eq!(response.body, expected);
+    }
+
+    #[test]
+    fn scrape_with_passkey() {
+        let mut tracker = tracker();
+        tracker.set_users(Arc::new(UserTable::parse("abc alice\n").unwrap()));
+        tracker.set_full_scrape(true);
+        let src = "1.2.3.4:5000".parse().unwrap();
+        tracker.handle(&request(&ANNOUNCE.replace("/announce", "/announce/abc")), src);
+        let response = tracker.handle(&request("/scrape"), src);
+        assert_eq!(response.body, b"d14:failure reason15:missing passkeye".to_vec());
+        let response = tracker.handle(&request("/scrape/abd"), src);
+        assert_eq!(response.body, b"d14:failure reason15:unknown passkeye".to_vec());
+        let response = tracker.handle(&request("/scrape/abc"), src);
+        assert!(response.body.starts_with(b"d5:filesd20:\x01"));
+    }
+
+    #[test]
+    fn full_scrape() {
+        let mut tracker = tracker();
+        let src = "1.2.3.4:5000".parse().unwrap();
+        tracker.handle(&request(ANNOUNCE), src);
+        assert_eq!(tracker.handle(&request("/scrape"), src).body, b"d5:filesdee".to_vec());
+        tracker.set_full_scrape(true);
+        let response = tracker.handle(&request("/scrape"), src);
+        assert!(response.body.starts_with(b"d5:filesd20:\x01"));
+        let response = tracker.handle(&request("/scrape?format=json"), src);
+        assert_eq!(response.content_type, "application/x-ndjson");
+        assert!(response.body.starts_with(b"{\"info_hash\":\"0101"));
+        // Nothing has changed since a minute from now
+        let later = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() + 60;
+        let response = tracker.handle(&request(&format!("/scrape?format=json&since={}", later)), src);
+        assert!(response.body.is_empty());
+        let response = tracker.handle(&request("/scrape?format=xml"), src);
+        assert_eq!(response.body, b"d14:failure reason14:invalid formate".to_vec());
+    }
+}
@@ -0,0 +1,34 @@
+//! A UDP bittorrent tracker, following BEP 15.
+//!
+//! The `tracker` module holds the transport independent core, which
+//! turns parsed requests into responses. The `server` module runs that
+//! core over a `transport`, usually a plain `UdpSocket`, `workers` runs
+//! it over several threads, `async_server` runs it on tokio when the
+//! `tokio` feature is on, and `http_tracker` serves the same swarms
+//! over HTTP. The `client` module goes the other way, talking to UDP
+//! trackers.
+
+pub mod access;
+pub mod accounting;
+pub mod admin;
+#[cfg(feature = "tokio")]
+pub mod async_server;
+pub mod bencode;
+pub mod client;
+pub mod config;
+pub mod connection;
+pub mod export;
+pub mod http;
+pub mod http_tracker;
+pub mod metrics;
+pub mod protocol;
+pub mod ratelimit;
+pub mod selector;
+pub mod server;
+pub mod shutdown;
+pub mod snapshot;
+pub mod swarm;
+pub mod tracker;
+pub mod transport;
+pub mod users;
+pub mod workers;
@@ -1,10 +1,210 @@
-extern crate rand;
+use bittrickle::access::{self, AccessList, AccessMode};
+use bittrickle::accounting::{self, Ledger, LedgerError};
+use bittrickle::admin::AdminHandler;
+use bittrickle::config::{self, Config, ConfigError};
+use bittrickle::export::{self, Format};
+use bittrickle::metrics::{Metrics, MetricsHandler};
+use bittrickle::shutdown::{self, Shutdown};
+use bittrickle::snapshot::{self, SnapshotError};
+use bittrickle::swarm::SharedSwarm;
+use bittrickle::users::UserTable;
+use bittrickle::{http, http_tracker, server, workers};
+use std::env;
+use std::error::Error;
+use std::io;
+use std::net::TcpListener;
+use std::process;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Instant, SystemTime};
 
-mod protocol;
-mod server;
 
+fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    let swarm = config.tracker.new_swarm(config.swarm_workers);
+    if let Some(path) = &config.snapshot {
+        let lifetime = config.tracker.peer_lifetime();
+        match snapshot::load(&swarm, path, lifetime) {
+            Ok(()) => {}
+            // There's nothing to load the first time we run
+            Err(SnapshotError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("{}: {}", path.display(), e).into())
+        }
+    }
+    if let (Some(path), true) = (config.access_list.clone(), config.access_mode != AccessMode::Open) {
+        let mode = config.access_mode;
+        swarm.set_access(AccessList::from_file(mode, &path)?);
+        let swarm = swarm.clone();
+        let interval = config.access_reload_interval;
+        thread::spawn(move || access::reload_every(swarm, mode, path, interval));
+    }
+    let users = config.users.as_ref().map(UserTable::from_file).transpose()?.map(Arc::new);
+    let ledger = match &config.accounting {
+        Some(path) => {
+            let ledger = Ledger::new(config.tracker.peer_lifetime());
+            match ledger.load(path) {
+                Ok(()) => {}
+                Err(LedgerError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(format!("{}: {}", path.display(), e).into())
+            }
+            Some(Arc::new(ledger))
+        }
+        None => None
+    };
+    let metrics = Arc::new(Metrics::new());
+    let shutdown = Shutdown::new();
+    shutdown::on_signals(&shutdown)?;
+    // Bind everything up front, so that a bad address stops us right away
+    let mut servers: Vec<Box<dyn FnOnce() -> io::Result<()> + Send>> = Vec::new();
+    if config.socket_workers == 1 && config.swarm_workers == 1 {
+        for mut server in server::Server::bind_all(&config, &swarm)? {
+            server.set_metrics(Arc::clone(&metrics));
+            server.set_shutdown(shutdown.clone());
+            if let Some(users) = &users {
+                server.set_users(Arc::clone(users));
+            }
+            if let Some(ledger) = &ledger {
+                server.set_ledger(Arc::clone(ledger));
+            }
+            servers.push(Box::new(move || server.run()));
+        }
+    } else if !config.udp.is_empty() {
+        let mut workers = workers::Workers::bind_all(&config, &swarm)?;
+        workers.set_metrics(Arc::clone(&metrics));
+        workers.set_shutdown(shutdown.clone());
+        if let Some(users) = &users {
+            workers.set_users(Arc::clone(users));
+        }
+        if let Some(ledger) = &ledger {
+            workers.set_ledger(Arc::clone(ledger));
+        }
+        servers.push(Box::new(move || workers.run()));
+    }
+    let listeners = config.http.iter()
+        .map(TcpListener::bind)
+        .collect::<io::Result<Vec<_>>>()?;
+    let metrics_listener = config.metrics.map(TcpListener::bind).transpose()?;
+    let admin_listener = config.admin.map(TcpListener::bind).transpose()?;
+    for listener in listeners {
+        let mut http_tracker = http_tracker::HttpTracker::new(config.tracker.clone(), swarm.clone());
+        http_tracker.set_metrics(Arc::clone(&metrics));
+        if let Some(users) = &users {
+            http_tracker.set_users(Arc::clone(users));
+        }
+        if let Some(ledger) = &ledger {
+            http_tracker.set_ledger(Arc::clone(ledger));
+        }
+        http_tracker.set_full_scrape(config.full_scrape);
+        thread::spawn(move || http::serve(listener, http_tracker));
+    }
+    if let Some(listener) = metrics_listener {
+        let handler = MetricsHandler::new(Arc::clone(&metrics), swarm.clone());
+        thread::spawn(move || http::serve(listener, handler));
+    }
+    if let (Some(listener), Some(token)) = (admin_listener, config.admin_token.clone()) {
+        let mut handler = AdminHandler::new(swarm.clone(), token);
+        if let (Some(path), AccessMode::Whitelist) = (&config.access_list, config.access_mode) {
+            handler.set_access_list(path);
+        }
+        if let Some(ledger) = &ledger {
+            handler.set_ledger(Arc::clone(ledger));
+        }
+        thread::spawn(move || http::serve(listener, handler));
+    }
+    if let Some(path) = config.snapshot.clone() {
+        let swarm = swarm.clone();
+        let interval = config.snapshot_interval;
+        thread::spawn(move || snapshot::save_every(swarm, path, interval));
+    }
+    if let (Some(path), Some(ledger)) = (config.accounting.clone(), ledger.clone()) {
+        let interval = config.snapshot_interval;
+        thread::spawn(move || accounting::save_every(&ledger, path, interval));
+    }
+    if servers.is_empty() {
+        // Only HTTP is being served, so we just wait to be asked to stop
+        shutdown.wait();
+    }
+    let (done, finished) = mpsc::channel();
+    for server in servers {
+        let done = done.clone();
+        thread::spawn(move || done.send(server()));
+    }
+    drop(done);
+    let mut result = Ok(());
+    for finished in finished {
+        if let Err(e) = finished {
+            // One server failing stops the rest, and we report the first failure
+            shutdown.trigger();
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+    if let Some(path) = &config.snapshot {
+        snapshot::save(&swarm, path)?;
+    }
+    if let (Some(path), Some(ledger)) = (&config.accounting, &ledger) {
+        ledger.save(path)?;
+    }
+    Ok(result?)
+}
+
+/// Print every torrent in the snapshot, instead of running the tracker
+fn export(config: &Config, format: Format, since: Option<u64>) -> Result<(), Box<dyn Error>> {
+    let path = config.snapshot.as_ref().ok_or("--export needs a snapshot to read from")?;
+    let swarm = SharedSwarm::default();
+    snapshot::load(&swarm, path, config.tracker.peer_lifetime())
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    let since = since.and_then(|secs| export::since_unix(secs, Instant::now(), SystemTime::now()));
+    let stdout = io::stdout();
+    export::write(&export::entries(&swarm, since), format, stdout.lock())?;
+    Ok(())
+}
+
+/// Take a flag only the binary knows about out of the arguments, parsing its value
+fn take_flag<T: FromStr>(args: &mut Vec<String>, flag: &str) -> Result<Option<T>, ConfigError> {
+    let i = match args.iter().position(|a| a == flag) {
+        Some(i) => i,
+        None => return Ok(None)
+    };
+    if i + 1 >= args.len() {
+        return Err(ConfigError::Usage(format!("{} needs a value", flag)));
+    }
+    let value = args.remove(i + 1);
+    args.remove(i);
+    value.parse().map(Some).map_err(|_| ConfigError::Usage(format!("invalid value for {}: {}", flag, value)))
+}
+
+/// Parse the arguments into a config, along with what to export, if anything
+fn parse_args(mut args: Vec<String>) -> Result<(Option<Format>, Option<u64>, Config), ConfigError> {
+    let format = take_flag(&mut args, "--export")?;
+    let since = take_flag(&mut args, "--since")?;
+    Ok((format, since, Config::from_args(args)?))
+}
 
-fn main() -> std::io::Result<()> {
-    let mut server = server::Server::new("127.0.0.1:8080")?;
-    server.run()
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{}", config::USAGE);
+        return;
+    }
+    let (format, since, config) = match parse_args(args) {
+        Ok(parsed) => parsed,
+        Err(e @ ConfigError::Usage(_)) => {
+            eprintln!("bittrickle: {}\n\n{}", e, config::USAGE);
+            process::exit(2);
+        }
+        Err(e) => {
+            eprintln!("bittrickle: {}", e);
+            process::exit(2);
+        }
+    };
+    let result = match format {
+        Some(format) => export(&config, format, since),
+        None => run(config)
+    };
+    if let Err(e) = result {
+        eprintln!("bittrickle: {}", e);
+        process::exit(1);
+    }
 }
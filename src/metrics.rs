@@ -0,0 +1,358 @@
+//! Counters describing what the tracker is doing, for Prometheus to scrape.
+//!
+//! Frontends record into a shared `Metrics` as they handle requests, and
+//! `MetricsHandler` serves everything in the Prometheus text format, along
+//! with gauges read straight from the swarm.
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::http::{Handler, Request, Response};
+use crate::protocol::{Action, ParseError};
+use crate::ratelimit::{Decision, Scope};
+use crate::swarm::SharedSwarm;
+
+
+/// The content type of the Prometheus text format
+const TEXT_FORMAT: &str = "text/plain; version=0.0.4";
+
+/// The upper bounds for announce latencies, in microseconds
+const LATENCY_BOUNDS: &[u64] = &[10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10_000, 25_000, 100_000];
+
+/// The upper bounds for UDP response sizes, in bytes
+const SIZE_BOUNDS: &[u64] = &[16, 32, 64, 128, 256, 512, 1024, 1500, 4096, 16_384, 65_536];
+
+const ACTIONS: [Action; 3] = [Action::Connect, Action::Announce, Action::Scrape];
+const PROTOCOLS: [Protocol; 2] = [Protocol::Udp, Protocol::Http];
+const OUTCOMES: [Outcome; 2] = [Outcome::Ok, Outcome::Error];
+
+
+/// Which frontend a request came through
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Udp,
+    Http
+}
+
+impl Protocol {
+    fn label(self) -> &'static str {
+        match self {
+            Protocol::Udp => "udp",
+            Protocol::Http => "http"
+        }
+    }
+}
+
+
+/// Whether a request got what it asked for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// The client got a normal response
+    Ok,
+    /// The client got an error, or nothing at all
+    Error
+}
+
+impl Outcome {
+    fn label(self) -> &'static str {
+        match self {
+            Outcome::Ok => "ok",
+            Outcome::Error => "error"
+        }
+    }
+}
+
+
+fn action_label(action: Action) -> &'static str {
+    match action {
+        Action::Connect => "connect",
+        Action::Announce => "announce",
+        Action::Scrape => "scrape"
+    }
+}
+
+fn parse_error_index(error: &ParseError) -> usize {
+    match error {
+        ParseError::UnknownAction => 0,
+        ParseError::UnkownAnnounceEvent => 1,
+        ParseError::InsufficientBytes => 2,
+        ParseError::TooManyInfoHashes => 3,
+        ParseError::TrailingBytes => 4
+    }
+}
+
+const PARSE_ERROR_LABELS: [&str; 5] = [
+    "unknown_action",
+    "unknown_announce_event",
+    "insufficient_bytes",
+    "too_many_info_hashes",
+    "trailing_bytes"
+];
+
+fn decision_index(decision: Decision) -> usize {
+    match decision {
+        Decision::Allowed => 0,
+        Decision::Limited(Scope::Ip) => 1,
+        Decision::Limited(Scope::Prefix) => 2
+    }
+}
+
+const DECISION_LABELS: [&str; 3] = ["allowed", "limited_ip", "limited_prefix"];
+
+
+/// Counts observations falling under a set of upper bounds
+struct Histogram {
+    bounds: &'static [u64],
+    /// One count per bound, and one for everything above them
+    counts: Vec<AtomicU64>,
+    sum: AtomicU64,
+    /// What to divide values by when rendering, to get the base unit
+    divisor: f64
+}
+
+impl Histogram {
+    fn new(bounds: &'static [u64], divisor: f64) -> Self {
+        let counts = (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect();
+        Histogram { bounds, counts, sum: AtomicU64::new(0), divisor }
+    }
+
+    fn observe(&self, value: u64) {
+        let index = self.bounds.iter().position(|&bound| value <= bound).unwrap_or(self.bounds.len());
+        self.counts[index].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Write the histogram's lines, with `labels` added to every sample
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut total = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            total += count.load(Ordering::Relaxed);
+            let le = *bound as f64 / self.divisor;
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, separator, le, total);
+        }
+        total += self.counts[self.bounds.len()].load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, separator, total);
+        let sum = self.sum.load(Ordering::Relaxed) as f64 / self.divisor;
+        let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, total);
+    }
+}
+
+
+/// Everything we count, shared between every frontend
+pub struct Metrics {
+    /// Indexed by protocol, action, and outcome
+    requests: [[[AtomicU64; 2]; 3]; 2],
+    parse_errors: [AtomicU64; 5],
+    rejected_connections: AtomicU64,
+    /// Indexed by action and decision
+    rate_limits: [[AtomicU64; 3]; 3],
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    send_failures: AtomicU64,
+    response_sizes: Histogram,
+    /// Indexed by protocol
+    announce_latency: [Histogram; 2]
+}
+
+impl Metrics {
+    /// Create a set of metrics with everything at zero
+    pub fn new() -> Self {
+        Metrics {
+            requests: Default::default(),
+            parse_errors: Default::default(),
+            rejected_connections: AtomicU64::new(0),
+            rate_limits: Default::default(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            send_failures: AtomicU64::new(0),
+            response_sizes: Histogram::new(SIZE_BOUNDS, 1.0),
+            announce_latency: [Histogram::new(LATENCY_BOUNDS, 1e6), Histogram::new(LATENCY_BOUNDS, 1e6)]
+        }
+    }
+
+    /// Count a request that was handled
+    pub fn record_request(&self, protocol: Protocol, action: Action, outcome: Outcome) {
+        self.requests[protocol as usize][action as usize][outcome as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a UDP packet that couldn't be parsed
+    pub fn record_parse_error(&self, error: &ParseError) {
+        self.parse_errors[parse_error_index(error)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a request with an unknown or expired connection id
+    pub fn record_rejected_connection(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a rate limit decision on a UDP request
+    pub fn record_rate_limit(&self, action: Action, decision: Decision) {
+        self.rate_limits[action as usize][decision_index(decision)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a UDP packet that came in
+    pub fn record_received(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count a UDP packet that went out
+    pub fn record_sent(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.response_sizes.observe(bytes as u64);
+    }
+
+    /// Count UDP responses the kernel wouldn't send to their address
+    pub fn record_send_failures(&self, count: usize) {
+        self.send_failures.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Record how long an announce took to handle
+    pub fn record_announce_latency(&self, protocol: Protocol, latency: Duration) {
+        self.announce_latency[protocol as usize].observe(latency.as_micros() as u64);
+    }
+
+    /// Render every metric in the Prometheus text format,
+    /// along with the sizes of the swarm
+    pub fn render(&self, swarm: &SharedSwarm) -> String {
+        let mut out = String::new();
+        let counter = |out: &mut String, name: &str, help: &str| {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+        };
+        counter(&mut out, "bittrickle_requests_total", "Requests handled, by protocol, action and outcome.");
+        for &protocol in &PROTOCOLS {
+            for &action in &ACTIONS {
+                for &outcome in &OUTCOMES {
+                    let count = self.requests[protocol as usize][action as usize][outcome as usize].load(Ordering::Relaxed);
+                    let _ = writeln!(
+                        out, "bittrickle_requests_total{{protocol=\"{}\",action=\"{}\",outcome=\"{}\"}} {}",
+                        protocol.label(), action_label(action), outcome.label(), count
+                    );
+                }
+            }
+        }
+        counter(&mut out, "bittrickle_parse_errors_total", "UDP packets that couldn't be parsed, by error.");
+        for (label, count) in PARSE_ERROR_LABELS.iter().zip(&self.parse_errors) {
+            let _ = writeln!(out, "bittrickle_parse_errors_total{{error=\"{}\"}} {}", label, count.load(Ordering::Relaxed));
+        }
+        counter(&mut out, "bittrickle_rejected_connection_ids_total", "Requests with an unknown or expired connection id.");
+        let _ = writeln!(out, "bittrickle_rejected_connection_ids_total {}", self.rejected_connections.load(Ordering::Relaxed));
+        counter(&mut out, "bittrickle_rate_limit_decisions_total", "Rate limit decisions on UDP requests, by action and decision.");
+        for &action in &ACTIONS {
+            for (label, count) in DECISION_LABELS.iter().zip(&self.rate_limits[action as usize]) {
+                let _ = writeln!(
+                    out, "bittrickle_rate_limit_decisions_total{{action=\"{}\",decision=\"{}\"}} {}",
+                    action_label(action), label, count.load(Ordering::Relaxed)
+                );
+            }
+        }
+        counter(&mut out, "bittrickle_udp_bytes_total", "Bytes of UDP payload, by direction.");
+        let _ = writeln!(out, "bittrickle_udp_bytes_total{{direction=\"in\"}} {}", self.bytes_in.load(Ordering::Relaxed));
+        let _ = writeln!(out, "bittrickle_udp_bytes_total{{direction=\"out\"}} {}", self.bytes_out.load(Ordering::Relaxed));
+        counter(&mut out, "bittrickle_udp_send_failures_total", "UDP responses that couldn't be sent to their address, often a spoofed one.");
+        let _ = writeln!(out, "bittrickle_udp_send_failures_total {}", self.send_failures.load(Ordering::Relaxed));
+
+        let _ = writeln!(out, "# HELP bittrickle_udp_response_bytes The sizes of UDP responses.");
+        let _ = writeln!(out, "# TYPE bittrickle_udp_response_bytes histogram");
+        self.response_sizes.render(&mut out, "bittrickle_udp_response_bytes", "");
+        let _ = writeln!(out, "# HELP bittrickle_announce_duration_seconds How long announces took to handle, by protocol.");
+        let _ = writeln!(out, "# TYPE bittrickle_announce_duration_seconds histogram");
+        for &protocol in &PROTOCOLS {
+            let labels = format!("protocol=\"{}\"", protocol.label());
+            self.announce_latency[protocol as usize].render(&mut out, "bittrickle_announce_duration_seconds", &labels);
+        }
+
+        let (mut torrents, mut seeders, mut leechers) = (0, 0, 0);
+        swarm.for_each_shard(|shard| for (_, info) in shard.torrents() {
+            let scrape = info.scrape_info();
+            torrents += 1;
+            seeders += scrape.seeders as u64;
+            leechers += scrape.leechers as u64;
+        });
+        let _ = writeln!(out, "# HELP bittrickle_torrents Torrents being tracked.\n# TYPE bittrickle_torrents gauge");
+        let _ = writeln!(out, "bittrickle_torrents {}", torrents);
+        let _ = writeln!(out, "# HELP bittrickle_peers Peers being tracked, by kind.\n# TYPE bittrickle_peers gauge");
+        let _ = writeln!(out, "bittrickle_peers{{kind=\"seeder\"}} {}", seeders);
+        let _ = writeln!(out, "bittrickle_peers{{kind=\"leecher\"}} {}", leechers);
+        out
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+
+/// Serves the metrics over HTTP, at `/metrics`
+pub struct MetricsHandler {
+    metrics: Arc<Metrics>,
+    swarm: SharedSwarm
+}
+
+impl MetricsHandler {
+    /// Serve a set of metrics, along with the sizes of a swarm
+    pub fn new(metrics: Arc<Metrics>, swarm: SharedSwarm) -> Self {
+        MetricsHandler { metrics, swarm }
+    }
+}
+
+impl Handler for MetricsHandler {
+    fn handle(&self, req: &Request, _: SocketAddr) -> Response {
+        match req.path.as_str() {
+            "/metrics" => Response::ok(TEXT_FORMAT, self.metrics.render(&self.swarm).into_bytes()),
+            _ => Response::text(404, "not found")
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use crate::tracker::{Settings, Tracker};
+
+    #[test]
+    fn count_udp_requests() {
+        let mut tracker = Tracker::new(Settings::default());
+        let metrics = Arc::clone(tracker.metrics());
+        let src = "1.2.3.4:6881".parse().unwrap();
+        let now = Instant::now();
+        let connect = [0, 0, 4, 0x17, 0x27, 0x10, 0x19, 0x80, 0, 0, 0, 0, 0, 0, 0, 1];
+        tracker.handle_packet(src, &connect, now);
+        // A scrape with a made up connection id, and an unknown action
+        let mut scrape = vec![0; 8];
+        scrape.extend_from_slice(&[0, 0, 0, 2, 0, 0, 0, 3]);
+        scrape.extend_from_slice(&[1; 20]);
+        tracker.handle_packet(src, &scrape, now);
+        tracker.handle_packet(src, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 9, 0, 0, 0, 4], now);
+        let text = metrics.render(&tracker.swarm());
+        assert!(text.contains("bittrickle_requests_total{protocol=\"udp\",action=\"connect\",outcome=\"ok\"} 1\n"));
+        assert!(text.contains("bittrickle_requests_total{protocol=\"udp\",action=\"scrape\",outcome=\"error\"} 1\n"));
+        assert!(text.contains("bittrickle_parse_errors_total{error=\"unknown_action\"} 1\n"));
+        assert!(text.contains("bittrickle_rejected_connection_ids_total 1\n"));
+        assert!(text.contains("bittrickle_torrents 0\n"));
+    }
+
+    #[test]
+    fn render_histograms() {
+        let metrics = Metrics::new();
+        metrics.record_sent(16);
+        metrics.record_sent(1000);
+        metrics.record_announce_latency(Protocol::Http, Duration::from_micros(30));
+        let text = metrics.render(&SharedSwarm::default());
+        assert!(text.contains("bittrickle_udp_response_bytes_bucket{le=\"16\"} 1\n"));
+        assert!(text.contains("bittrickle_udp_response_bytes_bucket{le=\"1024\"} 2\n"));
+        assert!(text.contains("bittrickle_udp_response_bytes_sum 1016\n"));
+        assert!(text.contains("bittrickle_udp_bytes_total{direction=\"out\"} 1016\n"));
+        let bucket = "bittrickle_announce_duration_seconds_bucket{protocol=\"http\",le=\"0.00005\"} 1\n";
+        assert!(text.contains(bucket));
+        assert!(text.contains("bittrickle_announce_duration_seconds_count{protocol=\"udp\"} 0\n"));
+    }
+}
@@ -1,5 +1,7 @@
-use rand::Rng;
-use std::net::{SocketAddrV4};
+use std::error;
+use std::fmt;
+use std::net::{SocketAddrV4, SocketAddrV6};
+use std::str::FromStr;
 
 /// Reads a u32 from a sequence of bytes, without checking length
 /// If the length is insufficient, subsequent bytes will be 0
@@ -93,17 +95,82 @@ pub enum ParseError {
     /// This announce event was unkown
     UnkownAnnounceEvent,
     /// The byte size for the data was insufficient
-    InsufficientBytes
+    InsufficientBytes,
+    /// A scrape asked about more torrents than we allow
+    TooManyInfoHashes,
+    /// A request was followed by bytes that don't belong to it
+    TrailingBytes
 }
 
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            ParseError::UnknownAction => "unknown action",
+            ParseError::UnkownAnnounceEvent => "unknown announce event",
+            ParseError::InsufficientBytes => "packet too short",
+            ParseError::TooManyInfoHashes => "too many info hashes",
+            ParseError::TrailingBytes => "trailing bytes after request"
+        };
+        f.write_str(message)
+    }
+}
+
+impl error::Error for ParseError {}
+
 
 /// A specialized `Result` for ParseErrors
 pub type ParseResult<T> = Result<T, ParseError>;
 
 
+/// What to do with a scrape asking about more torrents than we allow
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScrapeOverflow {
+    /// Only answer for the first torrents, up to the limit
+    Truncate,
+    /// Answer with an error
+    Error
+}
+
+impl FromStr for ScrapeOverflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "truncate" => Ok(ScrapeOverflow::Truncate),
+            "error" => Ok(ScrapeOverflow::Error),
+            _ => Err(format!("unknown scrape overflow action: {}", s))
+        }
+    }
+}
+
+
+/// How much we accept in a single request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParseLimits {
+    /// The most info hashes a scrape can ask about.
+    /// BEP 15 allows about 74, which is what fits in a single packet.
+    pub max_scrape_hashes: usize,
+    /// What to do with scrapes asking about more than that
+    pub scrape_overflow: ScrapeOverflow,
+    /// Refuse connects with anything after them, and announces followed
+    /// by anything that isn't a list of BEP 41 options
+    pub strict: bool
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        ParseLimits {
+            max_scrape_hashes: 74,
+            scrape_overflow: ScrapeOverflow::Truncate,
+            strict: false
+        }
+    }
+}
+
+
 /// Used to communicate intent between the client and the tracker
 /// The `Error` branch is removed, since it's only present in tracker responses
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Action {
     /// The client wishes to connect to the tracker
     Connect,
@@ -129,16 +196,38 @@ impl Action {
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct TransactionID(i32);
 
-/// A random ID used to confirm the identity of the client
+impl TransactionID {
+    /// Construct a TransactionID from its raw value
+    pub fn new(id: i32) -> Self {
+        TransactionID(id)
+    }
+
+    /// Try and read the transaction id out of a raw request packet.
+    /// This works even if the rest of the packet doesn't parse,
+    /// which lets us tell the client what went wrong.
+    pub fn from_packet(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 16 {
+            return None;
+        }
+        Some(TransactionID(read_i32(&bytes[12..])))
+    }
+}
+
+/// An ID the tracker hands out to confirm the identity of the client
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct ConnectionID(i64);
 
 impl ConnectionID {
-    /// Construct a new random ConnectionID
-    pub fn random<R: Rng + ?Sized>(rng: &mut R) -> Self {
-        ConnectionID(rng.gen())
+    /// Construct a ConnectionID from its raw value
+    pub fn new(id: i64) -> Self {
+        ConnectionID(id)
     }
     
+    /// The magic id clients use to connect
+    pub fn magic() -> Self {
+        ConnectionID(0x41727101980)
+    }
+
     /// Check if this connection id is the magic one the client says
     pub fn is_magic_id(&self) -> bool {
         self.0 == 0x41727101980
@@ -148,6 +237,9 @@ impl ConnectionID {
 /// Used to hold info hashes identifying a torrent
 pub type InfoHash = [u8; 20];
 
+/// The ID a peer chooses for itself
+pub type PeerId = [u8; 20];
+
 
 /// Useful for identifying which request we're dealing with
 #[derive(Debug, Clone)]
@@ -178,15 +270,28 @@ pub struct ConnectRequest {
 }
 
 impl ConnectRequest {
-    fn from_bytes(connection_id: ConnectionID, bytes: &[u8]) -> ParseResult<Self> {
+    fn from_bytes(connection_id: ConnectionID, bytes: &[u8], limits: &ParseLimits) -> ParseResult<Self> {
         if bytes.len() < 16 {
             return Err(ParseError::InsufficientBytes);
         }
+        if limits.strict && bytes.len() > 16 {
+            return Err(ParseError::TrailingBytes);
+        }
         let transaction_id = TransactionID(read_i32(&bytes[12..]));
         Ok(ConnectRequest { connection_id, transaction_id })
     }
 }
 
+impl Writable for ConnectRequest {
+    /// The buffer should be at least 16 bytes long
+    fn write(&self, buf: &mut [u8]) -> usize {
+        write_i64(self.connection_id.0, buf);
+        write_u32(0, &mut buf[8..]);
+        write_i32(self.transaction_id.0, &mut buf[12..]);
+        16
+    }
+}
+
 
 /// Represents the tracker response for a `ConnectRequest`
 #[derive(Debug, Clone)]
@@ -207,6 +312,18 @@ impl Writable for ConnectResponse {
     }
 }
 
+impl ConnectResponse {
+    fn from_bytes(bytes: &[u8]) -> ParseResult<Self> {
+        if bytes.len() < 16 {
+            return Err(ParseError::InsufficientBytes);
+        }
+        Ok(ConnectResponse {
+            transaction_id: TransactionID(read_i32(&bytes[4..])),
+            connection_id: ConnectionID(read_i64(&bytes[8..]))
+        })
+    }
+}
+
 
 /// Represents the event type for an Announce
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -231,6 +348,96 @@ impl AnnounceEvent {
             _ => Err(ParseError::UnkownAnnounceEvent)
         }
     }
+
+    fn to_i32(self) -> i32 {
+        match self {
+            AnnounceEvent::Nothing => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3
+        }
+    }
+}
+
+
+/// An option trailing an announce request, as in BEP 41
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnnounceOption {
+    /// Marks the end of the options, anything after it is ignored
+    EndOfOptions,
+    /// Padding, with no meaning
+    Nop,
+    /// Part of the path and query of the tracker's URL
+    UrlData(Vec<u8>)
+}
+
+impl AnnounceOption {
+    /// Split the path and query of a URL into as many options as it needs
+    pub fn url_data(data: &[u8]) -> Vec<Self> {
+        data.chunks(255).map(|chunk| AnnounceOption::UrlData(chunk.to_vec())).collect()
+    }
+
+    /// Parse the options following the fixed part of an announce.
+    ///
+    /// Options we don't know are skipped using their length byte, and
+    /// anything after the end of options is ignored, as BEP 41 says.
+    /// A truncated option ends the list, or is an error when `strict`.
+    fn parse_all(bytes: &[u8], strict: bool) -> ParseResult<Vec<Self>> {
+        let truncated = |options| if strict { Err(ParseError::TrailingBytes) } else { Ok(options) };
+        let mut options = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                0 => {
+                    options.push(AnnounceOption::EndOfOptions);
+                    break;
+                }
+                1 => {
+                    options.push(AnnounceOption::Nop);
+                    i += 1;
+                }
+                kind => {
+                    let len = match bytes.get(i + 1) {
+                        Some(&len) => len as usize,
+                        None => return truncated(options)
+                    };
+                    let data = match bytes.get(i + 2..i + 2 + len) {
+                        Some(data) => data,
+                        None => return truncated(options)
+                    };
+                    if kind == 2 {
+                        options.push(AnnounceOption::UrlData(data.to_vec()));
+                    }
+                    i += 2 + len;
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+impl Writable for AnnounceOption {
+    /// The buffer should be at least 2 bytes long, plus the URL data
+    fn write(&self, buf: &mut [u8]) -> usize {
+        match self {
+            AnnounceOption::EndOfOptions => {
+                buf[0] = 0;
+                1
+            }
+            AnnounceOption::Nop => {
+                buf[0] = 1;
+                1
+            }
+            AnnounceOption::UrlData(data) => {
+                // Longer data is cut to fit the length byte
+                let len = data.len().min(255);
+                buf[0] = 2;
+                buf[1] = len as u8;
+                buf[2..2 + len].copy_from_slice(&data[..len]);
+                2 + len
+            }
+        }
+    }
 }
 
 
@@ -243,7 +450,7 @@ pub struct AnnounceRequest {
     /// Any bytes are valid for the info hash
     pub info_hash: InfoHash,
     /// The ID the peer wishes to use
-    pub peer_id: [u8; 20],
+    pub peer_id: PeerId,
     /// How many bytes the client has downloaded
     pub downloaded: i64,
     /// How many bytes the client has left to download
@@ -260,11 +467,25 @@ pub struct AnnounceRequest {
     /// Negative indicates no preference
     pub num_want: i32,
     /// The port the client would like us to use
-    pub port: u16
+    pub port: u16,
+    /// The BEP 41 options after the fixed part of the request
+    pub options: Vec<AnnounceOption>
 }
 
 impl AnnounceRequest {
-    fn from_bytes(connection_id: ConnectionID, bytes: &[u8]) -> ParseResult<Self> {
+    /// The path and query of the URL the client used for us, from every
+    /// URL data option in order, which is empty if there are none
+    pub fn url_data(&self) -> Vec<u8> {
+        let mut url_data = Vec::new();
+        for option in &self.options {
+            if let AnnounceOption::UrlData(data) = option {
+                url_data.extend_from_slice(data);
+            }
+        }
+        url_data
+    }
+
+    fn from_bytes(connection_id: ConnectionID, bytes: &[u8], limits: &ParseLimits) -> ParseResult<Self> {
         if bytes.len() < 98 {
             return Err(ParseError::InsufficientBytes);
         }
@@ -285,14 +506,83 @@ impl AnnounceRequest {
             ip: read_u32(&bytes[84..]),
             key: read_u32(&bytes[88..]),
             num_want: read_i32(&bytes[92..]),
-            port: read_u16(&bytes[96..])
+            port: read_u16(&bytes[96..]),
+            options: AnnounceOption::parse_all(&bytes[98..], limits.strict)?
         })
     }
 }
 
+impl Writable for AnnounceRequest {
+    /// The buffer should be at least 98 bytes long, plus room for the options
+    fn write(&self, buf: &mut [u8]) -> usize {
+        write_i64(self.connection_id.0, buf);
+        write_u32(1, &mut buf[8..]);
+        write_i32(self.transaction_id.0, &mut buf[12..]);
+        buf[16..36].copy_from_slice(&self.info_hash);
+        buf[36..56].copy_from_slice(&self.peer_id);
+        write_i64(self.downloaded, &mut buf[56..]);
+        write_i64(self.left, &mut buf[64..]);
+        write_i64(self.uploaded, &mut buf[72..]);
+        write_i32(self.event.to_i32(), &mut buf[80..]);
+        write_u32(self.ip, &mut buf[84..]);
+        write_u32(self.key, &mut buf[88..]);
+        write_i32(self.num_want, &mut buf[92..]);
+        write_u16(self.port, &mut buf[96..]);
+        let mut i = 98;
+        for option in &self.options {
+            i += option.write(&mut buf[i..]);
+        }
+        i
+    }
+}
+
+/// A peer address that can be sent to clients in the compact format
+pub trait CompactPeer {
+    /// How many bytes a single address takes up
+    const SIZE: usize;
+
+    /// Write this address to a buffer, that must be at least `SIZE` bytes long
+    fn write_compact(&self, buf: &mut [u8]);
+
+    /// Read an address from a buffer, that must be at least `SIZE` bytes long
+    fn read_compact(bytes: &[u8]) -> Self;
+}
+
+impl CompactPeer for SocketAddrV4 {
+    const SIZE: usize = 6;
+
+    fn write_compact(&self, buf: &mut [u8]) {
+        write_u32(u32::from(*self.ip()), buf);
+        write_u16(self.port(), &mut buf[4..]);
+    }
+
+    fn read_compact(bytes: &[u8]) -> Self {
+        SocketAddrV4::new(read_u32(bytes).into(), read_u16(&bytes[4..]))
+    }
+}
+
+impl CompactPeer for SocketAddrV6 {
+    const SIZE: usize = 18;
+
+    fn write_compact(&self, buf: &mut [u8]) {
+        buf[..16].copy_from_slice(&self.ip().octets());
+        write_u16(self.port(), &mut buf[16..]);
+    }
+
+    fn read_compact(bytes: &[u8]) -> Self {
+        let mut octets = [0; 16];
+        octets.copy_from_slice(&bytes[..16]);
+        SocketAddrV6::new(octets.into(), read_u16(&bytes[16..]), 0, 0)
+    }
+}
+
+
 /// Represents the response to the Announce Request
+///
+/// Requests arriving over IPv4 get `SocketAddrV4` peers, and
+/// requests arriving over IPv6 get `SocketAddrV6` peers.
 #[derive(Debug, Clone)]
-pub struct AnnounceResponse {
+pub struct AnnounceResponse<P = SocketAddrV4> {
     /// The transaction id matching the client
     pub transaction_id: TransactionID,
     /// The interval in seconds we want the client to respond with
@@ -302,23 +592,48 @@ pub struct AnnounceResponse {
     /// How many peers have finished downloading the file
     pub seeders: i32,
     /// The peers we're sending in this response
-    pub peers: Vec<SocketAddrV4>
+    pub peers: Vec<P>
+}
+
+impl<P: CompactPeer> AnnounceResponse<P> {
+    /// How many peers fit in a response of at most `size` bytes
+    pub fn peers_fitting(size: usize) -> usize {
+        size.saturating_sub(20) / P::SIZE
+    }
 }
 
-impl Writable for AnnounceResponse {
+impl<P: CompactPeer> Writable for AnnounceResponse<P> {
+    /// The buffer should be at least 20 bytes long.
+    /// Peers that don't fit in the buffer are left out.
     fn write(&self, buf: &mut [u8]) -> usize {
         write_u32(1, buf);
         write_i32(self.transaction_id.0, &mut buf[4..]);
         write_i32(self.interval, &mut buf[8..]);
         write_i32(self.leechers, &mut buf[12..]);
         write_i32(self.seeders, &mut buf[16..]);
+        let count = self.peers.len().min(Self::peers_fitting(buf.len()));
         let mut i = 20;
-        for peer in &self.peers {
-            write_u32(u32::from(*peer.ip()), &mut buf[i..]);
-            write_u16(peer.port(), &mut buf[i + 4..]);
-            i += 6;
+        for peer in &self.peers[..count] {
+            peer.write_compact(&mut buf[i..]);
+            i += P::SIZE;
         }
-        20 + 6 * self.peers.len()
+        i
+    }
+}
+
+impl<P: CompactPeer> AnnounceResponse<P> {
+    fn from_bytes(bytes: &[u8]) -> ParseResult<Self> {
+        if bytes.len() < 20 || !(bytes.len() - 20).is_multiple_of(P::SIZE) {
+            return Err(ParseError::InsufficientBytes);
+        }
+        let peers = bytes[20..].chunks(P::SIZE).map(P::read_compact).collect();
+        Ok(AnnounceResponse {
+            transaction_id: TransactionID(read_i32(&bytes[4..])),
+            interval: read_i32(&bytes[8..]),
+            leechers: read_i32(&bytes[12..]),
+            seeders: read_i32(&bytes[16..]),
+            peers
+        })
     }
 }
 
@@ -335,25 +650,44 @@ pub struct ScrapeRequest {
 }
 
 impl ScrapeRequest {
-    fn from_bytes(connection_id: ConnectionID, bytes: &[u8]) -> ParseResult<Self> {
+    fn from_bytes(connection_id: ConnectionID, bytes: &[u8], limits: &ParseLimits) -> ParseResult<Self> {
         let len = bytes.len();
-        if bytes.len() < 16  || (len - 16) % 20 != 0 {
+        if bytes.len() < 16  || !(len - 16).is_multiple_of(20) {
             return Err(ParseError::InsufficientBytes)
         }
         let transaction_id = TransactionID(read_i32(&bytes[12..]));
-        let mut info_hashes = Vec::with_capacity((len - 16) / 20);
-        let mut i = 16;
-        while i < len {
+        let mut count = (len - 16) / 20;
+        if count > limits.max_scrape_hashes {
+            match limits.scrape_overflow {
+                ScrapeOverflow::Truncate => count = limits.max_scrape_hashes,
+                ScrapeOverflow::Error => return Err(ParseError::TooManyInfoHashes)
+            }
+        }
+        let mut info_hashes = Vec::with_capacity(count);
+        for chunk in bytes[16..].chunks_exact(20).take(count) {
             let mut hash = [0; 20];
-            let next_i = i + 20;
-            hash.copy_from_slice(&bytes[i..next_i]);
+            hash.copy_from_slice(chunk);
             info_hashes.push(hash);
-            i = next_i;
         }
         Ok(ScrapeRequest { connection_id, transaction_id, info_hashes })
     }
 }
 
+impl Writable for ScrapeRequest {
+    /// The buffer should be at least 16 bytes long, plus 20 per info hash
+    fn write(&self, buf: &mut [u8]) -> usize {
+        write_i64(self.connection_id.0, buf);
+        write_u32(2, &mut buf[8..]);
+        write_i32(self.transaction_id.0, &mut buf[12..]);
+        let mut i = 16;
+        for hash in &self.info_hashes {
+            buf[i..i + 20].copy_from_slice(hash);
+            i += 20;
+        }
+        16 + 20 * self.info_hashes.len()
+    }
+}
+
 /// An individual part of a scrape response
 #[derive(Clone, Debug)]
 pub struct ScrapeInfo {
@@ -396,6 +730,116 @@ impl Writable for ScrapeResponse {
     }
 }
 
+impl ScrapeResponse {
+    fn from_bytes(bytes: &[u8]) -> ParseResult<Self> {
+        if bytes.len() < 8 || !(bytes.len() - 8).is_multiple_of(12) {
+            return Err(ParseError::InsufficientBytes);
+        }
+        let scrapes = bytes[8..].chunks(12).map(|chunk| ScrapeInfo {
+            seeders: read_i32(chunk),
+            completed: read_i32(&chunk[4..]),
+            leechers: read_i32(&chunk[8..])
+        });
+        Ok(ScrapeResponse {
+            transaction_id: TransactionID(read_i32(&bytes[4..])),
+            scrapes: scrapes.collect()
+        })
+    }
+}
+
+
+/// Represents an error the tracker sends back instead of a normal response
+#[derive(Debug, Clone)]
+pub struct ErrorResponse {
+    /// The transaction id matching the client
+    pub transaction_id: TransactionID,
+    /// A human readable explanation of what went wrong
+    pub message: String
+}
+
+impl ErrorResponse {
+    /// Create a new error response for a given transaction
+    pub fn new(transaction_id: TransactionID, message: impl Into<String>) -> Self {
+        ErrorResponse { transaction_id, message: message.into() }
+    }
+}
+
+impl Writable for ErrorResponse {
+    /// The message is truncated if it doesn't fit in the buffer
+    fn write(&self, buf: &mut [u8]) -> usize {
+        write_u32(3, buf);
+        write_i32(self.transaction_id.0, &mut buf[4..]);
+        let message = self.message.as_bytes();
+        let len = message.len().min(buf.len() - 8);
+        buf[8..8 + len].copy_from_slice(&message[..len]);
+        8 + len
+    }
+}
+
+impl ErrorResponse {
+    fn from_bytes(bytes: &[u8]) -> ParseResult<Self> {
+        if bytes.len() < 8 {
+            return Err(ParseError::InsufficientBytes);
+        }
+        let transaction_id = TransactionID(read_i32(&bytes[4..]));
+        let message = String::from_utf8_lossy(&bytes[8..]).into_owned();
+        Ok(ErrorResponse { transaction_id, message })
+    }
+}
+
+
+/// An enum for the different responses the tracker can send
+#[derive(Debug, Clone)]
+pub enum Response {
+    Connect(ConnectResponse),
+    Announce(AnnounceResponse<SocketAddrV4>),
+    AnnounceV6(AnnounceResponse<SocketAddrV6>),
+    Scrape(ScrapeResponse),
+    Error(ErrorResponse)
+}
+
+impl Writable for Response {
+    fn write(&self, buf: &mut [u8]) -> usize {
+        match self {
+            Response::Connect(r) => r.write(buf),
+            Response::Announce(r) => r.write(buf),
+            Response::AnnounceV6(r) => r.write(buf),
+            Response::Scrape(r) => r.write(buf),
+            Response::Error(r) => r.write(buf)
+        }
+    }
+}
+
+impl Response {
+    /// Parse a response from the tracker.
+    /// Announce responses carry IPv6 peers when `ipv6` is set,
+    /// which should be the case when talking to the tracker over IPv6.
+    pub fn from_bytes(bytes: &[u8], ipv6: bool) -> ParseResult<Self> {
+        if bytes.len() < 8 {
+            return Err(ParseError::InsufficientBytes);
+        }
+        match read_i32(bytes) {
+            0 => ConnectResponse::from_bytes(bytes).map(Response::Connect),
+            1 if ipv6 => AnnounceResponse::from_bytes(bytes).map(Response::AnnounceV6),
+            1 => AnnounceResponse::from_bytes(bytes).map(Response::Announce),
+            2 => ScrapeResponse::from_bytes(bytes).map(Response::Scrape),
+            3 => ErrorResponse::from_bytes(bytes).map(Response::Error),
+            _ => Err(ParseError::UnknownAction)
+        }
+    }
+
+    /// The transaction id this response is for
+    pub fn transaction_id(&self) -> TransactionID {
+        match self {
+            Response::Connect(r) => r.transaction_id,
+            Response::Announce(r) => r.transaction_id,
+            Response::AnnounceV6(r) => r.transaction_id,
+            Response::Scrape(r) => r.transaction_id,
+            Response::Error(r) => r.transaction_id
+        }
+    }
+}
+
 
 /// An enum for the different types of requests the client can make
 #[derive(Debug, Clone, PartialEq)]
@@ -406,17 +850,23 @@ pub enum Request {
 }
 
 impl Request {
+    /// Parse a request with the default limits
     pub fn from_bytes(bytes: &[u8]) -> ParseResult<Self> {
+        Request::parse(bytes, &ParseLimits::default())
+    }
+
+    /// Parse a request, accepting only as much as `limits` allow
+    pub fn parse(bytes: &[u8], limits: &ParseLimits) -> ParseResult<Self> {
         let header = RequestHeader::from_bytes(bytes)?;
         match header.action {
             Action::Connect =>
-                ConnectRequest::from_bytes(header.connection_id, bytes)
+                ConnectRequest::from_bytes(header.connection_id, bytes, limits)
                     .map(Request::Connect),
             Action::Announce =>
-                AnnounceRequest::from_bytes(header.connection_id, bytes)
+                AnnounceRequest::from_bytes(header.connection_id, bytes, limits)
                     .map(Request::Announce),
             Action::Scrape =>
-                ScrapeRequest::from_bytes(header.connection_id, bytes)
+                ScrapeRequest::from_bytes(header.connection_id, bytes, limits)
                     .map(Request::Scrape)
         }
     }
@@ -472,11 +922,76 @@ mod tests {
             ip: 0,
             key: 1,
             num_want: 1,
-            port: 1
+            port: 1,
+            options: Vec::new()
         });
         assert_eq!(request, Ok(announce_request));
     }
 
+    #[test]
+    fn parse_announce_options() {
+        let mut bytes = vec![0; 98];
+        bytes[11] = 1;
+        bytes.extend_from_slice(&[1, 2, 5]);
+        bytes.extend_from_slice(b"/anno");
+        // An unknown option, skipped over
+        bytes.extend_from_slice(&[9, 2, 0, 0]);
+        bytes.extend_from_slice(&[2, 7]);
+        bytes.extend_from_slice(b"unce/ab");
+        bytes.extend_from_slice(&[0, 2, 3]);
+        let announce = match Request::from_bytes(&bytes) {
+            Ok(Request::Announce(r)) => r,
+            r => panic!("unexpected request {:?}", r)
+        };
+        assert_eq!(announce.options.len(), 4);
+        assert_eq!(announce.options[3], AnnounceOption::EndOfOptions);
+        assert_eq!(announce.url_data(), b"/announce/ab");
+        // A truncated option ends the list
+        bytes.truncate(98);
+        bytes.extend_from_slice(&[2, 10, b'/']);
+        match Request::from_bytes(&bytes) {
+            Ok(Request::Announce(r)) => assert!(r.options.is_empty()),
+            r => panic!("unexpected request {:?}", r)
+        }
+    }
+
+    #[test]
+    fn parse_strictly() {
+        let strict = ParseLimits { strict: true, ..ParseLimits::default() };
+        let mut connect = vec![0, 0, 4, 0x17, 0x27, 0x10, 0x19, 0x80, 0, 0, 0, 0, 0, 0, 0, 1];
+        assert!(Request::parse(&connect, &strict).is_ok());
+        connect.push(0);
+        assert!(Request::from_bytes(&connect).is_ok());
+        assert_eq!(Request::parse(&connect, &strict), Err(ParseError::TrailingBytes));
+        let mut announce = vec![0; 98];
+        announce[11] = 1;
+        // Anything after the end of options is allowed, even when strict
+        announce.extend_from_slice(&[1, 2, 1, b'/', 0, 0xff, 0xff]);
+        assert!(Request::parse(&announce, &strict).is_ok());
+        announce.truncate(98);
+        announce.extend_from_slice(&[2, 10, b'/']);
+        assert_eq!(Request::parse(&announce, &strict), Err(ParseError::TrailingBytes));
+    }
+
+    #[test]
+    fn limit_scrapes() {
+        let mut bytes = vec![0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+        for i in 0..80u8 {
+            bytes.extend_from_slice(&[i; 20]);
+        }
+        match Request::from_bytes(&bytes) {
+            Ok(Request::Scrape(r)) => {
+                assert_eq!(r.info_hashes.len(), 74);
+                assert_eq!(r.info_hashes[73], [73; 20]);
+            }
+            r => panic!("unexpected request {:?}", r)
+        }
+        let limits = ParseLimits { scrape_overflow: ScrapeOverflow::Error, ..ParseLimits::default() };
+        assert_eq!(Request::parse(&bytes, &limits), Err(ParseError::TooManyInfoHashes));
+        bytes.truncate(16 + 74 * 20);
+        assert!(Request::parse(&bytes, &limits).is_ok());
+    }
+
     #[test]
     fn parse_scrape() {
         let bytes = [
@@ -494,4 +1009,128 @@ mod tests {
         });
         assert_eq!(request, Ok(scrape_request));
     }
+
+    #[test]
+    fn transaction_id_from_bad_packet() {
+        let bytes = [
+            1, 2, 3, 4, 5, 6, 7, 8,
+            0, 0, 0, 9,
+            1, 2, 3, 4
+        ];
+        assert_eq!(Request::from_bytes(&bytes), Err(ParseError::UnknownAction));
+        let transaction_id = TransactionID::from_packet(&bytes);
+        assert_eq!(transaction_id, Some(TransactionID(0x1020304)));
+        assert_eq!(TransactionID::from_packet(&bytes[..12]), None);
+    }
+
+    #[test]
+    fn write_announce_v6() {
+        let response = AnnounceResponse {
+            transaction_id: TransactionID(1),
+            interval: 2,
+            leechers: 3,
+            seeders: 4,
+            peers: vec!["[::1]:258".parse::<SocketAddrV6>().unwrap()]
+        };
+        let mut buf = [0; 64];
+        let count = response.write(&mut buf);
+        assert_eq!(count, 38);
+        assert_eq!(&buf[..4], &[0, 0, 0, 1]);
+        assert_eq!(buf[35], 1);
+        assert_eq!(&buf[36..38], &[1, 2]);
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let announce = AnnounceRequest {
+            connection_id: ConnectionID(7),
+            transaction_id: TransactionID(8),
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            downloaded: 3,
+            left: 4,
+            uploaded: 5,
+            event: AnnounceEvent::Stopped,
+            ip: 6,
+            key: 7,
+            num_want: -1,
+            port: 6881,
+            options: vec![AnnounceOption::Nop, AnnounceOption::UrlData(b"/announce".to_vec())]
+        };
+        let scrape = ScrapeRequest {
+            connection_id: ConnectionID(7),
+            transaction_id: TransactionID(8),
+            info_hashes: vec![[1; 20], [2; 20]]
+        };
+        let connect = ConnectRequest {
+            connection_id: ConnectionID::magic(),
+            transaction_id: TransactionID(8)
+        };
+        let mut buf = [0; 128];
+        let count = announce.write(&mut buf);
+        assert_eq!(Request::from_bytes(&buf[..count]), Ok(Request::Announce(announce)));
+        let count = scrape.write(&mut buf);
+        assert_eq!(Request::from_bytes(&buf[..count]), Ok(Request::Scrape(scrape)));
+        let count = connect.write(&mut buf);
+        assert_eq!(Request::from_bytes(&buf[..count]), Ok(Request::Connect(connect)));
+    }
+
+    #[test]
+    fn parse_responses() {
+        let mut buf = [0; 128];
+        let announce = AnnounceResponse {
+            transaction_id: TransactionID(1),
+            interval: 2,
+            leechers: 3,
+            seeders: 4,
+            peers: vec!["[::1]:258".parse::<SocketAddrV6>().unwrap()]
+        };
+        let count = announce.write(&mut buf);
+        match Response::from_bytes(&buf[..count], true) {
+            Ok(Response::AnnounceV6(r)) => assert_eq!(r.peers, announce.peers),
+            r => panic!("unexpected response {:?}", r)
+        }
+
+        let scrape = ScrapeResponse {
+            transaction_id: TransactionID(1),
+            scrapes: vec![ScrapeInfo { seeders: 1, completed: 2, leechers: 3 }]
+        };
+        let count = scrape.write(&mut buf);
+        match Response::from_bytes(&buf[..count], false) {
+            Ok(Response::Scrape(r)) => assert_eq!(r.scrapes[0].leechers, 3),
+            r => panic!("unexpected response {:?}", r)
+        }
+
+        let error = ErrorResponse::new(TransactionID(1), "nope");
+        let count = error.write(&mut buf);
+        match Response::from_bytes(&buf[..count], false) {
+            Ok(Response::Error(r)) => assert_eq!(r.message, "nope"),
+            r => panic!("unexpected response {:?}", r)
+        }
+    }
+
+    #[test]
+    fn write_announce_within_buffer() {
+        let peer = "1.2.3.4:5".parse::<SocketAddrV4>().unwrap();
+        let response = AnnounceResponse {
+            transaction_id: TransactionID(1),
+            interval: 2,
+            leechers: 3,
+            seeders: 4,
+            peers: vec![peer; 10]
+        };
+        let mut buf = [0; 40];
+        assert_eq!(response.write(&mut buf), 38);
+        assert_eq!(&buf[32..38], &[1, 2, 3, 4, 0, 5]);
+    }
+
+    #[test]
+    fn write_error() {
+        let response = ErrorResponse::new(TransactionID(0x1020304), "bad");
+        let mut buf = [0; 16];
+        let count = response.write(&mut buf);
+        assert_eq!(&buf[..count], &[0, 0, 0, 3, 1, 2, 3, 4, b'b', b'a', b'd']);
+        let count = response.write(&mut buf[..10]);
+        assert_eq!(count, 10);
+    }
 }
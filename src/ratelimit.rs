@@ -0,0 +1,338 @@
+//! Token bucket rate limits, per source address and per network prefix.
+//!
+//! Every client address, and every /24 or /64 network, gets a bucket per
+//! action, which fills up at a steady rate and holds a limited burst.
+//! Each request takes a token from both of its buckets, and is refused,
+//! without taking anything, if either is empty. Limiting whole prefixes
+//! keeps a single network from getting around the limits by spreading
+//! over many addresses.
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+use crate::protocol::Action;
+use crate::swarm::canonical_addr;
+
+
+/// The most buckets we keep for one action and scope.
+/// Spoofed floods can come from any address, so this has to be bounded.
+const MAX_BUCKETS: usize = 100_000;
+
+
+/// How fast requests can come in, and how many can come at once
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    /// The requests allowed per second, in the long run
+    pub rate: f64,
+    /// The requests allowed in a single burst
+    pub burst: u32
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    /// Parse a limit written as `<rate>/<burst>`, or just `<rate>`,
+    /// which allows bursts of a second's worth of requests
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid rate limit: {}, expected <per second>/<burst>", s);
+        let mut parts = s.splitn(2, '/');
+        let rate: f64 = parts.next().unwrap_or("").trim().parse().map_err(|_| invalid())?;
+        let burst = match parts.next() {
+            Some(burst) => burst.trim().parse().map_err(|_| invalid())?,
+            None => rate.ceil() as u32
+        };
+        if !rate.is_finite() || rate <= 0.0 || burst == 0 {
+            return Err(invalid());
+        }
+        Ok(Limit { rate, burst })
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.rate, self.burst)
+    }
+}
+
+
+/// What to do with a request that goes over a limit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverLimit {
+    /// Send nothing back, which costs us the least
+    Drop,
+    /// Send back an error, so that honest clients know to slow down
+    Error
+}
+
+impl FromStr for OverLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(OverLimit::Drop),
+            "error" => Ok(OverLimit::Error),
+            _ => Err(format!("unknown over limit action: {}", s))
+        }
+    }
+}
+
+
+/// The limits for each action, none of which are set by default
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimits {
+    pub connect_ip: Option<Limit>,
+    pub connect_prefix: Option<Limit>,
+    pub announce_ip: Option<Limit>,
+    pub announce_prefix: Option<Limit>,
+    pub scrape_ip: Option<Limit>,
+    pub scrape_prefix: Option<Limit>,
+    /// What to do with requests over a limit
+    pub over_limit: OverLimit
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            connect_ip: None,
+            connect_prefix: None,
+            announce_ip: None,
+            announce_prefix: None,
+            scrape_ip: None,
+            scrape_prefix: None,
+            over_limit: OverLimit::Drop
+        }
+    }
+}
+
+impl RateLimits {
+    /// The limits for an action, per address and per prefix
+    fn for_action(&self, action: Action) -> [Option<Limit>; 2] {
+        match action {
+            Action::Connect => [self.connect_ip, self.connect_prefix],
+            Action::Announce => [self.announce_ip, self.announce_prefix],
+            Action::Scrape => [self.scrape_ip, self.scrape_prefix]
+        }
+    }
+}
+
+
+/// Which of a request's buckets a decision was made on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    /// The bucket for the exact address
+    Ip,
+    /// The bucket for the /24 or /64 the address is in
+    Prefix
+}
+
+/// Whether a request can go ahead
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    Allowed,
+    Limited(Scope)
+}
+
+
+/// The network an address is in, for limiting by prefix
+fn prefix(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4((u32::from(ip) & 0xffff_ff00).into()),
+        IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & !((1 << 64) - 1)).into())
+    }
+}
+
+
+/// A bucket of tokens, refilled as time passes
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant
+}
+
+impl Bucket {
+    /// Refill the bucket up to `now`
+    fn refill(&mut self, limit: Limit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst as f64);
+        self.updated = now;
+    }
+}
+
+
+/// The buckets for one action and scope, in a fixed number of slots.
+///
+/// Each address hashes to a slot, with a random key so that floods can't
+/// aim for one, and takes it over from whichever address had it before.
+/// A flood of new addresses can only push out older buckets, which then
+/// start again full, so it never keeps anyone out or costs more memory.
+struct Buckets {
+    hasher: RandomState,
+    slots: Vec<Option<(IpAddr, Bucket)>>
+}
+
+impl Buckets {
+    fn new() -> Self {
+        Buckets { hasher: RandomState::new(), slots: Vec::new() }
+    }
+
+    /// The bucket for a key, refilled up to `now`
+    fn bucket(&mut self, key: IpAddr, limit: Limit, now: Instant) -> &mut Bucket {
+        // Most limits are never set, so slots are only made once they're used
+        if self.slots.is_empty() {
+            self.slots = vec![None; MAX_BUCKETS];
+        }
+        let slot = &mut self.slots[(self.hasher.hash_one(key) % MAX_BUCKETS as u64) as usize];
+        if slot.as_ref().is_some_and(|(owner, _)| *owner != key) {
+            *slot = None;
+        }
+        let (_, bucket) = slot.get_or_insert((key, Bucket { tokens: limit.burst as f64, updated: now }));
+        bucket.refill(limit, now);
+        bucket
+    }
+}
+
+
+/// Decides which requests go over their limits.
+///
+/// This can be shared between threads, so that clients get the same
+/// limits whichever thread their requests end up on.
+pub struct RateLimiter {
+    limits: RateLimits,
+    /// Indexed by action, then by scope
+    buckets: [[Mutex<Buckets>; 2]; 3]
+}
+
+impl RateLimiter {
+    /// Create a limiter with every bucket full
+    pub fn new(limits: RateLimits) -> Self {
+        let buckets = || Mutex::new(Buckets::new());
+        RateLimiter {
+            limits,
+            buckets: [[buckets(), buckets()], [buckets(), buckets()], [buckets(), buckets()]]
+        }
+    }
+
+    /// The limits this limiter enforces
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    fn lock(&self, action: Action, scope: Scope) -> MutexGuard<'_, Buckets> {
+        self.buckets[action as usize][scope as usize].lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Decide if a request from an address can go ahead, taking tokens if so.
+    /// Refused requests take nothing, even from the bucket that had tokens left.
+    pub fn check(&self, action: Action, ip: IpAddr, now: Instant) -> Decision {
+        // Mapped IPv4 addresses share buckets with the plain ones
+        let ip = canonical_addr((ip, 0).into()).ip();
+        let [ip_limit, prefix_limit] = self.limits.for_action(action);
+        // Both locks are always taken in this order, so this can't deadlock
+        let mut ip_buckets = ip_limit.map(|_| self.lock(action, Scope::Ip));
+        let mut prefix_buckets = prefix_limit.map(|_| self.lock(action, Scope::Prefix));
+        let ip_bucket = ip_buckets.as_mut().zip(ip_limit).map(|(buckets, limit)| buckets.bucket(ip, limit, now));
+        let prefix_bucket = prefix_buckets.as_mut().zip(prefix_limit).map(|(buckets, limit)| buckets.bucket(prefix(ip), limit, now));
+        if ip_bucket.as_ref().is_some_and(|bucket| bucket.tokens < 1.0) {
+            return Decision::Limited(Scope::Ip);
+        }
+        if prefix_bucket.as_ref().is_some_and(|bucket| bucket.tokens < 1.0) {
+            return Decision::Limited(Scope::Prefix);
+        }
+        for bucket in ip_bucket.into_iter().chain(prefix_bucket) {
+            bucket.tokens -= 1.0;
+        }
+        Decision::Allowed
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(RateLimits::default())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_limits() {
+        assert_eq!("2.5/10".parse(), Ok(Limit { rate: 2.5, burst: 10 }));
+        assert_eq!("3".parse(), Ok(Limit { rate: 3.0, burst: 3 }));
+        assert!("0/10".parse::<Limit>().is_err());
+        assert!("1/0".parse::<Limit>().is_err());
+        assert!("fast".parse::<Limit>().is_err());
+    }
+
+    #[test]
+    fn buckets_refill() {
+        let limits = RateLimits { connect_ip: Some(Limit { rate: 1.0, burst: 2 }), ..RateLimits::default() };
+        let limiter = RateLimiter::new(limits);
+        let now = Instant::now();
+        let src = ip("1.2.3.4");
+        assert_eq!(limiter.check(Action::Connect, src, now), Decision::Allowed);
+        assert_eq!(limiter.check(Action::Connect, src, now), Decision::Allowed);
+        assert_eq!(limiter.check(Action::Connect, src, now), Decision::Limited(Scope::Ip));
+        // Other addresses and actions have their own buckets
+        assert_eq!(limiter.check(Action::Connect, ip("1.2.3.5"), now), Decision::Allowed);
+        assert_eq!(limiter.check(Action::Announce, src, now), Decision::Allowed);
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.check(Action::Connect, src, later), Decision::Allowed);
+        assert_eq!(limiter.check(Action::Connect, src, later), Decision::Limited(Scope::Ip));
+    }
+
+    #[test]
+    fn prefixes_share_buckets() {
+        let limits = RateLimits { scrape_prefix: Some(Limit { rate: 1.0, burst: 2 }), ..RateLimits::default() };
+        let limiter = RateLimiter::new(limits);
+        let now = Instant::now();
+        assert_eq!(limiter.check(Action::Scrape, ip("10.0.0.1"), now), Decision::Allowed);
+        assert_eq!(limiter.check(Action::Scrape, ip("::ffff:10.0.0.2"), now), Decision::Allowed);
+        assert_eq!(limiter.check(Action::Scrape, ip("10.0.0.3"), now), Decision::Limited(Scope::Prefix));
+        assert_eq!(limiter.check(Action::Scrape, ip("10.0.1.1"), now), Decision::Allowed);
+        assert_eq!(limiter.check(Action::Scrape, ip("2001:db8::1"), now), Decision::Allowed);
+        assert_eq!(limiter.check(Action::Scrape, ip("2001:db8::ffff:1"), now), Decision::Allowed);
+        assert_eq!(limiter.check(Action::Scrape, ip("2001:db8::2"), now), Decision::Limited(Scope::Prefix));
+        assert_eq!(limiter.check(Action::Scrape, ip("2001:db8:0:1::1"), now), Decision::Allowed);
+    }
+
+    #[test]
+    fn refusals_take_nothing() {
+        let limits = RateLimits {
+            announce_ip: Some(Limit { rate: 0.001, burst: 2 }),
+            announce_prefix: Some(Limit { rate: 1.0, burst: 1 }),
+            ..RateLimits::default()
+        };
+        let limiter = RateLimiter::new(limits);
+        let now = Instant::now();
+        let src = ip("1.2.3.4");
+        assert_eq!(limiter.check(Action::Announce, src, now), Decision::Allowed);
+        assert_eq!(limiter.check(Action::Announce, src, now), Decision::Limited(Scope::Prefix));
+        // The refused announce left the address its second token
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.check(Action::Announce, src, later), Decision::Allowed);
+        assert_eq!(limiter.check(Action::Announce, src, later + Duration::from_secs(1)), Decision::Limited(Scope::Ip));
+    }
+
+    #[test]
+    fn floods_push_out_old_buckets() {
+        let limits = RateLimits { connect_ip: Some(Limit { rate: 0.001, burst: 1 }), ..RateLimits::default() };
+        let limiter = RateLimiter::new(limits);
+        let now = Instant::now();
+        for i in 0..2 * MAX_BUCKETS as u32 {
+            assert_eq!(limiter.check(Action::Connect, IpAddr::from(i.to_be_bytes()), now), Decision::Allowed);
+        }
+        assert_eq!(limiter.check(Action::Connect, ip("255.1.2.3"), now), Decision::Allowed);
+        assert_eq!(limiter.lock(Action::Connect, Scope::Ip).slots.len(), MAX_BUCKETS);
+    }
+}
@@ -0,0 +1,168 @@
+use rand::{FromEntropy, rngs::SmallRng, seq::SliceRandom};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use crate::swarm::Peer;
+
+
+/// A peer we could send back in an announce response
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate<'a> {
+    /// The address the peer can be reached at
+    pub addr: SocketAddr,
+    /// What we know about the peer
+    pub peer: &'a Peer
+}
+
+
+/// A strategy for choosing which peers to send back to an announcing client.
+///
+/// The swarm has already left out the client itself, and split the
+/// candidates into seeders and leechers, so a selector only has to pick
+/// which of the candidates it gets are the most useful.
+pub trait PeerSelector: Send {
+    /// Choose up to `amount` of the candidates to send to `requester`
+    fn select<'a>(&mut self, requester: SocketAddr, candidates: Vec<Candidate<'a>>, amount: usize) -> Vec<Candidate<'a>>;
+}
+
+
+/// Picks peers uniformly at random
+pub struct RandomSelector {
+    rng: SmallRng
+}
+
+impl RandomSelector {
+    pub fn new() -> Self {
+        RandomSelector { rng: SmallRng::from_entropy() }
+    }
+}
+
+impl Default for RandomSelector {
+    fn default() -> Self {
+        RandomSelector::new()
+    }
+}
+
+impl PeerSelector for RandomSelector {
+    fn select<'a>(&mut self, _: SocketAddr, candidates: Vec<Candidate<'a>>, amount: usize) -> Vec<Candidate<'a>> {
+        candidates.choose_multiple(&mut self.rng, amount).cloned().collect()
+    }
+}
+
+
+/// Picks the peers that announced most recently, since they're the
+/// most likely to still be around
+pub struct FreshestSelector;
+
+impl PeerSelector for FreshestSelector {
+    fn select<'a>(&mut self, _: SocketAddr, mut candidates: Vec<Candidate<'a>>, amount: usize) -> Vec<Candidate<'a>> {
+        candidates.sort_by_key(|c| std::cmp::Reverse(c.peer.last_announce()));
+        candidates.truncate(amount);
+        candidates
+    }
+}
+
+
+/// How many leading bits two addresses of the same family have in common
+fn common_prefix(a: IpAddr, b: IpAddr) -> u32 {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => (u32::from(a) ^ u32::from(b)).leading_zeros(),
+        (IpAddr::V6(a), IpAddr::V6(b)) => (u128::from(a) ^ u128::from(b)).leading_zeros(),
+        _ => 0
+    }
+}
+
+/// Picks the peers whose addresses share the longest prefix with the
+/// client's, as a cheap guess at which ones are nearby.
+/// Peers that are just as close are picked at random.
+pub struct LocalitySelector {
+    rng: SmallRng
+}
+
+impl LocalitySelector {
+    pub fn new() -> Self {
+        LocalitySelector { rng: SmallRng::from_entropy() }
+    }
+}
+
+impl Default for LocalitySelector {
+    fn default() -> Self {
+        LocalitySelector::new()
+    }
+}
+
+impl PeerSelector for LocalitySelector {
+    fn select<'a>(&mut self, requester: SocketAddr, mut candidates: Vec<Candidate<'a>>, amount: usize) -> Vec<Candidate<'a>> {
+        candidates.shuffle(&mut self.rng);
+        candidates.sort_by_key(|c| std::cmp::Reverse(common_prefix(requester.ip(), c.addr.ip())));
+        candidates.truncate(amount);
+        candidates
+    }
+}
+
+
+/// The selectors that can be chosen in the config
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelectorKind {
+    Random,
+    Freshest,
+    Locality
+}
+
+impl SelectorKind {
+    /// Create a selector of this kind
+    pub fn build(self) -> Box<dyn PeerSelector> {
+        match self {
+            SelectorKind::Random => Box::new(RandomSelector::new()),
+            SelectorKind::Freshest => Box::new(FreshestSelector),
+            SelectorKind::Locality => Box::new(LocalitySelector::new())
+        }
+    }
+}
+
+impl FromStr for SelectorKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(SelectorKind::Random),
+            "freshest" => Ok(SelectorKind::Freshest),
+            "locality" => Ok(SelectorKind::Locality),
+            _ => Err(format!("unknown peer selector: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for SelectorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            SelectorKind::Random => "random",
+            SelectorKind::Freshest => "freshest",
+            SelectorKind::Locality => "locality"
+        };
+        f.write_str(name)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix_lengths() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(common_prefix(ip("10.0.0.1"), ip("10.0.0.1")), 32);
+        assert_eq!(common_prefix(ip("10.0.0.1"), ip("10.0.1.1")), 23);
+        assert_eq!(common_prefix(ip("10.0.0.1"), ip("::1")), 0);
+    }
+
+    #[test]
+    fn parse_kinds() {
+        for kind in &[SelectorKind::Random, SelectorKind::Freshest, SelectorKind::Locality] {
+            assert_eq!(kind.to_string().parse(), Ok(*kind));
+        }
+        assert!("nearest".parse::<SelectorKind>().is_err());
+    }
+}
@@ -1,201 +1,148 @@
-use rand::{prelude::ThreadRng, thread_rng, Rng, seq::SliceRandom};
-use std::collections::{HashMap};
 use std::io;
-use std::net::{ToSocketAddrs, SocketAddr, SocketAddrV4, UdpSocket};
-
-use crate::protocol::{
-    AnnounceRequest, AnnounceEvent, AnnounceResponse,
-    ConnectionID, ConnectResponse, ConnectRequest, InfoHash, Request, 
-    ScrapeInfo, ScrapeResponse, ScrapeRequest, Writable
-};
-
-
-/// Represents the information associated with the torrent
-#[derive(Clone, Debug)]
-struct TorrentInfo {
-    leechers: i32,
-    completed: i32,
-    seeders: i32,
-    peers: Vec<SocketAddrV4>
-}
+use std::net::{ToSocketAddrs, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Instant;
 
-impl TorrentInfo {
-    /// Try and insert a socket address into our set of peers
-    fn insert(&mut self, sock: SocketAddrV4) -> bool {
-        let search_res = self.peers.binary_search_by(|probe| {
-            (probe.ip(), probe.port()).cmp(&(sock.ip(), sock.port()))
-        });
-        match search_res {
-            Ok(_) => false,
-            Err(insert_i) => {
-                self.peers.insert(insert_i, sock);
-                true
-            }
-        }
-    }
-
-    /// Add a new peer to an existing torrent
-    fn handle_peer(&mut self, peer: SocketAddr, event: AnnounceEvent) {
-        match peer {
-            SocketAddr::V4(sock) => { 
-                match event {
-                    AnnounceEvent::Nothing => {}
-                    AnnounceEvent::Completed => {
-                        self.leechers -= 1;
-                        self.seeders += 1;
-                        self.completed += 1;
-                    }
-                    AnnounceEvent::Started => {
-                        if self.insert(sock) {
-                            self.leechers += 1;
-                        }
-                    }
-                    AnnounceEvent::Stopped => {
-                        self.leechers -= 1;
-                    }
-                }
-            }
-            // We don't handle v6 address
-            SocketAddr::V6(_) => {}
-        }
-    }
-
-    /// Create a torrent from the first peer to announce it
-    fn from_first_peer(peer: SocketAddr) -> Self {
-        let mut info = TorrentInfo {
-            leechers: 0, completed: 0, seeders: 0, peers: Vec::new()
-        };
-        match peer {
-            SocketAddr::V4(sock) => {
-                if info.insert(sock) {
-                    info.seeders += 1;
-                }
-            }
-            SocketAddr::V6(_) => {}
-        }
-        info
-    }
-    
-    fn sample_peers<R: Rng>(&self, rng: &mut R, amount: usize) -> Vec<SocketAddrV4> {
-        self.peers.choose_multiple(rng, amount).cloned().collect()
-    }
-}
+use crate::accounting::Ledger;
+use crate::config::Config;
+use crate::metrics::Metrics;
+use crate::protocol::Writable;
+use crate::ratelimit::RateLimiter;
+use crate::shutdown::{Shutdown, POLL_INTERVAL};
+use crate::swarm::SharedSwarm;
+use crate::tracker::{Settings, Tracker};
+use crate::transport::{Backend, Batch, Transport, BATCH_SIZE};
+use crate::users::UserTable;
 
 
 /// Holds all the state a server needs to run
 pub struct Server {
-    rng: ThreadRng,
-    socket: UdpSocket,
-    read_buf: Vec<u8>,
-    write_buf: Vec<u8>,
-    connections: HashMap<SocketAddr, ConnectionID>,
-    torrents: HashMap<InfoHash, TorrentInfo>
+    transport: Box<dyn Transport>,
+    incoming: Batch,
+    outgoing: Batch,
+    tracker: Tracker,
+    shutdown: Shutdown
 }
 
 impl Server {
     /// Create a new server, with an address to bind the socket to.
     /// The socket might not be able to be created, so this
     /// function returns an io result.
-    pub fn new(addr: impl ToSocketAddrs) -> io::Result<Self> {
-        let rng = thread_rng();
+    ///
+    /// Binding to an unspecified IPv6 address, such as `[::]:8080`, gives
+    /// a dual-stack socket on systems that allow it, serving both
+    /// IPv4 and IPv6 clients.
+    pub fn new(addr: impl ToSocketAddrs, settings: Settings) -> io::Result<Self> {
+        Server::with_tracker(addr, Tracker::new(settings))
+    }
+
+    /// Create a new server, answering requests with an existing tracker
+    pub fn with_tracker(addr: impl ToSocketAddrs, tracker: Tracker) -> io::Result<Self> {
+        Server::with_backend(addr, tracker, Backend::Std)
+    }
+
+    /// Create a new server, moving packets with a certain backend
+    pub fn with_backend(addr: impl ToSocketAddrs, tracker: Tracker, backend: Backend) -> io::Result<Self> {
         let socket = UdpSocket::bind(addr)?;
-        let read_buf = vec![0; 2048];
-        let write_buf = vec![0; 2048];
-        let connections = HashMap::new();
-        let torrents = HashMap::new();
-        Ok(Server { 
-            rng, socket, read_buf, write_buf, connections, torrents 
-         })
+        // We need to wake up regularly to sweep and check for a shutdown, even without traffic
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        Ok(Server::with_transport(backend.wrap(socket), tracker))
     }
 
-    /// Run the server, blocking the current thread
-    /// If an io error occurrs at any point, this function returns.
-    pub fn run(&mut self) -> std::io::Result<()> {
-        loop {
-            let (amt, src) = self.socket.recv_from(&mut self.read_buf)?;
-            let request = Request::from_bytes(&self.read_buf[..amt]);
-            if let Ok(r) = request {
-                self.handle_request(src, &r)?;
-            }
-        }
+    /// Create a new server over any transport.
+    /// Reads from the transport should time out at least every `POLL_INTERVAL`,
+    /// or the server will be slow to notice a shutdown.
+    pub fn with_transport(transport: Box<dyn Transport>, tracker: Tracker) -> Self {
+        let incoming = Batch::new(BATCH_SIZE, 2048);
+        // Responses are kept within the datagram budget by the tracker
+        let settings = tracker.settings();
+        let outgoing = Batch::new(BATCH_SIZE, settings.max_datagram_v4.max(settings.max_datagram_v6));
+        Server { transport, incoming, outgoing, tracker, shutdown: Shutdown::new() }
     }
- 
-    fn write_to_socket(&mut self, w: impl Writable, src: SocketAddr) -> io::Result<()> {
-        let count = w.write(&mut self.write_buf);
-        let mut start = 0;
-        while start < count {
-            let slice = &self.write_buf[start..count];
-            start = self.socket.send_to(slice, src)?;
-        }
-        Ok(())
+
+    /// Create a server for every UDP address in a config, all sharing one swarm
+    pub fn bind_all(config: &Config, swarm: &SharedSwarm) -> io::Result<Vec<Self>> {
+        let limiter = Arc::new(RateLimiter::new(config.tracker.rate_limits.clone()));
+        config.udp.iter().map(|addr| {
+            let mut tracker = Tracker::with_swarm(config.tracker.clone(), SharedSwarm::clone(swarm));
+            tracker.set_rate_limiter(Arc::clone(&limiter));
+            Server::with_backend(addr, tracker, config.udp_backend)
+        }).collect()
     }
-    
-    fn handle_request(&mut self, src: SocketAddr, request: &Request) -> io::Result<()> {
-        match request {
-            Request::Connect(r) => self.handle_connect(src, r),
-            Request::Announce(r) => self.handle_announce(src, r),
-            Request::Scrape(r) => self.handle_scrape(src, r)
-        }
+
+    /// Record what this server does into a shared set of metrics
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.tracker.set_metrics(metrics);
     }
 
-    fn handle_connect(&mut self, src: SocketAddr, req: &ConnectRequest) -> io::Result<()> {
-        // We do nothing if the magic id is wrong
-        if req.connection_id.is_magic_id() {
-            let connection_id = ConnectionID::random(&mut self.rng);
-            let transaction_id = req.transaction_id;
-            let response = ConnectResponse {
-                transaction_id, connection_id
-            };
-            self.write_to_socket(response, src)?;
-            self.connections.insert(src, connection_id);
-        }
-        Ok(())
+    /// Only let in announces from these users, see `Tracker::set_users`
+    pub fn set_users(&mut self, users: Arc<UserTable>) {
+        self.tracker.set_users(users);
     }
 
-    fn handle_announce(&mut self, src: SocketAddr, req: &AnnounceRequest) -> io::Result<()> {
-        if Some(&req.connection_id) == self.connections.get(&src) {
-            let info = match self.torrents.get_mut(&req.info_hash) {
-                Some(info) => {
-                    info.handle_peer(src, req.event);
-                    info.clone()
-                }
-                None => {
-                    let info = TorrentInfo::from_first_peer(src);
-                    self.torrents.insert(req.info_hash, info.clone());
-                    info
-                }
-            };
-            let transaction_id = req.transaction_id;
-            let interval = 15 * 60;
-            let leechers = info.leechers;
-            let seeders = info.seeders;
-            let peers = info.sample_peers(&mut self.rng, 50);
-            let response = AnnounceResponse {
-                transaction_id, interval, leechers, seeders, peers
-            };
-            self.write_to_socket(response, src)?;
-        }
-        Ok(())
+    /// Credit the bytes clients report to their accounts, see `Tracker::set_ledger`
+    pub fn set_ledger(&mut self, ledger: Arc<Ledger>) {
+        self.tracker.set_ledger(ledger);
+    }
+
+    /// Stop running once this handle is triggered
+    pub fn set_shutdown(&mut self, shutdown: Shutdown) {
+        self.shutdown = shutdown;
     }
 
-    fn handle_scrape(&mut self, src: SocketAddr, req: &ScrapeRequest) -> io::Result<()> {
-        if Some(&req.connection_id) == self.connections.get(&src) {
-            let mut scrapes = Vec::with_capacity(self.torrents.len());
-            for hash in &req.info_hashes {
-                let scrape = match self.torrents.get(hash) {
-                    Some(info) => ScrapeInfo {
-                        seeders: info.seeders,
-                        completed: info.completed,
-                        leechers: info.leechers
-                    },
-                    None => ScrapeInfo::empty()
-                };
-                scrapes.push(scrape);
+    /// Get a handle to the swarm this server uses, to share it with other frontends
+    pub fn swarm(&self) -> SharedSwarm {
+        self.tracker.swarm()
+    }
+
+    /// The address the socket is bound to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.transport.local_addr()
+    }
+
+    /// Run the server, blocking the current thread
+    /// If the socket fails at any point, this function returns. Responses
+    /// that just can't go to their address are counted and skipped.
+    ///
+    /// Once the shutdown handle is triggered, the server answers the packets
+    /// it already read, stops reading, and returns `Ok(())`.
+    pub fn run(&mut self) -> std::io::Result<()> {
+        let metrics = Arc::clone(self.tracker.metrics());
+        while !self.shutdown.is_triggered() {
+            self.transport.recv(&mut self.incoming)?;
+            let now = Instant::now();
+            self.outgoing.clear();
+            for (src, bytes) in self.incoming.iter() {
+                metrics.record_received(bytes.len());
+                if let Some(response) = self.tracker.handle_packet(src, bytes, now) {
+                    self.outgoing.push_with(src, |buf| {
+                        let count = response.write(buf);
+                        metrics.record_sent(count);
+                        count
+                    });
+                }
             }
-            let transaction_id = req.transaction_id;
-            let response = ScrapeResponse { transaction_id, scrapes };
-            self.write_to_socket(response, src)?;
+            let failed = self.transport.send(&self.outgoing)?;
+            metrics.record_send_failures(failed);
+            self.tracker.maintain(Instant::now());
         }
         Ok(())
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn stop_on_shutdown() {
+        let mut server = Server::new("127.0.0.1:0", Settings::default()).unwrap();
+        let shutdown = Shutdown::new();
+        server.set_shutdown(shutdown.clone());
+        let handle = thread::spawn(move || server.run());
+        shutdown.trigger();
+        assert!(handle.join().unwrap().is_ok());
+    }
+}
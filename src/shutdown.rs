@@ -0,0 +1,148 @@
+//! Stopping the servers cleanly.
+//!
+//! A `Shutdown` is shared between the servers and whoever decides when to
+//! stop. Servers check it between batches of packets, so everything they
+//! already received is answered before they return. `on_signals` triggers
+//! it on SIGINT or SIGTERM, which is how systemd asks us to stop.
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::Duration;
+
+
+/// How often blocking loops wake up to check for a shutdown
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+
+#[derive(Debug, Default)]
+struct State {
+    triggered: AtomicBool,
+    lock: Mutex<()>,
+    condvar: Condvar
+}
+
+
+/// A handle for asking servers to stop, which can be cloned and shared
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    state: Arc<State>
+}
+
+impl Shutdown {
+    /// Create a handle that hasn't been triggered yet
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    /// Ask everything sharing this handle to stop
+    pub fn trigger(&self) {
+        let _guard = self.state.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        self.state.triggered.store(true, Ordering::SeqCst);
+        self.state.condvar.notify_all();
+    }
+
+    /// Whether or not we've been asked to stop
+    pub fn is_triggered(&self) -> bool {
+        self.state.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Block the current thread until we're asked to stop
+    pub fn wait(&self) {
+        let mut guard = self.state.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        while !self.is_triggered() {
+            guard = self.state.condvar.wait(guard).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+
+#[cfg(unix)]
+mod signals {
+    use std::io;
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::thread;
+
+    use super::Shutdown;
+
+    /// The end of the pipe the signal handler writes to
+    static WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+    const SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+    /// Only async signal safe calls are allowed here, so we just wake up
+    /// the thread waiting on the other end of the pipe
+    extern "C" fn handle(_signal: libc::c_int) {
+        let byte = 1u8;
+        unsafe {
+            libc::write(WRITE_FD.load(Ordering::SeqCst), &byte as *const u8 as *const libc::c_void, 1);
+        }
+    }
+
+    pub fn install(shutdown: &Shutdown) -> io::Result<()> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let [read_fd, write_fd] = fds;
+        WRITE_FD.store(write_fd, Ordering::SeqCst);
+        for &signal in &SIGNALS {
+            if unsafe { libc::signal(signal, handle as *const () as libc::sighandler_t) } == libc::SIG_ERR {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        let shutdown = shutdown.clone();
+        thread::spawn(move || {
+            let mut byte = 0u8;
+            loop {
+                let read = unsafe { libc::read(read_fd, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+                // Reads interrupted by the signal itself are retried
+                if read >= 0 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                    break;
+                }
+            }
+            // A second signal stops us right away, in case shutting down hangs
+            for &signal in &SIGNALS {
+                unsafe { libc::signal(signal, libc::SIG_DFL) };
+            }
+            shutdown.trigger();
+        });
+        Ok(())
+    }
+}
+
+/// Trigger a shutdown when the process gets SIGINT or SIGTERM.
+/// A second signal kills the process as usual.
+///
+/// This should only be called once per process, and does nothing on
+/// systems without unix signals.
+pub fn on_signals(shutdown: &Shutdown) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        signals::install(shutdown)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = shutdown;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn wait_for_trigger() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.is_triggered());
+        let waiter = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || shutdown.wait())
+        };
+        shutdown.trigger();
+        waiter.join().unwrap();
+        assert!(shutdown.is_triggered());
+    }
+}
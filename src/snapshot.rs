@@ -0,0 +1,465 @@
+//! Saving the swarm to disk, so that it survives restarts.
+//!
+//! A snapshot is a small header, followed by every torrent, and then a
+//! checksum of everything before it. Integers are big endian, like the
+//! rest of the protocol:
+//!
+//! ```text
+//! header:  "BTSNAP" version:u16 saved_at:u64 torrents:u32
+//! torrent: info_hash:[u8; 20] completed:i32 changed:u64 peers:u32
+//! peer:    family:u8 ip:[u8; 4 or 16] port:u16 peer_id:[u8; 20] key:u32
+//!          left:i64 uploaded:i64 downloaded:i64 last_seen:u64 counted:u8
+//! footer:  checksum:u64
+//! ```
+//!
+//! Times are seconds since the unix epoch, since `Instant`s mean nothing
+//! to another process. Version 1 snapshots, which are still read, don't
+//! have the `changed` time, so their torrents count as changed when saved.
+use siphasher::sip::SipHasher24;
+use std::convert::TryInto;
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::swarm::{Peer, SharedSwarm};
+
+
+/// The bytes every snapshot starts with
+const MAGIC: &[u8; 6] = b"BTSNAP";
+
+/// The version of the format we write
+const VERSION: u16 = 2;
+
+/// The oldest version of the format we can still read
+const MIN_VERSION: u16 = 1;
+
+
+/// The errors that can happen while reading a snapshot
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// The file couldn't be read
+    Io(io::Error),
+    /// The file isn't a snapshot at all
+    BadMagic,
+    /// The snapshot was written by a version of the format we don't know
+    UnsupportedVersion(u16),
+    /// The file ends before the snapshot does
+    Truncated,
+    /// The contents don't match the checksum
+    BadChecksum,
+    /// A peer has an address family other than 4 or 6
+    BadFamily(u8),
+    /// There are bytes after the checksum
+    TrailingBytes
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "couldn't read snapshot: {}", e),
+            SnapshotError::BadMagic => f.write_str("not a snapshot file"),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {}", v),
+            SnapshotError::Truncated => f.write_str("snapshot is truncated"),
+            SnapshotError::BadChecksum => f.write_str("snapshot is corrupt: checksum mismatch"),
+            SnapshotError::BadFamily(family) => write!(f, "snapshot is corrupt: unknown address family {}", family),
+            SnapshotError::TrailingBytes => f.write_str("snapshot is corrupt: trailing bytes")
+        }
+    }
+}
+
+impl error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            SnapshotError::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+
+/// A checksum over some bytes, to catch corruption
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hasher = SipHasher24::new();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// Seconds since the unix epoch, which is 0 for times before it
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+
+/// Turns a snapshot into bytes
+struct Encoder {
+    buf: Vec<u8>
+}
+
+impl Encoder {
+    fn u8(&mut self, n: u8) {
+        self.buf.push(n);
+    }
+
+    fn u16(&mut self, n: u16) {
+        self.buf.extend_from_slice(&n.to_be_bytes());
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.buf.extend_from_slice(&n.to_be_bytes());
+    }
+
+    fn u64(&mut self, n: u64) {
+        self.buf.extend_from_slice(&n.to_be_bytes());
+    }
+
+    fn i64(&mut self, n: i64) {
+        self.buf.extend_from_slice(&n.to_be_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+}
+
+
+/// A peer as it was saved, before we know if it's still fresh
+struct SavedPeer {
+    addr: SocketAddr,
+    peer_id: [u8; 20],
+    key: u32,
+    left: i64,
+    uploaded: i64,
+    downloaded: i64,
+    last_seen: u64,
+    counted_completion: bool
+}
+
+
+/// Reads a snapshot back, failing if the bytes run out
+struct Decoder<'a> {
+    bytes: &'a [u8]
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.bytes.len() < n {
+            return Err(SnapshotError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        let mut out = [0; N];
+        out.copy_from_slice(self.take(N)?);
+        Ok(out)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    fn i64(&mut self) -> Result<i64, SnapshotError> {
+        Ok(i64::from_be_bytes(self.array()?))
+    }
+
+    fn peer(&mut self) -> Result<SavedPeer, SnapshotError> {
+        let ip = match self.u8()? {
+            4 => IpAddr::V4(Ipv4Addr::from(self.array::<4>()?)),
+            6 => IpAddr::V6(Ipv6Addr::from(self.array::<16>()?)),
+            family => return Err(SnapshotError::BadFamily(family))
+        };
+        Ok(SavedPeer {
+            addr: SocketAddr::new(ip, self.u16()?),
+            peer_id: self.array()?,
+            key: self.u32()?,
+            left: self.i64()?,
+            uploaded: self.i64()?,
+            downloaded: self.i64()?,
+            last_seen: self.u64()?,
+            counted_completion: self.u8()? != 0
+        })
+    }
+
+    /// Walk over a snapshot body without keeping anything,
+    /// to tell a truncated file apart from a corrupted one
+    fn skim(mut self, version: u16) -> Result<(), SnapshotError> {
+        self.u64()?;
+        for _ in 0..self.u32()? {
+            self.take(if version >= 2 { 32 } else { 24 })?;
+            for _ in 0..self.u32()? {
+                self.peer()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// Write a snapshot of a swarm to bytes.
+///
+/// `now` and `wall_now` should be taken at the same time, so that
+/// we can turn the peers' `Instant`s into wall clock times.
+/// Shards are locked one at a time, so the snapshot might not capture
+/// every shard at the exact same moment.
+pub fn encode(swarm: &SharedSwarm, now: Instant, wall_now: SystemTime) -> Vec<u8> {
+    let saved_at = unix_secs(wall_now);
+    let mut enc = Encoder { buf: Vec::new() };
+    enc.bytes(MAGIC);
+    enc.u16(VERSION);
+    enc.u64(saved_at);
+    // We only know how many torrents there are once we've seen every shard
+    let count_at = enc.buf.len();
+    enc.u32(0);
+    let mut torrent_count = 0u32;
+    swarm.for_each_shard(|shard| for (info_hash, info) in shard.torrents() {
+        torrent_count += 1;
+        enc.bytes(info_hash);
+        enc.u32(info.scrape_info().completed as u32);
+        enc.u64(saved_at.saturating_sub(now.saturating_duration_since(info.changed()).as_secs()));
+        let peers: Vec<_> = info.peers().collect();
+        enc.u32(peers.len() as u32);
+        for (addr, peer) in peers {
+            match addr.ip() {
+                IpAddr::V4(ip) => {
+                    enc.u8(4);
+                    enc.bytes(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    enc.u8(6);
+                    enc.bytes(&ip.octets());
+                }
+            }
+            enc.u16(addr.port());
+            enc.bytes(peer.peer_id());
+            enc.u32(peer.key());
+            enc.i64(peer.left());
+            enc.i64(peer.uploaded());
+            enc.i64(peer.downloaded());
+            let age = now.saturating_duration_since(peer.last_announce()).as_secs();
+            enc.u64(saved_at.saturating_sub(age));
+            enc.u8(peer.counted_completion() as u8);
+        }
+    });
+    enc.buf[count_at..count_at + 4].copy_from_slice(&torrent_count.to_be_bytes());
+    let sum = checksum(&enc.buf);
+    enc.u64(sum);
+    enc.buf
+}
+
+/// Read a snapshot back into `swarm`.
+///
+/// Peers that haven't been seen for longer than `peer_timeout`, counting
+/// the time we were down, are left out, along with any torrents that
+/// no longer have anything worth keeping.
+pub fn decode(bytes: &[u8], swarm: &SharedSwarm, peer_timeout: Duration, now: Instant, wall_now: SystemTime) -> Result<(), SnapshotError> {
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    let mut dec = Decoder { bytes: &bytes[MAGIC.len()..] };
+    let version = dec.u16()?;
+    if !(MIN_VERSION..=VERSION).contains(&version) {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    let header = MAGIC.len() + 2;
+    if bytes.len() < header + 8 {
+        return Err(SnapshotError::Truncated);
+    }
+    let (body, sum) = bytes.split_at(bytes.len() - 8);
+    if checksum(body) != u64::from_be_bytes(sum.try_into().unwrap()) {
+        // A file that was cut short fails the checksum too, so we look closer
+        let skimmed = Decoder { bytes: &body[header..] }.skim(version);
+        return Err(skimmed.err().unwrap_or(SnapshotError::BadChecksum));
+    }
+    dec.bytes = &body[header..];
+    let saved_at = dec.u64()?;
+    let wall_secs = unix_secs(wall_now);
+    // If the clock can't go back that far, the time is as recent as it gets
+    let instant = |secs: u64| now.checked_sub(Duration::from_secs(wall_secs.saturating_sub(secs))).unwrap_or(now);
+    let torrent_count = dec.u32()?;
+    for _ in 0..torrent_count {
+        let info_hash = dec.array()?;
+        let completed = dec.u32()? as i32;
+        let changed = instant(if version >= 2 { dec.u64()? } else { saved_at });
+        let peer_count = dec.u32()?;
+        let mut peers = Vec::new();
+        for _ in 0..peer_count {
+            let peer = dec.peer()?;
+            let age = Duration::from_secs(wall_secs.saturating_sub(peer.last_seen));
+            if age > peer_timeout {
+                continue;
+            }
+            let last_announce = instant(peer.last_seen);
+            peers.push((peer.addr, Peer::restore(
+                peer.peer_id, peer.key, peer.left, peer.uploaded, peer.downloaded,
+                last_announce, peer.counted_completion
+            )));
+        }
+        if !peers.is_empty() || completed != 0 {
+            swarm.lock(&info_hash).restore(info_hash, completed, changed, peers);
+        }
+    }
+    if !dec.bytes.is_empty() {
+        return Err(SnapshotError::TrailingBytes);
+    }
+    Ok(())
+}
+
+
+/// Save a snapshot of a shared swarm to a file.
+///
+/// The snapshot is written next to the file first, and then moved
+/// over it, so a crash halfway through never leaves a broken file behind.
+pub fn save(swarm: &SharedSwarm, path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    // Only hold the locks while encoding, not while writing
+    let bytes = encode(swarm, Instant::now(), SystemTime::now());
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Load a snapshot from a file into a swarm.
+/// See `decode` for which peers are kept.
+pub fn load(swarm: &SharedSwarm, path: impl AsRef<Path>, peer_timeout: Duration) -> Result<(), SnapshotError> {
+    let bytes = fs::read(path)?;
+    decode(&bytes, swarm, peer_timeout, Instant::now(), SystemTime::now())
+}
+
+/// Save a snapshot every `interval`, blocking the current thread forever.
+/// Failures are reported on stderr, and we try again next time.
+pub fn save_every(swarm: SharedSwarm, path: impl AsRef<Path>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        if let Err(e) = save(&swarm, path.as_ref()) {
+            eprintln!("bittrickle: couldn't save snapshot to {}: {}", path.as_ref().display(), e);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::AnnounceEvent;
+    use crate::swarm::{PeerAnnounce, Swarm};
+
+    fn announce(left: i64, event: AnnounceEvent) -> PeerAnnounce {
+        PeerAnnounce {
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            key: 3,
+            downloaded: 4,
+            left,
+            uploaded: 5,
+            event
+        }
+    }
+
+    fn swarm(now: Instant) -> SharedSwarm {
+        let mut swarm = Swarm::new();
+        let old = now - Duration::from_secs(100);
+        swarm.announce("1.1.1.1:1".parse().unwrap(), &announce(10, AnnounceEvent::Started), old).unwrap();
+        swarm.announce("[::1]:2".parse().unwrap(), &announce(10, AnnounceEvent::Started), now).unwrap();
+        swarm.announce("[::1]:2".parse().unwrap(), &announce(0, AnnounceEvent::Completed), now).unwrap();
+        SharedSwarm::new(swarm)
+    }
+
+    #[test]
+    fn round_trip() {
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        let bytes = encode(&swarm(now), now, wall_now);
+        // The shards don't have to match the ones we saved from
+        let restored = SharedSwarm::sharded(3, Swarm::new);
+        decode(&bytes, &restored, Duration::from_secs(1000), now, wall_now).unwrap();
+        let info = restored.scrape(&[1; 20]);
+        assert_eq!((info.seeders, info.leechers, info.completed), (1, 1, 1));
+        let shard = restored.lock(&[1; 20]);
+        let torrent = shard.torrent(&[1; 20]).unwrap();
+        let (_, peer) = torrent.peers().find(|(addr, _)| addr.is_ipv6()).unwrap();
+        assert_eq!((peer.key(), peer.uploaded(), peer.downloaded()), (3, 5, 4));
+        assert!(peer.counted_completion());
+    }
+
+    #[test]
+    fn drop_expired_peers() {
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        let bytes = encode(&swarm(now), now, wall_now);
+        let restored = SharedSwarm::default();
+        // We were down for a minute, which is too long for the older peer
+        let later = wall_now + Duration::from_secs(60);
+        decode(&bytes, &restored, Duration::from_secs(120), now, later).unwrap();
+        let info = restored.scrape(&[1; 20]);
+        assert_eq!((info.seeders, info.leechers, info.completed), (1, 0, 1));
+    }
+
+    #[test]
+    fn keep_changed_times() {
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        let bytes = encode(&swarm(now), now, wall_now);
+        let restored = SharedSwarm::default();
+        let later = now + Duration::from_secs(30);
+        decode(&bytes, &restored, Duration::from_secs(1000), later, wall_now + Duration::from_secs(30)).unwrap();
+        let changed = restored.lock(&[1; 20]).torrent(&[1; 20]).unwrap().changed();
+        assert_eq!(later.duration_since(changed).as_secs(), 30);
+        // Version 1 has no changed time, so we take the time it was saved
+        let mut old = bytes[..44].to_vec();
+        old[7] = 1;
+        old.extend_from_slice(&bytes[52..bytes.len() - 8]);
+        let sum = checksum(&old);
+        old.extend_from_slice(&sum.to_be_bytes());
+        let restored = SharedSwarm::default();
+        decode(&old, &restored, Duration::from_secs(1000), later, wall_now + Duration::from_secs(60)).unwrap();
+        assert_eq!(restored.scrape(&[1; 20]).completed, 1);
+        let changed = restored.lock(&[1; 20]).torrent(&[1; 20]).unwrap().changed();
+        assert_eq!(later.duration_since(changed).as_secs(), 60);
+    }
+
+    #[test]
+    fn reject_bad_snapshots() {
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        let bytes = encode(&swarm(now), now, wall_now);
+        let timeout = Duration::from_secs(1000);
+        let decode = |bytes: &[u8]| decode(bytes, &SharedSwarm::default(), timeout, now, wall_now);
+        assert!(matches!(decode(b"hello"), Err(SnapshotError::BadMagic)));
+        assert!(matches!(decode(&bytes[..bytes.len() - 20]), Err(SnapshotError::Truncated)));
+        let mut corrupt = bytes.clone();
+        corrupt[30] ^= 1;
+        assert!(matches!(decode(&corrupt), Err(SnapshotError::BadChecksum)));
+        let mut future = bytes.clone();
+        future[7] = 3;
+        assert!(matches!(decode(&future), Err(SnapshotError::UnsupportedVersion(3))));
+    }
+}
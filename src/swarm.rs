@@ -0,0 +1,734 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+use crate::access::{AccessList, AccessMode, SharedAccess};
+use crate::protocol::{AnnounceEvent, AnnounceRequest, InfoHash, PeerId, ScrapeInfo};
+use crate::selector::{Candidate, PeerSelector};
+
+
+/// Turn IPv4-mapped IPv6 addresses back into plain IPv4 addresses.
+/// A dual-stack socket reports IPv4 clients this way.
+pub fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(sock) => match sock.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::V4(SocketAddrV4::new(ip, sock.port())),
+            None => addr
+        },
+        SocketAddr::V4(_) => addr
+    }
+}
+
+
+
+/// The reasons a swarm can refuse an announce
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnnounceError {
+    /// We're already tracking as many torrents as we're allowed to
+    TooManyTorrents,
+    /// This torrent already has as many peers as we're allowed to track
+    TooManyPeers,
+    /// We only track whitelisted torrents, and this isn't one of them
+    NotRegistered,
+    /// This torrent is blacklisted
+    Blacklisted
+}
+
+impl fmt::Display for AnnounceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            AnnounceError::TooManyTorrents => "tracker is full",
+            AnnounceError::TooManyPeers => "torrent is full",
+            AnnounceError::NotRegistered => "torrent not registered",
+            AnnounceError::Blacklisted => "torrent is blacklisted"
+        };
+        f.write_str(message)
+    }
+}
+
+impl error::Error for AnnounceError {}
+
+
+/// What a peer tells us when it announces, whatever the transport
+#[derive(Clone, Debug)]
+pub struct PeerAnnounce {
+    /// The torrent the peer is announcing
+    pub info_hash: InfoHash,
+    /// The ID the peer wishes to use
+    pub peer_id: PeerId,
+    /// A key to help identify the peer
+    pub key: u32,
+    /// How many bytes the client has downloaded
+    pub downloaded: i64,
+    /// How many bytes the client has left to download
+    pub left: i64,
+    /// How many bytes the client has uploaded this session
+    pub uploaded: i64,
+    /// The event the client is reporting
+    pub event: AnnounceEvent
+}
+
+impl<'a> From<&'a AnnounceRequest> for PeerAnnounce {
+    fn from(req: &'a AnnounceRequest) -> Self {
+        PeerAnnounce {
+            info_hash: req.info_hash,
+            peer_id: req.peer_id,
+            key: req.key,
+            downloaded: req.downloaded,
+            left: req.left,
+            uploaded: req.uploaded,
+            event: req.event
+        }
+    }
+}
+
+
+/// The state we keep about an individual peer in a swarm
+#[derive(Clone, Debug)]
+pub struct Peer {
+    /// The id the client announced itself with
+    peer_id: PeerId,
+    /// The key the client uses to identify itself
+    key: u32,
+    /// How many bytes the peer still needs to download
+    left: i64,
+    /// How many bytes the peer has uploaded this session
+    uploaded: i64,
+    /// How many bytes the peer has downloaded this session
+    downloaded: i64,
+    /// The last time this peer announced itself to us
+    last_announce: Instant,
+    /// Whether or not we've already counted this peer as completing the torrent
+    counted_completion: bool
+}
+
+impl Peer {
+    fn from_announce(announce: &PeerAnnounce, now: Instant) -> Self {
+        Peer {
+            peer_id: announce.peer_id,
+            key: announce.key,
+            left: announce.left,
+            uploaded: announce.uploaded,
+            downloaded: announce.downloaded,
+            last_announce: now,
+            counted_completion: false
+        }
+    }
+
+    /// Rebuild a peer from saved state
+    pub(crate) fn restore(peer_id: PeerId, key: u32, left: i64, uploaded: i64, downloaded: i64, last_announce: Instant, counted_completion: bool) -> Self {
+        Peer { peer_id, key, left, uploaded, downloaded, last_announce, counted_completion }
+    }
+
+    /// The id this peer announced itself with
+    pub fn peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    /// The key this peer identifies itself with
+    pub fn key(&self) -> u32 {
+        self.key
+    }
+
+    /// How many bytes this peer still needs to download
+    pub fn left(&self) -> i64 {
+        self.left
+    }
+
+    /// How many bytes this peer has uploaded this session
+    pub fn uploaded(&self) -> i64 {
+        self.uploaded
+    }
+
+    /// How many bytes this peer has downloaded this session
+    pub fn downloaded(&self) -> i64 {
+        self.downloaded
+    }
+
+    /// Whether or not this peer has already been counted as completing the torrent
+    pub(crate) fn counted_completion(&self) -> bool {
+        self.counted_completion
+    }
+
+    /// The last time this peer announced itself to us
+    pub fn last_announce(&self) -> Instant {
+        self.last_announce
+    }
+
+    /// A peer with nothing left to download is a seeder
+    pub fn is_seeder(&self) -> bool {
+        self.left == 0
+    }
+
+    /// Check if an announce comes from the same client session as this peer.
+    /// A different client reusing the same address starts from scratch.
+    fn same_session(&self, announce: &PeerAnnounce) -> bool {
+        self.peer_id == announce.peer_id && self.key == announce.key
+    }
+
+    /// Update this peer with the latest announce, returning true
+    /// if this announce means the peer has just completed the torrent
+    fn update(&mut self, announce: &PeerAnnounce, now: Instant) -> bool {
+        if !self.same_session(announce) {
+            *self = Peer::from_announce(announce, now);
+        }
+        self.left = announce.left;
+        self.uploaded = announce.uploaded;
+        self.downloaded = announce.downloaded;
+        self.last_announce = now;
+        let completed = announce.event == AnnounceEvent::Completed && self.is_seeder();
+        if completed && !self.counted_completion {
+            self.counted_completion = true;
+            return true;
+        }
+        false
+    }
+}
+
+
+/// Represents the information associated with the torrent
+#[derive(Clone, Debug)]
+pub struct TorrentInfo {
+    leechers: i32,
+    completed: i32,
+    seeders: i32,
+    peers_v4: HashMap<SocketAddrV4, Peer>,
+    peers_v6: HashMap<SocketAddrV6, Peer>,
+    /// The last time any of the counts changed
+    changed: Instant
+}
+
+impl TorrentInfo {
+    /// Create a torrent without any peers
+    fn new(now: Instant) -> Self {
+        TorrentInfo {
+            leechers: 0, completed: 0, seeders: 0,
+            peers_v4: HashMap::new(), peers_v6: HashMap::new(),
+            changed: now
+        }
+    }
+
+    /// Insert or update a peer, returning true if it just completed the torrent
+    fn upsert(&mut self, sock: SocketAddr, announce: &PeerAnnounce, now: Instant) -> bool {
+        let fresh = || Peer::from_announce(announce, now);
+        let peer = match sock {
+            SocketAddr::V4(sock) => self.peers_v4.entry(sock).or_insert_with(fresh),
+            SocketAddr::V6(sock) => self.peers_v6.entry(sock).or_insert_with(fresh)
+        };
+        peer.update(announce, now)
+    }
+
+    /// Remove a peer from the swarm, returning false if it wasn't there
+    fn remove(&mut self, sock: SocketAddr) -> bool {
+        match sock {
+            SocketAddr::V4(sock) => self.peers_v4.remove(&sock).is_some(),
+            SocketAddr::V6(sock) => self.peers_v6.remove(&sock).is_some()
+        }
+    }
+
+    /// Recalculate the seeder and leecher counts from the live peers,
+    /// noting the time if they changed
+    fn recount(&mut self, now: Instant) {
+        let before = (self.seeders, self.leechers);
+        let all = self.peers_v4.values().chain(self.peers_v6.values());
+        let seeders = all.filter(|p| p.is_seeder()).count();
+        let total = self.peers_v4.len() + self.peers_v6.len();
+        self.seeders = seeders as i32;
+        self.leechers = (total - seeders) as i32;
+        if (self.seeders, self.leechers) != before {
+            self.changed = now;
+        }
+    }
+
+    /// Whether or not we're tracking a peer at some address
+    fn contains(&self, sock: SocketAddr) -> bool {
+        match sock {
+            SocketAddr::V4(sock) => self.peers_v4.contains_key(&sock),
+            SocketAddr::V6(sock) => self.peers_v6.contains_key(&sock)
+        }
+    }
+
+    /// Update the swarm with a peer's announce.
+    /// New peers are refused once the torrent has `max_peers` peers.
+    fn handle_peer(&mut self, peer: SocketAddr, announce: &PeerAnnounce, now: Instant, max_peers: usize) -> Result<(), AnnounceError> {
+        if announce.event == AnnounceEvent::Stopped {
+            self.remove(peer);
+        } else {
+            let total = self.peers_v4.len() + self.peers_v6.len();
+            if total >= max_peers && !self.contains(peer) {
+                return Err(AnnounceError::TooManyPeers);
+            }
+            if self.upsert(peer, announce, now) {
+                self.completed += 1;
+                self.changed = now;
+            }
+        }
+        self.recount(now);
+        Ok(())
+    }
+
+    /// Remove every peer that hasn't announced since `cutoff`
+    fn evict_before(&mut self, cutoff: Instant) {
+        self.peers_v4.retain(|_, p| p.last_announce >= cutoff);
+        self.peers_v6.retain(|_, p| p.last_announce >= cutoff);
+        // The cutoff is well in the past, and the counts change as we sweep
+        self.recount(Instant::now());
+    }
+
+    /// Whether or not this torrent can be forgotten entirely
+    fn is_empty(&self) -> bool {
+        self.peers_v4.is_empty() && self.peers_v6.is_empty() && self.completed == 0
+    }
+
+    /// Every peer in this torrent, with its address
+    pub fn peers(&self) -> impl Iterator<Item = (SocketAddr, &Peer)> {
+        let v4 = self.peers_v4.iter().map(|(addr, peer)| (SocketAddr::V4(*addr), peer));
+        let v6 = self.peers_v6.iter().map(|(addr, peer)| (SocketAddr::V6(*addr), peer));
+        v4.chain(v6)
+    }
+
+    /// The last time the counts we report for this torrent changed
+    pub fn changed(&self) -> Instant {
+        self.changed
+    }
+
+    /// The counts we report for this torrent
+    pub fn scrape_info(&self) -> ScrapeInfo {
+        ScrapeInfo {
+            seeders: self.seeders,
+            completed: self.completed,
+            leechers: self.leechers
+        }
+    }
+
+    /// Look up the peer at some address
+    fn peer(&self, sock: SocketAddr) -> Option<&Peer> {
+        match sock {
+            SocketAddr::V4(sock) => self.peers_v4.get(&sock),
+            SocketAddr::V6(sock) => self.peers_v6.get(&sock)
+        }
+    }
+
+    /// Pick up to `amount` peers to send back to `requester`, with the same address family.
+    ///
+    /// The requester never gets itself back. Seeders only get leechers, and
+    /// leechers get `seeder_share` percent seeders, when there are enough.
+    pub fn select_peers<'a>(&'a self, selector: &mut dyn PeerSelector, requester: SocketAddr, amount: usize, seeder_share: u8) -> Vec<Candidate<'a>> {
+        let requester = canonical_addr(requester);
+        let candidates: Vec<Candidate> = match requester {
+            SocketAddr::V4(_) => self.peers_v4.iter()
+                .map(|(addr, peer)| Candidate { addr: SocketAddr::V4(*addr), peer })
+                .collect(),
+            SocketAddr::V6(_) => self.peers_v6.iter()
+                .map(|(addr, peer)| Candidate { addr: SocketAddr::V6(*addr), peer })
+                .collect()
+        };
+        let (seeders, leechers): (Vec<_>, Vec<_>) = candidates.into_iter()
+            .filter(|c| c.addr != requester)
+            .partition(|c| c.peer.is_seeder());
+        if self.peer(requester).is_some_and(Peer::is_seeder) {
+            return selector.select(requester, leechers, amount);
+        }
+        // Take our share of seeders, then top up from whichever side has more
+        let wanted_seeders = (amount * usize::from(seeder_share)).div_ceil(100);
+        let leecher_count = (amount - wanted_seeders.min(seeders.len())).min(leechers.len());
+        let seeder_count = (amount - leecher_count).min(seeders.len());
+        let mut peers = selector.select(requester, seeders, seeder_count);
+        peers.extend(selector.select(requester, leechers, leecher_count));
+        peers
+    }
+}
+
+
+/// The table of every torrent we're tracking.
+///
+/// This is shared by every frontend, so that UDP and HTTP clients
+/// end up in the same swarms.
+#[derive(Debug)]
+pub struct Swarm {
+    torrents: HashMap<InfoHash, TorrentInfo>,
+    access: SharedAccess,
+    max_torrents: usize,
+    max_peers: usize
+}
+
+/// A swarm that can be shared between threads.
+///
+/// The torrents are split into shards by info hash, each with its own lock,
+/// so that threads working on different torrents don't wait on each other.
+/// With a single shard, this is just a swarm behind a lock.
+/// Every shard shares the same access list.
+#[derive(Debug, Clone)]
+pub struct SharedSwarm {
+    shards: Arc<Vec<Mutex<Swarm>>>,
+    access: SharedAccess
+}
+
+impl SharedSwarm {
+    /// Share a single swarm, without splitting it up
+    pub fn new(swarm: Swarm) -> Self {
+        SharedSwarm::from_shards(vec![swarm])
+    }
+
+    /// Create a swarm split into `count` shards, made by `make`.
+    /// The shards all use the access list of the first one.
+    pub fn sharded(count: usize, make: impl FnMut() -> Swarm) -> Self {
+        SharedSwarm::from_shards(std::iter::repeat_with(make).take(count.max(1)).collect())
+    }
+
+    fn from_shards(mut shards: Vec<Swarm>) -> Self {
+        let access = shards[0].access.clone();
+        for shard in &mut shards[1..] {
+            shard.access = access.clone();
+        }
+        SharedSwarm { shards: Arc::new(shards.into_iter().map(Mutex::new).collect()), access }
+    }
+
+    /// How many shards the torrents are split into
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// The shard a torrent lives in.
+    /// Info hashes are already uniformly distributed, so we use them directly.
+    pub fn shard_of(&self, info_hash: &InfoHash) -> usize {
+        let mut prefix = [0; 8];
+        prefix.copy_from_slice(&info_hash[..8]);
+        (u64::from_be_bytes(prefix) % self.shards.len() as u64) as usize
+    }
+
+    /// Lock a single shard.
+    ///
+    /// The swarm is always left consistent between calls, so we keep
+    /// going even if another thread panicked while holding the lock.
+    pub fn lock_shard(&self, index: usize) -> MutexGuard<'_, Swarm> {
+        self.shards[index].lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock the shard a torrent lives in
+    pub fn lock(&self, info_hash: &InfoHash) -> MutexGuard<'_, Swarm> {
+        self.lock_shard(self.shard_of(info_hash))
+    }
+
+    /// Run something on every shard in turn, only holding one lock at a time
+    pub fn for_each_shard(&self, mut f: impl FnMut(&mut Swarm)) {
+        for index in 0..self.shards.len() {
+            f(&mut self.lock_shard(index));
+        }
+    }
+
+    /// Get the counts for a torrent, which are empty for unknown torrents
+    pub fn scrape(&self, info_hash: &InfoHash) -> ScrapeInfo {
+        self.lock(info_hash).scrape(info_hash)
+    }
+
+    /// The access list every shard uses
+    pub fn access(&self) -> &SharedAccess {
+        &self.access
+    }
+
+    /// Replace the access list every shard uses
+    pub fn set_access(&self, access: AccessList) {
+        self.access.set(access);
+    }
+}
+
+impl Default for SharedSwarm {
+    fn default() -> Self {
+        SharedSwarm::new(Swarm::new())
+    }
+}
+
+
+impl Default for Swarm {
+    fn default() -> Self {
+        Swarm::new()
+    }
+}
+
+impl Swarm {
+    /// Create a new swarm, without any torrents or limits
+    pub fn new() -> Self {
+        Swarm::with_limits(None, None)
+    }
+
+    /// Create a new swarm, tracking at most `max_torrents` torrents,
+    /// and at most `max_peers` peers in each of them
+    pub fn with_limits(max_torrents: Option<usize>, max_peers: Option<usize>) -> Self {
+        Swarm {
+            torrents: HashMap::new(),
+            access: SharedAccess::default(),
+            max_torrents: max_torrents.unwrap_or(usize::MAX),
+            max_peers: max_peers.unwrap_or(usize::MAX)
+        }
+    }
+
+    /// The list deciding which torrents we accept announces for,
+    /// which can be shared with other swarms
+    pub fn access(&self) -> &SharedAccess {
+        &self.access
+    }
+
+    /// Replace the list deciding which torrents we accept announces for.
+    /// Torrents that are no longer allowed are kept until their peers go silent.
+    pub fn set_access(&mut self, access: AccessList) {
+        self.access.set(access);
+    }
+
+    /// Handle a peer's announce, returning the updated torrent
+    pub fn announce(&mut self, peer: SocketAddr, announce: &PeerAnnounce, now: Instant) -> Result<&TorrentInfo, AnnounceError> {
+        {
+            let access = self.access.read();
+            if !access.allows(&announce.info_hash) {
+                return match access.mode() {
+                    AccessMode::Blacklist => Err(AnnounceError::Blacklisted),
+                    _ => Err(AnnounceError::NotRegistered)
+                };
+            }
+        }
+        let full = self.torrents.len() >= self.max_torrents;
+        if full && !self.torrents.contains_key(&announce.info_hash) {
+            return Err(AnnounceError::TooManyTorrents);
+        }
+        let info = self.torrents.entry(announce.info_hash).or_insert_with(|| TorrentInfo::new(now));
+        info.handle_peer(canonical_addr(peer), announce, now, self.max_peers)?;
+        Ok(info)
+    }
+
+    /// Every torrent we're tracking
+    pub fn torrents(&self) -> impl Iterator<Item = (&InfoHash, &TorrentInfo)> {
+        self.torrents.iter()
+    }
+
+    /// Get a single torrent, if we're tracking it
+    pub fn torrent(&self, info_hash: &InfoHash) -> Option<&TorrentInfo> {
+        self.torrents.get(info_hash)
+    }
+
+    /// Stop tracking a torrent, along with all of its peers.
+    /// Returns false if we weren't tracking it.
+    ///
+    /// Nothing stops the torrent coming back with its peers' next announces,
+    /// unless the access list keeps it out.
+    pub fn remove_torrent(&mut self, info_hash: &InfoHash) -> bool {
+        self.torrents.remove(info_hash).is_some()
+    }
+
+    /// Remove a single peer from a torrent, returning false if it wasn't there
+    pub fn remove_peer(&mut self, info_hash: &InfoHash, peer: SocketAddr) -> bool {
+        let info = match self.torrents.get_mut(info_hash) {
+            Some(info) => info,
+            None => return false
+        };
+        let removed = info.remove(canonical_addr(peer));
+        info.recount(Instant::now());
+        removed
+    }
+
+    /// Put back a torrent from saved state, replacing what we had.
+    /// Limits aren't checked, since the torrent was already allowed in.
+    pub(crate) fn restore(&mut self, info_hash: InfoHash, completed: i32, changed: Instant, peers: Vec<(SocketAddr, Peer)>) {
+        let mut info = TorrentInfo::new(changed);
+        info.completed = completed;
+        for (addr, peer) in peers {
+            match canonical_addr(addr) {
+                SocketAddr::V4(addr) => info.peers_v4.insert(addr, peer),
+                SocketAddr::V6(addr) => info.peers_v6.insert(addr, peer)
+            };
+        }
+        info.recount(changed);
+        self.torrents.insert(info_hash, info);
+    }
+
+    /// Get the counts for a torrent, which are empty for unknown torrents
+    pub fn scrape(&self, info_hash: &InfoHash) -> ScrapeInfo {
+        match self.torrents.get(info_hash) {
+            Some(info) => info.scrape_info(),
+            None => ScrapeInfo::empty()
+        }
+    }
+
+    /// Remove all the peers that haven't announced since `cutoff`,
+    /// along with any torrents that are no longer worth keeping
+    pub fn sweep(&mut self, cutoff: Instant) {
+        self.torrents.retain(|_, info| {
+            info.evict_before(cutoff);
+            !info.is_empty()
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::selector::RandomSelector;
+
+    fn announce(peer_id: u8, left: i64, event: AnnounceEvent) -> PeerAnnounce {
+        PeerAnnounce {
+            info_hash: [1; 20],
+            peer_id: [peer_id; 20],
+            key: 0,
+            downloaded: 0,
+            left,
+            uploaded: 0,
+            event
+        }
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn seeders_come_from_left() {
+        let mut swarm = Swarm::new();
+        let now = Instant::now();
+        swarm.announce(addr("1.1.1.1:1"), &announce(1, 10, AnnounceEvent::Started), now).unwrap();
+        swarm.announce(addr("[::2]:2"), &announce(2, 0, AnnounceEvent::Started), now).unwrap();
+        let info = swarm.scrape(&[1; 20]);
+        assert_eq!((info.seeders, info.leechers, info.completed), (1, 1, 0));
+    }
+
+    #[test]
+    fn completed_counts_once() {
+        let mut swarm = Swarm::new();
+        let now = Instant::now();
+        let peer = addr("1.1.1.1:1");
+        swarm.announce(peer, &announce(1, 10, AnnounceEvent::Started), now).unwrap();
+        swarm.announce(peer, &announce(1, 0, AnnounceEvent::Completed), now).unwrap();
+        swarm.announce(peer, &announce(1, 0, AnnounceEvent::Completed), now).unwrap();
+        let info = swarm.scrape(&[1; 20]);
+        assert_eq!((info.seeders, info.leechers, info.completed), (1, 0, 1));
+    }
+
+    #[test]
+    fn stopped_removes_peer() {
+        let mut swarm = Swarm::new();
+        let now = Instant::now();
+        let peer = addr("1.1.1.1:1");
+        swarm.announce(peer, &announce(1, 10, AnnounceEvent::Stopped), now).unwrap();
+        swarm.announce(peer, &announce(1, 10, AnnounceEvent::Started), now).unwrap();
+        swarm.announce(peer, &announce(1, 10, AnnounceEvent::Stopped), now).unwrap();
+        let info = swarm.scrape(&[1; 20]);
+        assert_eq!((info.seeders, info.leechers, info.completed), (0, 0, 0));
+    }
+
+    #[test]
+    fn remove_peers_and_torrents() {
+        let mut swarm = Swarm::new();
+        let now = Instant::now();
+        swarm.announce(addr("1.1.1.1:1"), &announce(1, 0, AnnounceEvent::Started), now).unwrap();
+        swarm.announce(addr("2.2.2.2:2"), &announce(2, 10, AnnounceEvent::Started), now).unwrap();
+        assert!(swarm.remove_peer(&[1; 20], addr("[::ffff:1.1.1.1]:1")));
+        assert!(!swarm.remove_peer(&[1; 20], addr("1.1.1.1:1")));
+        assert!(!swarm.remove_peer(&[2; 20], addr("2.2.2.2:2")));
+        let info = swarm.scrape(&[1; 20]);
+        assert_eq!((info.seeders, info.leechers), (0, 1));
+        assert!(swarm.remove_torrent(&[1; 20]));
+        assert!(!swarm.remove_torrent(&[1; 20]));
+        assert!(swarm.torrents.is_empty());
+    }
+
+    #[test]
+    fn limits_refuse_newcomers() {
+        let mut swarm = Swarm::with_limits(Some(1), Some(1));
+        let now = Instant::now();
+        let peer = addr("1.1.1.1:1");
+        swarm.announce(peer, &announce(1, 10, AnnounceEvent::Started), now).unwrap();
+        swarm.announce(peer, &announce(1, 0, AnnounceEvent::Nothing), now).unwrap();
+        let result = swarm.announce(addr("2.2.2.2:2"), &announce(2, 10, AnnounceEvent::Started), now);
+        assert_eq!(result.err(), Some(AnnounceError::TooManyPeers));
+        let mut other = announce(1, 10, AnnounceEvent::Started);
+        other.info_hash = [2; 20];
+        assert_eq!(swarm.announce(peer, &other, now).err(), Some(AnnounceError::TooManyTorrents));
+    }
+
+    #[test]
+    fn access_list_denies() {
+        let mut swarm = Swarm::new();
+        let now = Instant::now();
+        let peer = addr("1.1.1.1:1");
+        swarm.set_access(AccessList::new(AccessMode::Whitelist, Default::default()));
+        let result = swarm.announce(peer, &announce(1, 10, AnnounceEvent::Started), now);
+        assert_eq!(result.err(), Some(AnnounceError::NotRegistered));
+        swarm.access().write().insert([1; 20]);
+        assert!(swarm.announce(peer, &announce(1, 10, AnnounceEvent::Started), now).is_ok());
+    }
+
+    #[test]
+    fn shards_share_access() {
+        let swarm = SharedSwarm::sharded(4, Swarm::new);
+        swarm.set_access(AccessList::new(AccessMode::Blacklist, Default::default()));
+        swarm.lock_shard(3).access().write().insert([1; 20]);
+        swarm.for_each_shard(|shard| {
+            assert_eq!(shard.access().read().mode(), AccessMode::Blacklist);
+            assert!(!shard.access().read().allows(&[1; 20]));
+        });
+    }
+
+    #[test]
+    fn select_useful_peers() {
+        let mut swarm = Swarm::new();
+        let now = Instant::now();
+        for i in 1..=4 {
+            let peer = addr(&format!("1.1.1.{}:1", i));
+            swarm.announce(peer, &announce(i, 0, AnnounceEvent::Started), now).unwrap();
+        }
+        for i in 5..=8 {
+            let peer = addr(&format!("1.1.1.{}:1", i));
+            swarm.announce(peer, &announce(i, 10, AnnounceEvent::Started), now).unwrap();
+        }
+        let info = &swarm.torrents[&[1; 20]];
+        let mut selector = RandomSelector::new();
+        let seeders = |peers: &[Candidate]| peers.iter().filter(|c| c.peer.is_seeder()).count();
+
+        let seeder = addr("1.1.1.1:1");
+        let peers = info.select_peers(&mut selector, seeder, 10, 75);
+        assert_eq!((peers.len(), seeders(&peers)), (4, 0));
+
+        let leecher = addr("1.1.1.5:1");
+        let peers = info.select_peers(&mut selector, leecher, 4, 75);
+        assert_eq!((peers.len(), seeders(&peers)), (4, 3));
+        assert!(peers.iter().all(|c| c.addr != leecher));
+        // Without enough seeders, leechers make up the difference
+        let peers = info.select_peers(&mut selector, leecher, 10, 75);
+        assert_eq!((peers.len(), seeders(&peers)), (7, 4));
+        assert!(info.select_peers(&mut selector, addr("[::1]:1"), 10, 75).is_empty());
+    }
+
+    #[test]
+    fn shards_split_torrents() {
+        let shared = SharedSwarm::sharded(4, Swarm::new);
+        let now = Instant::now();
+        let mut indices = Vec::new();
+        for i in 0..8 {
+            let mut peer = announce(1, 10, AnnounceEvent::Started);
+            peer.info_hash[7] = i;
+            shared.lock(&peer.info_hash).announce(addr("1.1.1.1:1"), &peer, now).unwrap();
+            indices.push(shared.shard_of(&peer.info_hash));
+            assert_eq!(shared.scrape(&peer.info_hash).leechers, 1);
+        }
+        assert_eq!(indices, vec![0, 1, 2, 3, 0, 1, 2, 3]);
+        let mut total = 0;
+        shared.for_each_shard(|swarm| total += swarm.torrents().count());
+        assert_eq!(total, 8);
+    }
+
+    #[test]
+    fn sweep_evicts_silent_peers() {
+        let mut swarm = Swarm::new();
+        let now = Instant::now();
+        let later = now + Duration::from_secs(60);
+        swarm.announce(addr("1.1.1.1:1"), &announce(1, 10, AnnounceEvent::Started), now).unwrap();
+        swarm.announce(addr("2.2.2.2:2"), &announce(2, 10, AnnounceEvent::Started), later).unwrap();
+        swarm.sweep(later);
+        assert_eq!(swarm.scrape(&[1; 20]).leechers, 1);
+        swarm.sweep(later + Duration::from_secs(1));
+        assert!(swarm.torrents.is_empty());
+    }
+}
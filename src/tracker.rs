@@ -0,0 +1,648 @@
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::accounting::{Account, Ledger};
+use crate::connection::ConnectionValidator;
+use crate::metrics::{Metrics, Outcome, Protocol};
+use crate::protocol::{
+    Action, AnnounceRequest, AnnounceResponse, ConnectResponse, ConnectRequest,
+    ErrorResponse, ParseLimits, Request, Response, ScrapeResponse, ScrapeRequest, TransactionID
+};
+use crate::ratelimit::{Decision, OverLimit, RateLimiter, RateLimits};
+use crate::selector::{PeerSelector, SelectorKind};
+use crate::swarm::{canonical_addr, PeerAnnounce, SharedSwarm, Swarm};
+use crate::users::UserTable;
+
+
+/// The message we send back when a client uses an unknown connection id
+const INVALID_CONNECTION_MESSAGE: &str = "invalid connection id";
+
+/// The message we send back when a client goes over a rate limit, if we answer at all
+const RATE_LIMITED_MESSAGE: &str = "rate limited";
+
+/// How often we look for peers that have gone silent.
+/// Transports should call `Tracker::maintain` at least this often.
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+
+/// The tunable parameters of a tracker
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// How long we ask clients to wait between announces
+    pub announce_interval: Duration,
+    /// The shortest time we allow between announces, which only HTTP can express
+    pub min_interval: Duration,
+    /// How many peers we send back when the client doesn't say
+    pub default_num_want: usize,
+    /// The most peers we ever send back in one response
+    pub max_num_want: usize,
+    /// How we choose which peers to send back
+    pub selector: SelectorKind,
+    /// The percentage of seeders we try to send to leechers
+    pub seeder_share: u8,
+    /// The largest UDP response we send to IPv4 clients, in bytes
+    pub max_datagram_v4: usize,
+    /// The largest UDP response we send to IPv6 clients, in bytes
+    pub max_datagram_v6: usize,
+    /// How many announce intervals a peer can stay silent before we drop it
+    pub peer_timeout: u32,
+    /// How long a connection id stays valid, at least
+    pub connection_lifetime: Duration,
+    /// The most torrents we track at once, if limited
+    pub max_torrents: Option<usize>,
+    /// The most peers we track in a single torrent, if limited
+    pub max_peers: Option<usize>,
+    /// How often each client can send requests
+    pub rate_limits: RateLimits,
+    /// How much we accept in a single request
+    pub parse_limits: ParseLimits
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            announce_interval: Duration::from_secs(15 * 60),
+            min_interval: Duration::from_secs(60),
+            default_num_want: 50,
+            max_num_want: 200,
+            selector: SelectorKind::Random,
+            seeder_share: 75,
+            // What fits in an ethernet frame, after the IP and UDP headers
+            max_datagram_v4: 1472,
+            max_datagram_v6: 1452,
+            peer_timeout: 2,
+            connection_lifetime: Duration::from_secs(2 * 60),
+            max_torrents: None,
+            max_peers: None,
+            rate_limits: RateLimits::default(),
+            parse_limits: ParseLimits::default()
+        }
+    }
+}
+
+impl Settings {
+    /// How long a peer can stay silent before we drop it
+    pub fn peer_lifetime(&self) -> Duration {
+        self.announce_interval * self.peer_timeout
+    }
+
+    /// Create an empty swarm split into `shards`, with the limits these settings ask for.
+    /// The torrent limit is split evenly between the shards.
+    pub fn new_swarm(&self, shards: usize) -> SharedSwarm {
+        let shards = shards.max(1);
+        let max_torrents = self.max_torrents.map(|n| n.div_ceil(shards));
+        SharedSwarm::sharded(shards, || Swarm::with_limits(max_torrents, self.max_peers))
+    }
+}
+
+
+/// What to do with a packet, once the parts that don't touch the swarm are done
+#[derive(Debug)]
+pub enum Step {
+    /// Nothing should be sent back
+    Ignore,
+    /// This response should be sent back
+    Respond(Response),
+    /// This announce has a valid connection id, and needs to go to the swarm
+    Announce(AnnounceRequest)
+}
+
+
+/// The core of the UDP tracker, independent of any sockets.
+///
+/// This takes in requests along with the address they came from,
+/// and gives back the response to send, if any. Transports only need
+/// to move bytes around, and call `maintain` regularly.
+pub struct Tracker {
+    settings: Settings,
+    selector: Box<dyn PeerSelector>,
+    connections: ConnectionValidator,
+    swarm: SharedSwarm,
+    metrics: Arc<Metrics>,
+    limiter: Arc<RateLimiter>,
+    users: Option<Arc<UserTable>>,
+    ledger: Option<Arc<Ledger>>,
+    last_sweep: Instant
+}
+
+impl Tracker {
+    /// Create a new tracker, with an empty swarm
+    pub fn new(settings: Settings) -> Self {
+        let swarm = settings.new_swarm(1);
+        Tracker::with_swarm(settings, swarm)
+    }
+
+    /// Create a new tracker using an existing swarm
+    pub fn with_swarm(settings: Settings, swarm: SharedSwarm) -> Self {
+        let connections = ConnectionValidator::new(settings.connection_lifetime, Instant::now());
+        Tracker::with_validator(settings, swarm, connections)
+    }
+
+    /// Create a new tracker using an existing swarm and connection validator.
+    ///
+    /// Trackers sharing clones of a validator accept each other's connection ids,
+    /// so clients can talk to any of them.
+    pub fn with_validator(settings: Settings, swarm: SharedSwarm, connections: ConnectionValidator) -> Self {
+        let now = Instant::now();
+        Tracker {
+            selector: settings.selector.build(),
+            connections,
+            swarm,
+            metrics: Arc::new(Metrics::new()),
+            limiter: Arc::new(RateLimiter::new(settings.rate_limits.clone())),
+            settings,
+            users: None,
+            ledger: None,
+            last_sweep: now
+        }
+    }
+
+    /// The settings this tracker uses
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Change how this tracker chooses which peers to send back
+    pub fn set_selector(&mut self, selector: Box<dyn PeerSelector>) {
+        self.selector = selector;
+    }
+
+    /// Record what this tracker does into a shared set of metrics
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    /// The metrics this tracker records into
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Enforce rate limits shared with other trackers,
+    /// so that clients can't get around them by switching sockets
+    pub fn set_rate_limiter(&mut self, limiter: Arc<RateLimiter>) {
+        self.limiter = limiter;
+    }
+
+    /// Only let in announces carrying the passkey of one of these users,
+    /// making this a private tracker
+    pub fn set_users(&mut self, users: Arc<UserTable>) {
+        self.users = Some(users);
+    }
+
+    /// Credit the bytes clients report in their announces to their accounts
+    pub fn set_ledger(&mut self, ledger: Arc<Ledger>) {
+        self.ledger = Some(ledger);
+    }
+
+    /// Get a handle to the swarm this tracker uses, to share it with other frontends
+    pub fn swarm(&self) -> SharedSwarm {
+        self.swarm.clone()
+    }
+
+    /// Do periodic work, like removing silent peers, if it's due
+    pub fn maintain(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.last_sweep) < SWEEP_INTERVAL {
+            return;
+        }
+        if let Some(cutoff) = now.checked_sub(self.settings.peer_lifetime()) {
+            self.swarm.for_each_shard(|swarm| swarm.sweep(cutoff));
+        }
+        self.last_sweep = now;
+    }
+
+    /// Handle a raw packet, giving back the response to send, if any.
+    ///
+    /// Packets that don't parse get an error response, as long as they're
+    /// long enough for us to find a transaction id, and within the connect limits.
+    pub fn handle_packet(&mut self, src: SocketAddr, bytes: &[u8], now: Instant) -> Option<Response> {
+        match self.prepare(src, bytes, now) {
+            Step::Ignore => None,
+            Step::Respond(response) => Some(response),
+            Step::Announce(req) => Some(self.announce(src, &req, now))
+        }
+    }
+
+    /// Handle a parsed request, giving back the response to send, if any
+    pub fn handle(&mut self, src: SocketAddr, request: &Request, now: Instant) -> Option<Response> {
+        match self.prepare_request(src, request, now) {
+            Step::Ignore => None,
+            Step::Respond(response) => Some(response),
+            Step::Announce(req) => Some(self.announce(src, &req, now))
+        }
+    }
+
+    /// Do everything for a raw packet short of changing the swarm.
+    ///
+    /// Connects, scrapes, and errors are answered right away, while valid
+    /// announces are given back, to be passed to `announce` by whichever
+    /// thread owns the torrent.
+    pub fn prepare(&mut self, src: SocketAddr, bytes: &[u8], now: Instant) -> Step {
+        match Request::parse(bytes, &self.settings.parse_limits) {
+            Ok(request) => self.prepare_request(src, &request, now),
+            Err(e) => {
+                self.metrics.record_parse_error(&e);
+                // Without a transaction id the client can't match our error
+                let transaction_id = match TransactionID::from_packet(bytes) {
+                    Some(transaction_id) => transaction_id,
+                    None => return Step::Ignore
+                };
+                // Anyone can send us garbage from a spoofed address, so errors share
+                // the limits of connects, and are never answered once over them
+                let decision = self.limiter.check(Action::Connect, src.ip(), now);
+                self.metrics.record_rate_limit(Action::Connect, decision);
+                if decision != Decision::Allowed {
+                    return Step::Ignore;
+                }
+                Step::Respond(Response::Error(ErrorResponse::new(transaction_id, e.to_string())))
+            }
+        }
+    }
+
+    fn prepare_request(&mut self, src: SocketAddr, request: &Request, now: Instant) -> Step {
+        let (action, transaction_id) = match request {
+            Request::Connect(r) => (Action::Connect, r.transaction_id),
+            Request::Announce(r) => (Action::Announce, r.transaction_id),
+            Request::Scrape(r) => (Action::Scrape, r.transaction_id)
+        };
+        if let Some(step) = self.limit(src, action, transaction_id, now) {
+            return step;
+        }
+        match request {
+            Request::Connect(r) => match self.handle_connect(src, r, now) {
+                Some(response) => {
+                    self.metrics.record_request(Protocol::Udp, Action::Connect, Outcome::Ok);
+                    Step::Respond(response)
+                }
+                None => {
+                    self.metrics.record_request(Protocol::Udp, Action::Connect, Outcome::Error);
+                    Step::Ignore
+                }
+            },
+            Request::Announce(r) => {
+                if !self.connections.validate(src, r.connection_id, now) {
+                    return self.reject_connection(Action::Announce, r.transaction_id);
+                }
+                // Private trackers get the passkey from the URL data
+                let auth = self.users.as_ref().map(|users| users.authenticate(&r.url_data()).map(|_| ()));
+                match auth {
+                    Some(Err(e)) => {
+                        self.metrics.record_request(Protocol::Udp, Action::Announce, Outcome::Error);
+                        Step::Respond(Response::Error(ErrorResponse::new(r.transaction_id, e.to_string())))
+                    }
+                    _ => Step::Announce(r.clone())
+                }
+            }
+            Request::Scrape(r) => {
+                if self.connections.validate(src, r.connection_id, now) {
+                    self.metrics.record_request(Protocol::Udp, Action::Scrape, Outcome::Ok);
+                    Step::Respond(self.handle_scrape(r))
+                } else {
+                    self.reject_connection(Action::Scrape, r.transaction_id)
+                }
+            }
+        }
+    }
+
+    /// Check a request against the rate limits, giving back what to do if it's over them
+    fn limit(&self, src: SocketAddr, action: Action, transaction_id: TransactionID, now: Instant) -> Option<Step> {
+        let decision = self.limiter.check(action, src.ip(), now);
+        self.metrics.record_rate_limit(action, decision);
+        if decision == Decision::Allowed {
+            return None;
+        }
+        self.metrics.record_request(Protocol::Udp, action, Outcome::Error);
+        match self.limiter.limits().over_limit {
+            OverLimit::Drop => Some(Step::Ignore),
+            OverLimit::Error => Some(Step::Respond(Response::Error(ErrorResponse::new(transaction_id, RATE_LIMITED_MESSAGE))))
+        }
+    }
+
+    fn reject_connection(&self, action: Action, transaction_id: TransactionID) -> Step {
+        self.metrics.record_rejected_connection();
+        self.metrics.record_request(Protocol::Udp, action, Outcome::Error);
+        Step::Respond(Response::Error(ErrorResponse::new(transaction_id, INVALID_CONNECTION_MESSAGE)))
+    }
+
+    fn handle_connect(&mut self, src: SocketAddr, req: &ConnectRequest, now: Instant) -> Option<Response> {
+        // We do nothing if the magic id is wrong
+        if !req.connection_id.is_magic_id() {
+            return None;
+        }
+        let connection_id = self.connections.generate(src, now);
+        let transaction_id = req.transaction_id;
+        Some(Response::Connect(ConnectResponse { transaction_id, connection_id }))
+    }
+
+    /// Apply an announce to the swarm, and build the response.
+    /// The connection id should already have been checked by `prepare`.
+    pub fn announce(&mut self, src: SocketAddr, req: &AnnounceRequest, now: Instant) -> Response {
+        let started = Instant::now();
+        let response = self.handle_announce(src, req, now);
+        let outcome = match response {
+            Response::Error(_) => Outcome::Error,
+            _ => Outcome::Ok
+        };
+        self.metrics.record_request(Protocol::Udp, Action::Announce, outcome);
+        self.metrics.record_announce_latency(Protocol::Udp, started.elapsed());
+        response
+    }
+
+    fn handle_announce(&mut self, src: SocketAddr, req: &AnnounceRequest, now: Instant) -> Response {
+        let transaction_id = req.transaction_id;
+        let interval = self.settings.announce_interval.as_secs() as i32;
+        let mut swarm = self.swarm.lock(&req.info_hash);
+        // Peers are reachable on the port they announce, not the one they sent from
+        let mut peer = src;
+        peer.set_port(req.port);
+        let announce = PeerAnnounce::from(req);
+        let info = match swarm.announce(peer, &announce, now) {
+            Ok(info) => info,
+            Err(e) => return Response::Error(ErrorResponse::new(transaction_id, e.to_string()))
+        };
+        if let Some(ledger) = &self.ledger {
+            if let Some(account) = Account::for_announce(self.users.as_deref(), &req.url_data(), req.key) {
+                ledger.record(account, &announce, now);
+            }
+        }
+        let scrape = info.scrape_info();
+        let leechers = scrape.leechers;
+        let seeders = scrape.seeders;
+        // Clients send -1 to leave the amount up to us
+        let wanted = match req.num_want {
+            n if n < 0 => self.settings.default_num_want,
+            n => (n as usize).min(self.settings.max_num_want)
+        };
+        let fitting = match canonical_addr(peer) {
+            SocketAddr::V4(_) => AnnounceResponse::<SocketAddrV4>::peers_fitting(self.settings.max_datagram_v4),
+            SocketAddr::V6(_) => AnnounceResponse::<SocketAddrV6>::peers_fitting(self.settings.max_datagram_v6)
+        };
+        // The peers we get back have the same family as the request
+        let selected = info.select_peers(
+            &mut *self.selector, peer, wanted.min(fitting), self.settings.seeder_share
+        );
+        let addrs = selected.into_iter().map(|c| c.addr);
+        match canonical_addr(peer) {
+            SocketAddr::V4(_) => {
+                let peers = addrs.filter_map(|addr| match addr {
+                    SocketAddr::V4(addr) => Some(addr),
+                    SocketAddr::V6(_) => None
+                }).collect();
+                Response::Announce(AnnounceResponse {
+                    transaction_id, interval, leechers, seeders, peers
+                })
+            }
+            SocketAddr::V6(_) => {
+                let peers = addrs.filter_map(|addr| match addr {
+                    SocketAddr::V6(addr) => Some(addr),
+                    SocketAddr::V4(_) => None
+                }).collect();
+                Response::AnnounceV6(AnnounceResponse {
+                    transaction_id, interval, leechers, seeders, peers
+                })
+            }
+        }
+    }
+
+    fn handle_scrape(&self, req: &ScrapeRequest) -> Response {
+        let transaction_id = req.transaction_id;
+        let scrapes = req.info_hashes.iter().map(|hash| self.swarm.scrape(hash)).collect();
+        Response::Scrape(ScrapeResponse { transaction_id, scrapes })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::{AccessList, AccessMode};
+    use crate::protocol::{AnnounceEvent, ScrapeOverflow, Writable};
+    use crate::ratelimit::Limit;
+
+    fn connect(tracker: &mut Tracker, src: SocketAddr, now: Instant) -> [u8; 8] {
+        let packet = [0, 0, 4, 0x17, 0x27, 0x10, 0x19, 0x80, 0, 0, 0, 0, 0, 0, 0, 1];
+        let response = tracker.handle_packet(src, &packet, now).unwrap();
+        let mut buf = [0; 16];
+        response.write(&mut buf);
+        let mut connection_id = [0; 8];
+        connection_id.copy_from_slice(&buf[8..]);
+        connection_id
+    }
+
+    fn announce_packet(connection_id: [u8; 8]) -> Vec<u8> {
+        let mut packet = connection_id.to_vec();
+        packet.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2]);
+        packet.extend_from_slice(&[1; 20]);
+        packet.extend_from_slice(&[2; 20]);
+        // downloaded, left, uploaded, event, ip, key, num_want, port
+        packet.extend_from_slice(&[0; 24]);
+        packet.extend_from_slice(&[0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0]);
+        packet.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0x1a, 0xe1]);
+        packet
+    }
+
+    #[test]
+    fn connect_then_announce() {
+        let mut tracker = Tracker::new(Settings::default());
+        let now = Instant::now();
+        let src = "1.2.3.4:6881".parse().unwrap();
+        let connection_id = connect(&mut tracker, src, now);
+        let mut packet = announce_packet(connection_id);
+        // A leecher on another port gets our seeder back, but not itself
+        packet[64..72].copy_from_slice(&10i64.to_be_bytes());
+        packet[96..98].copy_from_slice(&6882u16.to_be_bytes());
+        tracker.handle_packet(src, &announce_packet(connection_id), now);
+        let response = tracker.handle_packet(src, &packet, now);
+        match response {
+            Some(Response::Announce(r)) => {
+                assert_eq!((r.seeders, r.leechers), (1, 1));
+                assert_eq!(r.peers, vec!["1.2.3.4:6881".parse().unwrap()]);
+            }
+            r => panic!("unexpected response {:?}", r)
+        }
+    }
+
+    #[test]
+    fn announce_over_ipv6() {
+        let mut tracker = Tracker::new(Settings::default());
+        let now = Instant::now();
+        let src = "[2001:db8::1]:6881".parse().unwrap();
+        let connection_id = connect(&mut tracker, src, now);
+        let response = tracker.handle_packet(src, &announce_packet(connection_id), now);
+        assert!(matches!(response, Some(Response::AnnounceV6(_))));
+    }
+
+    #[test]
+    fn num_want_within_budget() {
+        let settings = Settings { max_num_want: 1000, ..Settings::default() };
+        let mut tracker = Tracker::new(settings);
+        let now = Instant::now();
+        let swarm = tracker.swarm();
+        for i in 0..500u32 {
+            let peer = SocketAddr::from((i.to_be_bytes(), 6881));
+            let announce = PeerAnnounce {
+                info_hash: [1; 20],
+                peer_id: [0; 20],
+                key: 0,
+                downloaded: 0,
+                left: 10,
+                uploaded: 0,
+                event: AnnounceEvent::Started
+            };
+            swarm.lock(&announce.info_hash).announce(peer, &announce, now).unwrap();
+        }
+        let src = "1.2.3.4:6881".parse().unwrap();
+        let connection_id = connect(&mut tracker, src, now);
+        let mut packet = announce_packet(connection_id);
+        let mut num_peers = |num_want: i32| {
+            packet[92..96].copy_from_slice(&num_want.to_be_bytes());
+            match tracker.handle_packet(src, &packet, now) {
+                Some(Response::Announce(r)) => r.peers.len(),
+                r => panic!("unexpected response {:?}", r)
+            }
+        };
+        assert_eq!(num_peers(-1), 50);
+        assert_eq!(num_peers(7), 7);
+        // 1472 bytes only has room for 242 IPv4 peers
+        assert_eq!(num_peers(1000), 242);
+    }
+
+    #[test]
+    fn deny_unregistered_torrent() {
+        let mut tracker = Tracker::new(Settings::default());
+        let access = AccessList::new(AccessMode::Whitelist, Default::default());
+        tracker.swarm().set_access(access);
+        let now = Instant::now();
+        let src = "1.2.3.4:6881".parse().unwrap();
+        let connection_id = connect(&mut tracker, src, now);
+        match tracker.handle_packet(src, &announce_packet(connection_id), now) {
+            Some(Response::Error(r)) => assert_eq!(r.message, "torrent not registered"),
+            r => panic!("unexpected response {:?}", r)
+        }
+    }
+
+    #[test]
+    fn reject_stale_connection() {
+        let mut tracker = Tracker::new(Settings::default());
+        let now = Instant::now();
+        let src = "1.2.3.4:6881".parse().unwrap();
+        let connection_id = connect(&mut tracker, src, now);
+        let later = now + Settings::default().connection_lifetime * 2;
+        let response = tracker.handle_packet(src, &announce_packet(connection_id), later);
+        match response {
+            Some(Response::Error(r)) => assert_eq!(r.message, INVALID_CONNECTION_MESSAGE),
+            r => panic!("unexpected response {:?}", r)
+        }
+    }
+
+    #[test]
+    fn check_passkeys() {
+        let mut tracker = Tracker::new(Settings::default());
+        tracker.set_users(Arc::new(UserTable::parse("abc alice\n").unwrap()));
+        let now = Instant::now();
+        let src = "1.2.3.4:6881".parse().unwrap();
+        let connection_id = connect(&mut tracker, src, now);
+        let mut packet = announce_packet(connection_id);
+        let message = |tracker: &mut Tracker, packet: &[u8]| match tracker.handle_packet(src, packet, now) {
+            Some(Response::Error(r)) => r.message,
+            r => panic!("unexpected response {:?}", r)
+        };
+        assert_eq!(message(&mut tracker, &packet), "missing passkey");
+        packet.extend_from_slice(&[2, 13]);
+        packet.extend_from_slice(b"/announce/abd");
+        assert_eq!(message(&mut tracker, &packet), "unknown passkey");
+        packet[112] = b'c';
+        assert!(matches!(tracker.handle_packet(src, &packet, now), Some(Response::Announce(_))));
+    }
+
+    #[test]
+    fn credit_accounts() {
+        let mut tracker = Tracker::new(Settings::default());
+        let ledger = Arc::new(Ledger::new(Settings::default().peer_lifetime()));
+        tracker.set_ledger(Arc::clone(&ledger));
+        let now = Instant::now();
+        let src = "1.2.3.4:6881".parse().unwrap();
+        let connection_id = connect(&mut tracker, src, now);
+        let mut packet = announce_packet(connection_id);
+        packet[88..92].copy_from_slice(&7u32.to_be_bytes());
+        tracker.handle_packet(src, &packet, now);
+        // Then report some uploaded bytes, in a regular announce
+        packet[72..80].copy_from_slice(&1000i64.to_be_bytes());
+        packet[80..84].copy_from_slice(&0i32.to_be_bytes());
+        tracker.handle_packet(src, &packet, now);
+        assert_eq!(ledger.user(&Account::Key(7)).uploaded, 1000);
+    }
+
+    #[test]
+    fn limit_announces() {
+        let rate_limits = RateLimits {
+            announce_ip: Some(Limit { rate: 1.0, burst: 1 }),
+            over_limit: OverLimit::Error,
+            ..RateLimits::default()
+        };
+        let mut tracker = Tracker::new(Settings { rate_limits, ..Settings::default() });
+        let now = Instant::now();
+        let src = "1.2.3.4:6881".parse().unwrap();
+        let connection_id = connect(&mut tracker, src, now);
+        let packet = announce_packet(connection_id);
+        assert!(matches!(tracker.handle_packet(src, &packet, now), Some(Response::Announce(_))));
+        match tracker.handle_packet(src, &packet, now) {
+            Some(Response::Error(r)) => assert_eq!(r.message, RATE_LIMITED_MESSAGE),
+            r => panic!("unexpected response {:?}", r)
+        }
+        // Connects have no limit here
+        connect(&mut tracker, src, now);
+        let rendered = tracker.metrics().render(&tracker.swarm());
+        assert!(rendered.contains("bittrickle_rate_limit_decisions_total{action=\"announce\",decision=\"allowed\"} 1"));
+        assert!(rendered.contains("bittrickle_rate_limit_decisions_total{action=\"announce\",decision=\"limited_ip\"} 1"));
+        assert!(rendered.contains("bittrickle_rate_limit_decisions_total{action=\"connect\",decision=\"allowed\"} 2"));
+    }
+
+    #[test]
+    fn limit_scrapes() {
+        let parse_limits = ParseLimits { max_scrape_hashes: 2, ..ParseLimits::default() };
+        let mut tracker = Tracker::new(Settings { parse_limits, ..Settings::default() });
+        let now = Instant::now();
+        let src = "1.2.3.4:6881".parse().unwrap();
+        let mut packet = connect(&mut tracker, src, now).to_vec();
+        packet.extend_from_slice(&[0, 0, 0, 2, 0, 0, 0, 3]);
+        for i in 0..3 {
+            packet.extend_from_slice(&[i; 20]);
+        }
+        match tracker.handle_packet(src, &packet, now) {
+            Some(Response::Scrape(r)) => assert_eq!(r.scrapes.len(), 2),
+            r => panic!("unexpected response {:?}", r)
+        }
+        tracker.settings.parse_limits.scrape_overflow = ScrapeOverflow::Error;
+        match tracker.handle_packet(src, &packet, now) {
+            Some(Response::Error(r)) => assert_eq!(r.message, "too many info hashes"),
+            r => panic!("unexpected response {:?}", r)
+        }
+    }
+
+    #[test]
+    fn limit_parse_errors() {
+        let rate_limits = RateLimits {
+            connect_ip: Some(Limit { rate: 1.0, burst: 1 }),
+            over_limit: OverLimit::Error,
+            ..RateLimits::default()
+        };
+        let mut tracker = Tracker::new(Settings { rate_limits, ..Settings::default() });
+        let now = Instant::now();
+        let src = "1.2.3.4:6881".parse().unwrap();
+        let packet = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 9, 0, 0, 0, 1];
+        match tracker.handle_packet(src, &packet, now) {
+            Some(Response::Error(r)) => assert_eq!(r.message, "unknown action"),
+            r => panic!("unexpected response {:?}", r)
+        }
+        // Even with errors for limited requests, garbage gets nothing back
+        assert!(tracker.handle_packet(src, &packet, now).is_none());
+    }
+
+    #[test]
+    fn ignore_short_garbage() {
+        let mut tracker = Tracker::new(Settings::default());
+        let src = "1.2.3.4:6881".parse().unwrap();
+        assert!(tracker.handle_packet(src, &[1, 2, 3], Instant::now()).is_none());
+    }
+}
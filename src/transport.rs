@@ -0,0 +1,467 @@
+//! Moving datagrams in and out of a UDP socket.
+//!
+//! Servers read and write packets in batches through a `Transport`. The
+//! default is a plain `UdpSocket`, which costs one system call per packet.
+//! On Linux, `MmsgSocket` moves a whole batch with one `recvmmsg` or
+//! `sendmmsg` call instead.
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::str::FromStr;
+
+
+/// The most datagrams we move in one go
+pub const BATCH_SIZE: usize = 32;
+
+
+/// Check if an io error just means a read timed out
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+
+/// Check if a send error is about the socket itself, rather than one destination.
+///
+/// Spoofed packets can give us addresses the kernel won't send to, like port 0,
+/// and those shouldn't stop us from answering everyone else.
+pub(crate) fn is_socket_error(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    let socket_errors = [libc::EBADF, libc::ENOTSOCK, libc::EFAULT];
+    #[cfg(not(unix))]
+    let socket_errors: [i32; 0] = [];
+    e.raw_os_error().is_some_and(|code| socket_errors.contains(&code))
+}
+
+
+/// A set of datagrams, along with the address each came from or goes to
+pub struct Batch {
+    buffers: Vec<Vec<u8>>,
+    packets: Vec<(SocketAddr, usize)>
+}
+
+impl Batch {
+    /// Create an empty batch with room for `capacity` datagrams of up to `size` bytes
+    pub fn new(capacity: usize, size: usize) -> Self {
+        Batch { buffers: vec![vec![0; size]; capacity.max(1)], packets: Vec::with_capacity(capacity) }
+    }
+
+    /// How many datagrams the batch holds
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    /// Whether or not the batch holds any datagrams
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// The most datagrams the batch can hold
+    pub fn capacity(&self) -> usize {
+        self.buffers.len()
+    }
+
+    /// Remove every datagram
+    pub fn clear(&mut self) {
+        self.packets.clear();
+    }
+
+    /// Add a datagram, written into the batch by `write`, which returns its length.
+    /// This gives back false, without calling `write`, if the batch is full.
+    pub fn push_with(&mut self, addr: SocketAddr, write: impl FnOnce(&mut [u8]) -> usize) -> bool {
+        let index = self.packets.len();
+        match self.buffers.get_mut(index) {
+            Some(buf) => {
+                let len = write(buf);
+                self.packets.push((addr, len));
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Add a datagram that was read straight into the buffer at `index`.
+    /// Datagrams are matched to buffers by position, so if some before it were
+    /// dropped, its buffer is moved up to take the place of theirs.
+    #[cfg(target_os = "linux")]
+    fn push_received(&mut self, index: usize, addr: SocketAddr, len: usize) {
+        self.buffers.swap(self.packets.len(), index);
+        self.packets.push((addr, len));
+    }
+
+    /// Iterate over the datagrams, along with their addresses
+    pub fn iter(&self) -> impl Iterator<Item = (SocketAddr, &[u8])> {
+        self.packets.iter().zip(&self.buffers).map(|(&(addr, len), buf)| (addr, &buf[..len]))
+    }
+}
+
+
+/// A way of sending and receiving datagrams in batches
+pub trait Transport: Send {
+    /// Replace the contents of `batch` with the datagrams that have arrived,
+    /// waiting for at least one. If the socket's read timeout passes first,
+    /// the batch is left empty.
+    fn recv(&mut self, batch: &mut Batch) -> io::Result<()>;
+
+    /// Send every datagram in `batch`, giving back how many couldn't be sent.
+    /// Datagrams that can't go to their address are skipped, and only errors
+    /// with the socket itself are returned.
+    fn send(&mut self, batch: &Batch) -> io::Result<usize>;
+
+    /// The address we're receiving on
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// The plain transport, receiving one datagram at a time
+impl Transport for UdpSocket {
+    fn recv(&mut self, batch: &mut Batch) -> io::Result<()> {
+        batch.clear();
+        match self.recv_from(&mut batch.buffers[0]) {
+            Ok((amt, src)) => batch.packets.push((src, amt)),
+            Err(ref e) if is_timeout(e) => {}
+            Err(e) => return Err(e)
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, batch: &Batch) -> io::Result<usize> {
+        let mut failed = 0;
+        for (dst, bytes) in batch.iter() {
+            match self.send_to(bytes, dst) {
+                Ok(_) => {}
+                Err(e) if is_socket_error(&e) => return Err(e),
+                Err(_) => failed += 1
+            }
+        }
+        Ok(failed)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+
+/// Which transport a server uses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    /// One system call per datagram, which works everywhere
+    Std,
+    /// `recvmmsg` and `sendmmsg`, which only work on Linux
+    Mmsg
+}
+
+impl Backend {
+    /// Whether or not this backend works on the current system
+    pub fn is_supported(self) -> bool {
+        match self {
+            Backend::Std => true,
+            Backend::Mmsg => cfg!(target_os = "linux")
+        }
+    }
+
+    /// Wrap a socket in this kind of transport.
+    /// Backends that aren't supported here fall back to the plain socket.
+    pub fn wrap(self, socket: UdpSocket) -> Box<dyn Transport> {
+        match self {
+            #[cfg(target_os = "linux")]
+            Backend::Mmsg => Box::new(MmsgSocket::new(socket)),
+            _ => Box::new(socket)
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "std" => Ok(Backend::Std),
+            "mmsg" => Ok(Backend::Mmsg),
+            _ => Err(format!("unknown udp backend: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Backend::Std => "std",
+            Backend::Mmsg => "mmsg"
+        };
+        f.write_str(name)
+    }
+}
+
+
+/// Write a socket address in the form the kernel expects, giving back its length
+#[cfg(target_os = "linux")]
+pub(crate) fn to_raw(addr: SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+    use std::mem;
+
+    match addr {
+        SocketAddr::V4(addr) => {
+            // sockaddr_storage is big enough, and aligned enough, for any address
+            let raw = unsafe { &mut *(storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+            raw.sin_family = libc::AF_INET as libc::sa_family_t;
+            raw.sin_port = addr.port().to_be();
+            raw.sin_addr = libc::in_addr { s_addr: u32::from(*addr.ip()).to_be() };
+            raw.sin_zero = [0; 8];
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+        }
+        SocketAddr::V6(addr) => {
+            let raw = unsafe { &mut *(storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+            raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            raw.sin6_port = addr.port().to_be();
+            raw.sin6_flowinfo = addr.flowinfo();
+            raw.sin6_addr = libc::in6_addr { s6_addr: addr.ip().octets() };
+            raw.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+        }
+    }
+}
+
+/// Read back a socket address the kernel wrote, if it's one we know
+#[cfg(target_os = "linux")]
+fn from_raw(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let raw = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(raw.sin_addr.s_addr));
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(raw.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let raw = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(raw.sin6_addr.s6_addr);
+            let port = u16::from_be(raw.sin6_port);
+            Some(SocketAddr::V6(SocketAddrV6::new(ip, port, raw.sin6_flowinfo, raw.sin6_scope_id)))
+        }
+        _ => None
+    }
+}
+
+
+/// A transport moving whole batches with `recvmmsg` and `sendmmsg`.
+///
+/// The socket's read timeout still applies while waiting for the first
+/// datagram of a batch, and the rest are whatever had already arrived.
+#[cfg(target_os = "linux")]
+pub struct MmsgSocket {
+    socket: UdpSocket,
+    addrs: Vec<libc::sockaddr_storage>
+}
+
+#[cfg(target_os = "linux")]
+impl MmsgSocket {
+    /// Use a bound socket to move batches
+    pub fn new(socket: UdpSocket) -> Self {
+        let addrs = vec![unsafe { std::mem::zeroed() }; BATCH_SIZE];
+        MmsgSocket { socket, addrs }
+    }
+
+    /// Point a message header at a buffer and an address
+    fn header(iov: &mut libc::iovec, addr: &mut libc::sockaddr_storage, addr_len: libc::socklen_t) -> libc::mmsghdr {
+        let mut header: libc::mmsghdr = unsafe { std::mem::zeroed() };
+        header.msg_hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+        header.msg_hdr.msg_namelen = addr_len;
+        header.msg_hdr.msg_iov = iov;
+        header.msg_hdr.msg_iovlen = 1;
+        header
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Transport for MmsgSocket {
+    fn recv(&mut self, batch: &mut Batch) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        batch.clear();
+        let count = batch.capacity().min(BATCH_SIZE);
+        let mut iovs: [libc::iovec; BATCH_SIZE] = unsafe { std::mem::zeroed() };
+        let mut headers: [libc::mmsghdr; BATCH_SIZE] = unsafe { std::mem::zeroed() };
+        let addr_len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        let parts = batch.buffers.iter_mut().zip(iovs.iter_mut()).zip(self.addrs.iter_mut());
+        for (header, ((buf, iov), addr)) in headers.iter_mut().zip(parts).take(count) {
+            *iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() };
+            *header = MmsgSocket::header(iov, addr, addr_len);
+        }
+        // Block for the first datagram, then take whatever else is waiting
+        let received = unsafe {
+            libc::recvmmsg(
+                self.socket.as_raw_fd(), headers.as_mut_ptr(), count as libc::c_uint,
+                libc::MSG_WAITFORONE as _, std::ptr::null_mut()
+            )
+        };
+        if received < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::Interrupted => Ok(()),
+                _ if is_timeout(&e) => Ok(()),
+                _ => Err(e)
+            };
+        }
+        for (i, (header, addr)) in headers.iter().zip(&self.addrs).take(received as usize).enumerate() {
+            // Datagrams from address families we don't know are dropped
+            if let Some(src) = from_raw(addr) {
+                batch.push_received(i, src, header.msg_len as usize);
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, batch: &Batch) -> io::Result<usize> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut failed = 0;
+        let mut iovs: [libc::iovec; BATCH_SIZE] = unsafe { std::mem::zeroed() };
+        let mut headers: [libc::mmsghdr; BATCH_SIZE] = unsafe { std::mem::zeroed() };
+        let mut start = 0;
+        while start < batch.len() {
+            let count = (batch.len() - start).min(BATCH_SIZE);
+            let packets = batch.packets[start..start + count].iter().zip(&batch.buffers[start..]);
+            let parts = packets.zip(iovs.iter_mut()).zip(self.addrs.iter_mut());
+            for (header, ((((dst, len), buf), iov), addr)) in headers.iter_mut().zip(parts) {
+                let addr_len = to_raw(*dst, addr);
+                // The kernel only reads from the buffer when sending
+                *iov = libc::iovec { iov_base: buf.as_ptr() as *mut libc::c_void, iov_len: *len };
+                *header = MmsgSocket::header(iov, addr, addr_len);
+            }
+            let mut sent = 0;
+            while sent < count {
+                let result = unsafe {
+                    libc::sendmmsg(
+                        self.socket.as_raw_fd(), headers[sent..].as_mut_ptr(),
+                        (count - sent) as libc::c_uint, 0
+                    )
+                };
+                if result < 0 {
+                    let e = io::Error::last_os_error();
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    if is_socket_error(&e) {
+                        return Err(e);
+                    }
+                    // Errors are only reported for the first datagram, so we skip it
+                    failed += 1;
+                    sent += 1;
+                    continue;
+                }
+                sent += result as usize;
+            }
+            start += count;
+        }
+        Ok(failed)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn echo_batch(backend: Backend) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut transport = backend.wrap(socket);
+        let addr = transport.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        for i in 0..3u8 {
+            client.send_to(&[i; 4], addr).unwrap();
+        }
+        let mut incoming = Batch::new(BATCH_SIZE, 64);
+        let mut outgoing = Batch::new(BATCH_SIZE, 64);
+        let mut seen = 0;
+        while seen < 3 {
+            transport.recv(&mut incoming).unwrap();
+            for (src, bytes) in incoming.iter() {
+                assert_eq!(src, client.local_addr().unwrap());
+                assert_eq!(bytes, &[seen as u8; 4]);
+                outgoing.push_with(src, |buf| {
+                    buf[..2].copy_from_slice(&bytes[..2]);
+                    2
+                });
+                seen += 1;
+            }
+        }
+        transport.send(&outgoing).unwrap();
+        let mut buf = [0; 64];
+        for i in 0..3u8 {
+            let (amt, _) = client.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..amt], &[i; 2]);
+        }
+    }
+
+    #[test]
+    fn std_round_trip() {
+        echo_batch(Backend::Std);
+    }
+
+    #[test]
+    fn mmsg_round_trip() {
+        echo_batch(Backend::Mmsg);
+    }
+
+    fn skip_bad_destinations(backend: Backend) {
+        let mut transport = backend.wrap(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut outgoing = Batch::new(BATCH_SIZE, 64);
+        // Nothing can be sent to port 0, which a spoofed packet could come from
+        for &dst in &[client.local_addr().unwrap(), "127.0.0.1:0".parse().unwrap(), client.local_addr().unwrap()] {
+            outgoing.push_with(dst, |buf| {
+                buf[0] = dst.port() as u8;
+                1
+            });
+        }
+        assert_eq!(transport.send(&outgoing).unwrap(), 1);
+        let mut buf = [0; 64];
+        for _ in 0..2 {
+            let (amt, _) = client.recv_from(&mut buf).unwrap();
+            assert_eq!(amt, 1);
+        }
+    }
+
+    #[test]
+    fn std_skips_bad_destinations() {
+        skip_bad_destinations(Backend::Std);
+    }
+
+    #[test]
+    fn mmsg_skips_bad_destinations() {
+        skip_bad_destinations(Backend::Mmsg);
+    }
+
+    #[test]
+    fn batches_fill_up() {
+        let mut batch = Batch::new(2, 8);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        assert!(batch.push_with(addr, |_| 1));
+        assert!(batch.push_with(addr, |_| 8));
+        assert!(!batch.push_with(addr, |_| panic!("the batch is full")));
+        assert_eq!(batch.iter().map(|(_, bytes)| bytes.len()).collect::<Vec<_>>(), vec![1, 8]);
+        batch.clear();
+        assert!(batch.is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn dropped_datagrams_leave_no_gaps() {
+        let mut batch = Batch::new(3, 8);
+        for (i, buf) in batch.buffers.iter_mut().enumerate() {
+            buf[0] = i as u8;
+        }
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        // The datagram in the first buffer came from an address we couldn't read
+        batch.push_received(1, addr, 1);
+        batch.push_received(2, addr, 1);
+        assert_eq!(batch.iter().map(|(_, bytes)| bytes[0]).collect::<Vec<_>>(), vec![1, 2]);
+    }
+}
@@ -0,0 +1,189 @@
+//! The users of a private tracker, identified by their passkeys.
+//!
+//! Clients put their passkey in the tracker's URL, as in `/announce/<passkey>`.
+//! HTTP clients send that path directly, and UDP clients send it as BEP 41
+//! URL data, so both end up checked against the same table. HTTP scrapes
+//! go to `/scrape/<passkey>`, following the usual convention.
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str;
+
+
+/// The errors that can happen while loading a user table
+#[derive(Debug)]
+pub enum UserError {
+    /// The file couldn't be read
+    Io(PathBuf, io::Error),
+    /// A line isn't a passkey followed by a name, or repeats a passkey
+    BadLine(usize, String)
+}
+
+impl fmt::Display for UserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UserError::Io(path, e) => write!(f, "couldn't read {}: {}", path.display(), e),
+            UserError::BadLine(line, text) => write!(f, "line {}: invalid user {:?}", line, text)
+        }
+    }
+}
+
+impl error::Error for UserError {}
+
+
+/// Why an announce wasn't let in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthError {
+    /// The URL had no passkey in it
+    MissingPasskey,
+    /// The passkey doesn't belong to anyone
+    UnknownPasskey
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::MissingPasskey => f.write_str("missing passkey"),
+            AuthError::UnknownPasskey => f.write_str("unknown passkey")
+        }
+    }
+}
+
+impl error::Error for AuthError {}
+
+
+/// Find the passkey in the path of a tracker URL, such as `/announce/<passkey>`
+/// or `/scrape/<passkey>`. Anything after a `?` is ignored.
+pub fn passkey(path: &[u8]) -> Option<&str> {
+    let path = str::from_utf8(path).ok()?;
+    let path = path.split('?').next().unwrap_or("");
+    match path.strip_prefix("/announce/").or_else(|| path.strip_prefix("/scrape/")) {
+        Some(passkey) if !passkey.is_empty() && !passkey.contains('/') => Some(passkey),
+        _ => None
+    }
+}
+
+
+/// Someone allowed to use the tracker
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    /// A name for the user, for people to read
+    pub name: String
+}
+
+
+/// Every user, by passkey
+#[derive(Debug, Clone, Default)]
+pub struct UserTable {
+    users: HashMap<String, User>
+}
+
+impl UserTable {
+    /// Create an empty table, which lets nobody in
+    pub fn new() -> Self {
+        UserTable::default()
+    }
+
+    /// Parse a table with one user per line, as a passkey followed by a name.
+    /// Blank lines, and anything after a `#`, are ignored.
+    pub fn parse(text: &str) -> Result<Self, UserError> {
+        let mut table = UserTable::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let bad_line = || UserError::BadLine(i + 1, line.to_string());
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let passkey = parts.next().unwrap_or("");
+            let name = parts.next().unwrap_or("").trim();
+            if name.is_empty() || passkey.contains(['/', '?']) {
+                return Err(bad_line());
+            }
+            if !table.insert(passkey, User { name: name.to_string() }) {
+                return Err(bad_line());
+            }
+        }
+        Ok(table)
+    }
+
+    /// Read a table from a file, see `parse` for the format
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, UserError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| UserError::Io(path.to_owned(), e))?;
+        UserTable::parse(&text)
+    }
+
+    /// Add a user, returning false if the passkey was already taken
+    pub fn insert(&mut self, passkey: impl Into<String>, user: User) -> bool {
+        let passkey = passkey.into();
+        if self.users.contains_key(&passkey) {
+            return false;
+        }
+        self.users.insert(passkey, user);
+        true
+    }
+
+    /// Look up a user by passkey
+    pub fn get(&self, passkey: &str) -> Option<&User> {
+        self.users.get(passkey)
+    }
+
+    /// How many users there are
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    /// Whether or not there are no users at all
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// Find the user owning the passkey in the path of a tracker URL
+    pub fn authenticate(&self, path: &[u8]) -> Result<(&str, &User), AuthError> {
+        let passkey = passkey(path).ok_or(AuthError::MissingPasskey)?;
+        self.users.get_key_value(passkey)
+            .map(|(passkey, user)| (passkey.as_str(), user))
+            .ok_or(AuthError::UnknownPasskey)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_table() {
+        let table = UserTable::parse("# passkey name\n\nabc123  Alice Smith # admin\nxyz bob\n").unwrap();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get("abc123").unwrap().name, "Alice Smith");
+        let error = UserTable::parse("abc alice\nabc bob\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: invalid user \"abc bob\"");
+        assert!(UserTable::parse("lonely\n").is_err());
+        assert!(UserTable::parse("a/b carol\n").is_err());
+    }
+
+    #[test]
+    fn find_passkeys() {
+        assert_eq!(passkey(b"/announce/abc123"), Some("abc123"));
+        assert_eq!(passkey(b"/announce/abc123?event=started"), Some("abc123"));
+        assert_eq!(passkey(b"/announce"), None);
+        assert_eq!(passkey(b"/announce/"), None);
+        assert_eq!(passkey(b"/announce/a/b"), None);
+        assert_eq!(passkey(b"/scrape/abc123"), Some("abc123"));
+        assert_eq!(passkey(b"/scrape"), None);
+        assert_eq!(passkey(b"/announce/\xff"), None);
+    }
+
+    #[test]
+    fn authenticate_users() {
+        let table = UserTable::parse("abc alice\n").unwrap();
+        assert_eq!(table.authenticate(b"/announce/abc").unwrap().1.name, "alice");
+        assert_eq!(table.authenticate(b"/announce/abd"), Err(AuthError::UnknownPasskey));
+        assert_eq!(table.authenticate(b""), Err(AuthError::MissingPasskey));
+    }
+}
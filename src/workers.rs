@@ -0,0 +1,417 @@
+//! Serving UDP from several threads at once.
+//!
+//! Socket workers read packets, and handle everything that doesn't change
+//! the swarm themselves: connects, scrapes, and bad requests. Announces are
+//! passed on to swarm workers. The swarm is split into shards by info hash,
+//! and each swarm worker owns one shard, so every announce for a torrent
+//! goes through the same queue, and is applied in the order it came in.
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use crate::accounting::Ledger;
+use crate::config::Config;
+use crate::connection::ConnectionValidator;
+use crate::metrics::Metrics;
+use crate::protocol::{AnnounceRequest, Writable};
+use crate::ratelimit::RateLimiter;
+use crate::shutdown::{Shutdown, POLL_INTERVAL};
+use crate::swarm::SharedSwarm;
+use crate::tracker::{Settings, Step, Tracker, SWEEP_INTERVAL};
+use crate::transport::{self, is_socket_error, Backend, Batch, Transport, BATCH_SIZE};
+use crate::users::UserTable;
+
+
+/// How many announces can wait for a swarm worker.
+/// When a queue is full, announces are dropped, and clients retry later.
+const QUEUE_LENGTH: usize = 4096;
+
+
+/// Bind a socket that other sockets can bind to the same address,
+/// with the kernel spreading packets between them
+#[cfg(target_os = "linux")]
+fn bind_reuse_port(addr: SocketAddr) -> io::Result<UdpSocket> {
+    use std::mem;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let family = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6
+    };
+    let fd = unsafe { libc::socket(family, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // From here on, the socket gets closed if anything fails
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    let one: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_REUSEPORT,
+            &one as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut raw: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = transport::to_raw(addr, &mut raw);
+    let result = unsafe {
+        libc::bind(socket.as_raw_fd(), &raw as *const libc::sockaddr_storage as *const libc::sockaddr, len)
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+/// Bind `count` sockets to the same address.
+///
+/// On Linux, each socket is bound separately with `SO_REUSEPORT`, and the
+/// kernel balances packets between them. Elsewhere, one socket is shared.
+fn bind_many(addr: SocketAddr, count: usize) -> io::Result<Vec<UdpSocket>> {
+    #[cfg(target_os = "linux")]
+    {
+        let first = bind_reuse_port(addr)?;
+        // If we were asked for any port, the others need the one we got
+        let addr = first.local_addr()?;
+        let mut sockets = vec![first];
+        for _ in 1..count {
+            sockets.push(bind_reuse_port(addr)?);
+        }
+        Ok(sockets)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let first = UdpSocket::bind(addr)?;
+        let mut sockets = Vec::with_capacity(count);
+        for _ in 1..count {
+            sockets.push(first.try_clone()?);
+        }
+        sockets.push(first);
+        Ok(sockets)
+    }
+}
+
+
+/// An announce waiting for the swarm worker that owns its torrent
+struct Job {
+    /// Which socket the announce came in on, and the response goes out on
+    socket: usize,
+    src: SocketAddr,
+    request: AnnounceRequest
+}
+
+
+/// Send a response to a client, using `buf` to write it out.
+/// Only errors with the socket itself are returned.
+fn send(socket: &UdpSocket, buf: &mut [u8], response: impl Writable, dst: SocketAddr, metrics: &Metrics) -> io::Result<()> {
+    let count = response.write(buf);
+    match socket.send_to(&buf[..count], dst) {
+        Ok(_) => metrics.record_sent(count),
+        Err(e) if is_socket_error(&e) => return Err(e),
+        Err(_) => metrics.record_send_failures(1)
+    }
+    Ok(())
+}
+
+/// How big a buffer needs to be to hold any response the tracker builds
+fn write_buf_size(settings: &Settings) -> usize {
+    settings.max_datagram_v4.max(settings.max_datagram_v6)
+}
+
+
+/// A UDP tracker running over several socket workers and swarm workers
+pub struct Workers {
+    settings: Settings,
+    swarm: SharedSwarm,
+    sockets: Vec<UdpSocket>,
+    backend: Backend,
+    metrics: Arc<Metrics>,
+    users: Option<Arc<UserTable>>,
+    ledger: Option<Arc<Ledger>>,
+    shutdown: Shutdown
+}
+
+impl Workers {
+    /// Bind `socket_workers` sockets to each address, with one swarm worker
+    /// per shard of `swarm`.
+    pub fn bind(addrs: &[SocketAddr], socket_workers: usize, settings: Settings, swarm: SharedSwarm) -> io::Result<Self> {
+        let mut sockets = Vec::new();
+        for addr in addrs {
+            sockets.extend(bind_many(*addr, socket_workers.max(1))?);
+        }
+        let metrics = Arc::new(Metrics::new());
+        Ok(Workers {
+            settings,
+            swarm,
+            sockets,
+            backend: Backend::Std,
+            metrics,
+            users: None,
+            ledger: None,
+            shutdown: Shutdown::new()
+        })
+    }
+
+    /// Bind the workers for every UDP address in a config
+    pub fn bind_all(config: &Config, swarm: &SharedSwarm) -> io::Result<Self> {
+        let mut workers = Workers::bind(&config.udp, config.socket_workers, config.tracker.clone(), swarm.clone())?;
+        workers.set_backend(config.udp_backend);
+        Ok(workers)
+    }
+
+    /// Change how socket workers read and answer packets.
+    /// Swarm workers always send their responses one at a time.
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    /// Record what the workers do into a shared set of metrics
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    /// Only let in announces from these users, see `Tracker::set_users`
+    pub fn set_users(&mut self, users: Arc<UserTable>) {
+        self.users = Some(users);
+    }
+
+    /// Credit the bytes clients report to their accounts, see `Tracker::set_ledger`
+    pub fn set_ledger(&mut self, ledger: Arc<Ledger>) {
+        self.ledger = Some(ledger);
+    }
+
+    /// Stop every worker once this handle is triggered
+    pub fn set_shutdown(&mut self, shutdown: Shutdown) {
+        self.shutdown = shutdown;
+    }
+
+    /// The addresses the sockets are bound to, with one entry per socket worker
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.sockets.iter().map(UdpSocket::local_addr).collect()
+    }
+
+    /// Get a handle to the swarm the workers use, to share it with other frontends
+    pub fn swarm(&self) -> SharedSwarm {
+        self.swarm.clone()
+    }
+
+    /// Run every worker, blocking the current thread.
+    /// If an io error occurs in any worker, the others are stopped,
+    /// and this function returns that error.
+    ///
+    /// Once the shutdown handle is triggered, socket workers stop reading,
+    /// swarm workers finish the announces already queued, and this returns `Ok(())`.
+    pub fn run(self) -> io::Result<()> {
+        // Every worker accepts the connection ids the others hand out
+        let connections = ConnectionValidator::new(self.settings.connection_lifetime, Instant::now());
+        // And clients share one set of limits, whichever socket they land on
+        let limiter = Arc::new(RateLimiter::new(self.settings.rate_limits.clone()));
+        let (settings, swarm, metrics) = (&self.settings, &self.swarm, &self.metrics);
+        let (users, ledger) = (&self.users, &self.ledger);
+        let tracker = || {
+            let mut tracker = Tracker::with_validator(settings.clone(), swarm.clone(), connections.clone());
+            tracker.set_metrics(Arc::clone(metrics));
+            tracker.set_rate_limiter(Arc::clone(&limiter));
+            if let Some(users) = users {
+                tracker.set_users(Arc::clone(users));
+            }
+            if let Some(ledger) = ledger {
+                tracker.set_ledger(Arc::clone(ledger));
+            }
+            tracker
+        };
+        let (done, finished) = mpsc::channel();
+        let mut queues = Vec::with_capacity(self.swarm.shard_count());
+        for shard in 0..self.swarm.shard_count() {
+            let (queue, jobs) = mpsc::sync_channel(QUEUE_LENGTH);
+            queues.push(queue);
+            let sockets = self.sockets.iter().map(UdpSocket::try_clone).collect::<io::Result<_>>()?;
+            let worker = SwarmWorker {
+                shard,
+                tracker: tracker(),
+                sockets,
+                jobs,
+                write_buf: vec![0; write_buf_size(&self.settings)],
+                last_sweep: Instant::now()
+            };
+            let done = done.clone();
+            thread::spawn(move || done.send(worker.run()));
+        }
+        for (index, socket) in self.sockets.into_iter().enumerate() {
+            // Socket workers need to wake up regularly to check for a shutdown
+            socket.set_read_timeout(Some(POLL_INTERVAL))?;
+            let worker = SocketWorker {
+                index,
+                shutdown: self.shutdown.clone(),
+                tracker: tracker(),
+                swarm: self.swarm.clone(),
+                transport: self.backend.wrap(socket),
+                queues: queues.clone(),
+                incoming: Batch::new(BATCH_SIZE, 2048),
+                outgoing: Batch::new(BATCH_SIZE, write_buf_size(&self.settings))
+            };
+            let done = done.clone();
+            thread::spawn(move || done.send(worker.run()));
+        }
+        // Only the workers hold the queues now, so swarm workers stop after socket workers do
+        drop(queues);
+        drop(done);
+        let mut result = Ok(());
+        for finished in finished {
+            if let Err(e) = finished {
+                // One worker failing stops the rest, and we report the first failure
+                self.shutdown.trigger();
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+}
+
+
+/// Reads packets from one socket, answering what it can right away
+struct SocketWorker {
+    index: usize,
+    shutdown: Shutdown,
+    tracker: Tracker,
+    swarm: SharedSwarm,
+    transport: Box<dyn Transport>,
+    queues: Vec<SyncSender<Job>>,
+    incoming: Batch,
+    outgoing: Batch
+}
+
+impl SocketWorker {
+    fn run(mut self) -> io::Result<()> {
+        let metrics = Arc::clone(self.tracker.metrics());
+        while !self.shutdown.is_triggered() {
+            self.transport.recv(&mut self.incoming)?;
+            let now = Instant::now();
+            self.outgoing.clear();
+            for (src, bytes) in self.incoming.iter() {
+                metrics.record_received(bytes.len());
+                match self.tracker.prepare(src, bytes, now) {
+                    Step::Ignore => {}
+                    Step::Respond(response) => {
+                        self.outgoing.push_with(src, |buf| {
+                            let count = response.write(buf);
+                            metrics.record_sent(count);
+                            count
+                        });
+                    }
+                    Step::Announce(request) => {
+                        let shard = self.swarm.shard_of(&request.info_hash);
+                        let job = Job { socket: self.index, src, request };
+                        match self.queues[shard].try_send(job) {
+                            // The client will retransmit, by which time we might have caught up
+                            Ok(()) | Err(TrySendError::Full(_)) => {}
+                            Err(TrySendError::Disconnected(_)) => {
+                                return Err(io::Error::other("swarm worker stopped"));
+                            }
+                        }
+                    }
+                }
+            }
+            let failed = self.transport.send(&self.outgoing)?;
+            metrics.record_send_failures(failed);
+        }
+        Ok(())
+    }
+}
+
+
+/// Applies the announces for one shard of the swarm, in order
+struct SwarmWorker {
+    shard: usize,
+    tracker: Tracker,
+    sockets: Vec<UdpSocket>,
+    jobs: Receiver<Job>,
+    write_buf: Vec<u8>,
+    last_sweep: Instant
+}
+
+impl SwarmWorker {
+    fn run(mut self) -> io::Result<()> {
+        loop {
+            match self.jobs.recv_timeout(SWEEP_INTERVAL) {
+                Ok(job) => {
+                    let response = self.tracker.announce(job.src, &job.request, Instant::now());
+                    let metrics = self.tracker.metrics();
+                    send(&self.sockets[job.socket], &mut self.write_buf, response, job.src, metrics)?;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                // Every socket worker has stopped, and we've applied everything they sent us
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            self.sweep(Instant::now());
+        }
+    }
+
+    /// Remove silent peers from our shard, if it's due
+    fn sweep(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.last_sweep) < SWEEP_INTERVAL {
+            return;
+        }
+        if let Some(cutoff) = now.checked_sub(self.tracker.settings().peer_lifetime()) {
+            self.tracker.swarm().lock_shard(self.shard).sweep(cutoff);
+        }
+        self.last_sweep = now;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Announce, TrackerClient};
+    use crate::protocol::AnnounceEvent;
+    use crate::swarm::Swarm;
+
+    #[test]
+    fn announce_across_workers() {
+        let swarm = SharedSwarm::sharded(2, Swarm::new);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut workers = Workers::bind(&[addr], 2, Settings::default(), swarm.clone()).unwrap();
+        let shutdown = Shutdown::new();
+        workers.set_shutdown(shutdown.clone());
+        let addrs = workers.local_addrs().unwrap();
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(addrs[0], addrs[1]);
+        let handle = thread::spawn(move || workers.run());
+        let mut announce = Announce {
+            info_hash: [0; 20],
+            peer_id: [1; 20],
+            downloaded: 0,
+            left: 0,
+            uploaded: 0,
+            event: AnnounceEvent::Started,
+            key: 0,
+            num_want: -1,
+            port: 6881
+        };
+        // Torrents in both shards get a seeder, and then a leecher
+        for hash in 0..2u8 {
+            announce.info_hash[7] = hash;
+            let mut seeder = TrackerClient::new(addrs[0]).unwrap();
+            seeder.announce(&announce).unwrap();
+            let mut leecher = TrackerClient::new(addrs[0]).unwrap();
+            let response = leecher.announce(&Announce { peer_id: [2; 20], left: 10, port: 6882, ..announce.clone() }).unwrap();
+            assert_eq!((response.seeders, response.leechers), (1, 1));
+            assert_eq!(response.peers, vec![SocketAddr::from(([127, 0, 0, 1], 6881))]);
+            let scrape = leecher.scrape(&[announce.info_hash]).unwrap();
+            assert_eq!((scrape[0].seeders, scrape[0].leechers), (1, 1));
+        }
+        assert_eq!(swarm.lock_shard(0).torrents().count(), 1);
+        assert_eq!(swarm.lock_shard(1).torrents().count(), 1);
+        shutdown.trigger();
+        assert!(handle.join().unwrap().is_ok());
+    }
+}